@@ -0,0 +1,179 @@
+//! Evaluation of animations without a GPU. Every vertex moves along a closed bezier curve: its
+//! control points from the model followed by the mirrored tangent at the first point and the
+//! first point itself, so the curve ends where it started heading in the direction it started.
+//! Time is given in seconds and wraps around after `duration`.
+//!
+//! The same curves can be evaluated by the vertex shader, which works with a fixed number of
+//! control points. Curves with fewer points are elevated to that degree, which keeps their shape.
+use math_fx::de_casteljau;
+use model::Model;
+
+/// Control points per vertex evaluated by `assets/shader/animated_*.glslv`
+pub const GPU_CONTROL_POINTS: usize = 12;
+
+/// Axis aligned box containing every position of every vertex
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Animation {
+    /// Closed curve of every vertex
+    pub curves: Vec<Vec<[f32; 3]>>,
+    pub materials: Vec<[f32; 4]>,
+    /// Triangles as indices into the vertices, three per face
+    pub indices: Vec<u32>,
+    pub frames: usize,
+    pub duration: f32,
+    /// A bezier curve never leaves the hull of its control points, so neither do the vertices
+    pub bounds: Bounds
+}
+
+impl Animation {
+    pub fn new(model: &Model) -> Animation {
+        let curves: Vec<Vec<[f32; 3]>> = model.vertices.iter().map(|points| {
+            let mut curve = points.clone();
+            let (first, second) = (points[0], points[1]);
+            curve.push([2.0 * first[0] - second[0], 2.0 * first[1] - second[1], 2.0 * first[2] - second[2]]);
+            curve.push(first);
+            curve
+        }).collect();
+
+        let mut points = curves.iter().flat_map(|curve| curve.iter());
+        let first = points.next().cloned().unwrap_or([0.0; 3]);
+        let mut bounds = Bounds { min: first, max: first };
+        for point in points {
+            for (axis, &value) in point.iter().enumerate() {
+                bounds.min[axis] = bounds.min[axis].min(value);
+                bounds.max[axis] = bounds.max[axis].max(value);
+            }
+        }
+
+        Animation {
+            curves: curves,
+            materials: model.materials.clone(),
+            indices: model.faces.iter().flat_map(|face| face.iter().cloned()).collect(),
+            frames: model.frames,
+            duration: model.duration,
+            bounds: bounds
+        }
+    }
+
+    /// Position along the loop in `[0, 1)`
+    pub fn progress(&self, t: f32) -> f32 {
+        let progress = t.rem_euclid(self.duration) / self.duration;
+        // Rounding may land exactly on the end of the loop, which is its start
+        if progress >= 1.0 { 0.0 } else { progress }
+    }
+
+    pub fn position(&self, vertex: usize, t: f32) -> [f32; 3] {
+        de_casteljau(self.progress(t), &self.curves[vertex])
+    }
+
+    /// Positions of all vertices at a time
+    pub fn evaluate(&self, t: f32) -> Vec<[f32; 3]> {
+        (0..self.curves.len()).map(|vertex| self.position(vertex, t)).collect()
+    }
+
+    /// Positions of all vertices at one of the `frames` evenly spaced samples of the loop
+    pub fn frame(&self, frame: usize) -> Vec<[f32; 3]> {
+        self.evaluate(frame as f32 / self.frames as f32 * self.duration)
+    }
+
+    /// Sample to show at a time, the one at or right before it
+    pub fn frame_index(&self, t: f32) -> usize {
+        ((self.progress(t) * self.frames as f32) as usize).min(self.frames - 1)
+    }
+
+    /// Every curve with exactly `points` control points, `None` if one of them has more
+    pub fn elevated(&self, points: usize) -> Option<Vec<Vec<[f32; 3]>>> {
+        self.curves.iter().map(|curve| {
+            let mut curve = curve.clone();
+            while curve.len() < points {
+                curve = elevate(&curve);
+            }
+            if curve.len() == points { Some(curve) } else { None }
+        }).collect()
+    }
+}
+
+/// Describes the same curve with one more control point
+pub fn elevate(curve: &[[f32; 3]]) -> Vec<[f32; 3]> {
+    let n = curve.len() as f32;
+    let mut elevated = vec![curve[0]];
+    for i in 1..curve.len() {
+        let a = i as f32 / n;
+        let (previous, current) = (curve[i - 1], curve[i]);
+        elevated.push([
+            a * previous[0] + (1.0 - a) * current[0],
+            a * previous[1] + (1.0 - a) * current[1],
+            a * previous[2] + (1.0 - a) * current[2]
+        ]);
+    }
+    elevated.push(curve[curve.len() - 1]);
+    elevated
+}
+
+#[cfg(test)]
+fn test_animation() -> Animation {
+    let text = "3d 2\ncollision 1 1\nframes 4\nduration 2\ng 1 0 0\nv 0 0 0 1 0 0 1 1 0\nv 0 2 0 0 2 1\nf 0 1 1\n";
+    Animation::new(&Model::parse("test.3d", text).unwrap())
+}
+
+#[test]
+fn evaluate_positions() {
+    let animation = test_animation();
+    assert_eq!(animation.curves[1], vec![[0.0, 2.0, 0.0], [0.0, 2.0, 1.0], [0.0, 2.0, -1.0], [0.0, 2.0, 0.0]]);
+    assert_eq!(animation.indices, vec![0, 1, 1]);
+    assert_eq!(animation.bounds, Bounds { min: [-1.0, 0.0, -1.0], max: [1.0, 2.0, 1.0] });
+
+    // Every vertex starts at its first control point
+    assert_eq!(animation.evaluate(0.0), vec![[0.0, 0.0, 0.0], [0.0, 2.0, 0.0]]);
+
+    // Halfway through the 2 second loop the binomial weights are 1/16, 4/16, 6/16, 4/16, 1/16
+    // for the first curve and 1/8, 3/8, 3/8, 1/8 for the second one
+    assert_eq!(animation.position(0, 1.0), [0.375, 0.375, 0.0]);
+    assert_eq!(animation.position(1, 1.0), [0.0, 2.0, 0.0]);
+    assert_eq!(animation.position(1, 0.5), [0.0, 2.0, 0.28125]);
+}
+
+#[test]
+fn loop_boundaries() {
+    let animation = test_animation();
+    let start = animation.evaluate(0.0);
+    assert_eq!(animation.evaluate(2.0), start);
+    assert_eq!(animation.evaluate(-2.0), start);
+    assert_eq!(animation.evaluate(1.0), animation.evaluate(5.0));
+
+    // The curves are closed, right before the end of the loop vertices are back at their start
+    for (end, start) in animation.evaluate(1.9999).iter().zip(start.iter()) {
+        assert!((0..3).all(|axis| (end[axis] - start[axis]).abs() < 1e-3));
+    }
+
+    assert_eq!(animation.frame(0), start);
+    assert_eq!(animation.frame(2), animation.evaluate(1.0));
+    assert_eq!((animation.frame_index(0.0), animation.frame_index(0.49), animation.frame_index(0.5)), (0, 0, 1));
+    assert_eq!((animation.frame_index(1.99), animation.frame_index(2.0), animation.frame_index(-0.1)), (3, 0, 3));
+}
+
+#[test]
+fn elevate_curves() {
+    let animation = test_animation();
+    let elevated = animation.elevated(GPU_CONTROL_POINTS).unwrap();
+    assert!(elevated.iter().all(|curve| curve.len() == GPU_CONTROL_POINTS));
+    assert_eq!(elevate(&[[0.0, 0.0, 0.0], [3.0, 0.0, 0.0]]), vec![[0.0, 0.0, 0.0], [1.5, 0.0, 0.0], [3.0, 0.0, 0.0]]);
+
+    // Elevated curves pass through the same positions
+    for (vertex, curve) in elevated.iter().enumerate() {
+        for i in 0..=10 {
+            let t = i as f32 / 10.0;
+            let (expected, found) = (animation.position(vertex, t * animation.duration), de_casteljau(t, curve));
+            assert!((0..3).all(|axis| (found[axis] - expected[axis]).abs() < 1e-4), "vertex {} at {}: {:?} != {:?}", vertex, t, found, expected);
+        }
+    }
+
+    // Too many control points for the shader
+    assert_eq!(animation.elevated(4), None);
+}
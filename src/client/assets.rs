@@ -0,0 +1,259 @@
+//! Named assets. `assets/manifest.txt` lists every asset with one line per asset, so maps and
+//! unit types can refer to "tree" or "knight" instead of file names or indices:
+//!
+//! ```text
+//! # <name> <type> <file> [duration <seconds>] [collision <radius> <height>]
+//! tree animation 3d/0.3d
+//! knight animation 3d/1.3d duration 1.5
+//! ```
+//!
+//! Files are relative to the manifest. Duration and collision override what the file itself says.
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+
+use model::{Model, ParseError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AssetType {
+    Animation
+}
+
+impl AssetType {
+    pub fn parse(name: &str) -> Option<AssetType> {
+        match name {
+            "animation" => Some(AssetType::Animation),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AssetType::Animation => "animation"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asset {
+    pub name: String,
+    pub asset_type: AssetType,
+    pub file: PathBuf,
+    pub duration: Option<f32>,
+    /// Collision radius and height
+    pub collision: Option<(f32, f32)>
+}
+
+impl Asset {
+    /// Applies the overrides of the manifest to a model loaded from the asset's file
+    pub fn apply(&self, mut model: Model) -> Model {
+        if let Some(duration) = self.duration { model.duration = duration }
+        if let Some((radius, height)) = self.collision {
+            model.collision_radius = radius;
+            model.collision_y = height;
+        }
+        model
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetError {
+    /// The manifest couldn't be read or a line of it is invalid, line 0 referring to the whole file
+    Manifest { file: String, line: usize, reason: String },
+    /// No asset of that name is listed in the manifest
+    Missing(String),
+    WrongType { name: String, expected: AssetType, found: AssetType },
+    Model(ParseError)
+}
+
+impl From<ParseError> for AssetError {
+    fn from(e: ParseError) -> AssetError {
+        AssetError::Model(e)
+    }
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssetError::Manifest { ref file, line, ref reason } => write!(f, "{}:{}: {}", file, line, reason),
+            AssetError::Missing(ref name) => write!(f, "no asset named '{}' in the manifest", name),
+            AssetError::WrongType { ref name, expected, found } =>
+                write!(f, "asset '{}' is a {}, expected a {}", name, found.name(), expected.name()),
+            AssetError::Model(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub assets: Vec<Asset>
+}
+
+impl Manifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest, AssetError> {
+        let file = path.as_ref().display().to_string();
+        let mut text = String::new();
+        File::open(path.as_ref()).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| AssetError::Manifest { file: file.clone(), line: 0, reason: e.to_string() })?;
+        let root = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        Manifest::parse(&file, &text, root)
+    }
+
+    /// Parses a manifest, resolving the files it lists relative to `root`
+    pub fn parse(file: &str, text: &str, root: &Path) -> Result<Manifest, AssetError> {
+        let mut assets: Vec<Asset> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |reason: String| AssetError::Manifest { file: file.to_string(), line: i + 1, reason: reason };
+            let parts: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            if parts.is_empty() { continue }
+            if parts.len() < 3 { return Err(error("expected a name, type and file".to_string())) }
+            if assets.iter().any(|asset| asset.name == parts[0]) {
+                return Err(error(format!("asset '{}' is listed twice", parts[0])))
+            }
+            let mut asset = Asset {
+                name: parts[0].to_string(),
+                asset_type: AssetType::parse(parts[1]).ok_or_else(|| error(format!("unknown asset type '{}'", parts[1])))?,
+                file: root.join(parts[2]),
+                duration: None,
+                collision: None
+            };
+
+            let mut options = parts[3..].iter();
+            while let Some(&option) = options.next() {
+                let mut number = |valid: fn(f32) -> bool| match options.next() {
+                    Some(value) => value.parse::<f32>().ok().filter(|value| value.is_finite() && valid(*value))
+                        .ok_or_else(|| error(format!("invalid value '{}' for {}", value, option))),
+                    None => Err(error(format!("missing value for {}", option)))
+                };
+                match option {
+                    "duration" => asset.duration = Some(number(|seconds| seconds > 0.0)?),
+                    // Flat props may have no height or no radius to collide with
+                    "collision" => asset.collision = Some((number(|size| size >= 0.0)?, number(|size| size >= 0.0)?)),
+                    _ => return Err(error(format!("unknown option '{}'", option)))
+                }
+            }
+            assets.push(asset);
+        }
+        Ok(Manifest { assets: assets })
+    }
+
+    pub fn get(&self, name: &str, asset_type: AssetType) -> Result<&Asset, AssetError> {
+        let asset = self.assets.iter().find(|asset| asset.name == name).ok_or_else(|| AssetError::Missing(name.to_string()))?;
+        if asset.asset_type != asset_type {
+            return Err(AssetError::WrongType { name: name.to_string(), expected: asset_type, found: asset.asset_type })
+        }
+        Ok(asset)
+    }
+
+    /// Every asset of the given type, in the order they are listed
+    pub fn of_type(&self, asset_type: AssetType) -> Vec<&Asset> {
+        self.assets.iter().filter(|asset| asset.asset_type == asset_type).collect()
+    }
+}
+
+/// Loaded assets of one type. Names are resolved to ids once, which then index the registry.
+pub struct Registry<T> {
+    entries: Vec<(String, T)>
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Registry<T> {
+        Registry::new()
+    }
+}
+
+impl<T> Registry<T> {
+    pub fn new() -> Registry<T> {
+        Registry {
+            entries: Vec::new()
+        }
+    }
+
+    /// Adds an asset, replacing one of the same name while keeping its id
+    pub fn insert(&mut self, name: &str, asset: T) -> usize {
+        match self.entries.iter().position(|entry| entry.0 == name) {
+            Some(id) => {
+                self.entries[id].1 = asset;
+                id
+            },
+            None => {
+                self.entries.push((name.to_string(), asset));
+                self.entries.len() - 1
+            }
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Result<usize, AssetError> {
+        self.entries.iter().position(|entry| entry.0 == name).ok_or_else(|| AssetError::Missing(name.to_string()))
+    }
+
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.entries.get(id).map(|entry| &entry.0[..])
+    }
+
+    pub fn get(&self, id: usize) -> Option<&T> {
+        self.entries.get(id).map(|entry| &entry.1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T> Index<usize> for Registry<T> {
+    type Output = T;
+
+    fn index(&self, id: usize) -> &T {
+        &self.entries[id].1
+    }
+}
+
+#[test]
+fn parse_manifest() {
+    let text = "# name type file\ntree animation 3d/0.3d\n\nknight animation 3d/1.3d duration 1.5 collision 0.5 2 # sphere\n";
+    let manifest = Manifest::parse("manifest.txt", text, Path::new("assets")).unwrap();
+    assert_eq!(manifest.assets[1], Asset {
+        name: "knight".to_string(),
+        asset_type: AssetType::Animation,
+        file: PathBuf::from("assets/3d/1.3d"),
+        duration: Some(1.5),
+        collision: Some((0.5, 2.0))
+    });
+    assert_eq!(manifest.get("tree", AssetType::Animation).map(|asset| &asset.file), Ok(&PathBuf::from("assets/3d/0.3d")));
+    assert_eq!(manifest.get("scout", AssetType::Animation).unwrap_err().to_string(), "no asset named 'scout' in the manifest");
+
+    let error = |text: &str| Manifest::parse("manifest.txt", text, Path::new("")).unwrap_err().to_string();
+    assert_eq!(error("tree animation"), "manifest.txt:1: expected a name, type and file");
+    assert_eq!(error("tree sound tree.ogg"), "manifest.txt:1: unknown asset type 'sound'");
+    assert_eq!(error("tree animation 0.3d\ntree animation 1.3d"), "manifest.txt:2: asset 'tree' is listed twice");
+    assert_eq!(error("tree animation 0.3d duration -1"), "manifest.txt:1: invalid value '-1' for duration");
+    assert_eq!(error("tree animation 0.3d duration inf"), "manifest.txt:1: invalid value 'inf' for duration");
+    assert_eq!(error("tree animation 0.3d collision 1"), "manifest.txt:1: missing value for collision");
+    assert_eq!(error("tree animation 0.3d collision 1 -0.5"), "manifest.txt:1: invalid value '-0.5' for collision");
+    let flat = Manifest::parse("manifest.txt", "rug animation 3d/2.3d collision 1 0\n", Path::new("")).unwrap();
+    assert_eq!(flat.assets[0].collision, Some((1.0, 0.0)));
+    assert_eq!(error("tree animation 0.3d speed 2"), "manifest.txt:1: unknown option 'speed'");
+
+    let mut registry = Registry::new();
+    assert_eq!(registry.insert("tree", 1), 0);
+    assert_eq!(registry.insert("knight", 2), 1);
+    assert_eq!(registry.insert("tree", 3), 0);
+    assert_eq!((registry.id("knight"), registry[0], registry.name(1)), (Ok(1), 3, Some("knight")));
+    assert_eq!(registry.id("scout"), Err(AssetError::Missing("scout".to_string())));
+}
+
+#[test]
+fn load_bundled_manifest() {
+    let manifest = Manifest::load(format!("{}/assets/manifest.txt", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    for asset in manifest.of_type(AssetType::Animation) {
+        let model = asset.apply(Model::load(&asset.file).unwrap());
+        assert!(model.collision_radius > 0.0 && !model.faces.is_empty());
+    }
+    assert!(manifest.get("tree", AssetType::Animation).is_ok() && manifest.get("knight", AssetType::Animation).is_ok());
+}
@@ -4,5 +4,4 @@ pub type TRotation = f32;
 pub type TTime = f32;
 pub type T4Matrix<T> = [[T; 4]; 4];
 
-pub const PLAYER_HEIGHT: TCoordinate = 0.7;
-pub const ANIMATION_FRAMES: usize = 300;
+pub use networking::PLAYER_HEIGHT;
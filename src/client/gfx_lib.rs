@@ -18,3 +18,65 @@ gfx_parameters!( Params {
     u_model_view_proj@ u_model_view_proj: [[f32; 4]; 4],
     t_color@ t_color: TextureParam<R>,
 });
+
+/// Vertex of an animation evaluated on the GPU, see `animation::GPU_CONTROL_POINTS`. The control
+/// points are packed into vec4s, GL only guarantees 16 attributes including the instance ones.
+gfx_vertex!( AnimatedVertex {
+    a_curve0@ a_curve0: [f32; 4],
+    a_curve1@ a_curve1: [f32; 4],
+    a_curve2@ a_curve2: [f32; 4],
+    a_curve3@ a_curve3: [f32; 4],
+    a_curve4@ a_curve4: [f32; 4],
+    a_curve5@ a_curve5: [f32; 4],
+    a_curve6@ a_curve6: [f32; 4],
+    a_curve7@ a_curve7: [f32; 4],
+    a_curve8@ a_curve8: [f32; 4],
+    a_color@ a_color: [f32; 4],
+});
+
+impl AnimatedVertex {
+    pub fn new(p: &[[f32; 3]], color: [f32; 4]) -> AnimatedVertex {
+        let mut c = [[0.0; 4]; 9];
+        for (i, &value) in p[..12].iter().flat_map(|point| point.iter()).enumerate() {
+            c[i / 4][i % 4] = value;
+        }
+        AnimatedVertex {
+            a_curve0: c[0], a_curve1: c[1], a_curve2: c[2], a_curve3: c[3], a_curve4: c[4],
+            a_curve5: c[5], a_curve6: c[6], a_curve7: c[7], a_curve8: c[8],
+            a_color: color,
+        }
+    }
+}
+
+gfx_parameters!( AnimatedParams {
+    u_view_proj@ u_view_proj: [[f32; 4]; 4],
+    u_time@ u_time: f32,
+    t_color@ t_color: TextureParam<R>,
+});
+
+/// Per instance attributes of static objects, the columns of the model matrix and the phase
+gfx_vertex!( Instance {
+    a_model0@ a_model0: [f32; 4],
+    a_model1@ a_model1: [f32; 4],
+    a_model2@ a_model2: [f32; 4],
+    a_model3@ a_model3: [f32; 4],
+    a_phase@ a_phase: f32,
+});
+
+impl Instance {
+    pub fn new(model: [[f32; 4]; 4], phase: f32) -> Instance {
+        Instance {
+            a_model0: model[0],
+            a_model1: model[1],
+            a_model2: model[2],
+            a_model3: model[3],
+            a_phase: phase,
+        }
+    }
+}
+
+/// Parameters of instanced baked meshes, the model matrix is part of every instance
+gfx_parameters!( InstancedParams {
+    u_view_proj@ u_view_proj: [[f32; 4]; 4],
+    t_color@ t_color: TextureParam<R>,
+});
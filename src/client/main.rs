@@ -1,3 +1,6 @@
+// Structs are initialized as `field: field` throughout, the style of the 2015 code base
+#![allow(clippy::redundant_field_names)]
+
 // ------------------- GFX -------------------
 #[macro_use]
 extern crate gfx;
@@ -24,23 +27,191 @@ extern crate rand;
 extern crate time;
 extern crate find_folder;
 
+// ------------------- File -------------------
+use std::fs::File;
+use std::io::Read;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
 // ------------------- Intern -------------------
+#[macro_use]
+extern crate server;
+use server::logging::{self, Level};
 mod world;
 mod math_fx;
+mod model;
+mod animation;
+mod assets;
+mod watch;
 mod gfx_lib;
 mod consts;
 use world::*;
+use assets::Manifest;
+use watch::Watcher;
 use gfx_lib::*;
 
 // ------------------- Network -------------------
 mod networking;
 use networking::API;
 use networking::Server;
+use networking::ReplayViewer;
+use server::chat::{Channel, Message, Participant, Ping};
+use server::lobby::LobbyError;
+
+/// Vertex shaders for GLSL 1.20 and 1.50, then the fragment shaders, in `assets/shader`
+const SHADERS: [&str; 4] = ["cube_120.glslv", "cube_150.glslv", "cube_120.glslf", "cube_150.glslf"];
+const EMBEDDED_SHADERS: [&[u8]; 4] = [
+    include_bytes!("../../assets/shader/cube_120.glslv"),
+    include_bytes!("../../assets/shader/cube_150.glslv"),
+    include_bytes!("../../assets/shader/cube_120.glslf"),
+    include_bytes!("../../assets/shader/cube_150.glslf")
+];
+/// Shaders drawing instances of baked meshes
+const INSTANCED_SHADERS: [&str; 4] = ["instanced_120.glslv", "instanced_150.glslv", "cube_120.glslf", "cube_150.glslf"];
+const EMBEDDED_INSTANCED_SHADERS: [&[u8]; 4] = [
+    include_bytes!("../../assets/shader/instanced_120.glslv"),
+    include_bytes!("../../assets/shader/instanced_150.glslv"),
+    include_bytes!("../../assets/shader/cube_120.glslf"),
+    include_bytes!("../../assets/shader/cube_150.glslf")
+];
+/// Shaders evaluating animations on the GPU
+const ANIMATED_SHADERS: [&str; 4] = ["animated_120.glslv", "animated_150.glslv", "cube_120.glslf", "cube_150.glslf"];
+const EMBEDDED_ANIMATED_SHADERS: [&[u8]; 4] = [
+    include_bytes!("../../assets/shader/animated_120.glslv"),
+    include_bytes!("../../assets/shader/animated_150.glslv"),
+    include_bytes!("../../assets/shader/cube_120.glslf"),
+    include_bytes!("../../assets/shader/cube_150.glslf")
+];
+
+/// Pings fade out over this many ticks before and after the time they mark
+const PING_FADE: server::TimeIndex = 50;
+
+/// Pillar marking a ping, fading and shrinking with the distance of its time to the present
+fn ping_marker(ping: &Ping, present: server::TimeIndex) -> Option<((f32, f32), [f32; 4], f32)> {
+    let distance = if ping.time > present { ping.time - present } else { present - ping.time };
+    if distance > PING_FADE { return None }
+    let strength = 1.0 - distance as f32 / PING_FADE as f32;
+    Some((ping.location, [0.3 + 0.6 * strength, 0.1, 0.1, 1.0], 1.0 + 4.0 * strength))
+}
+
+/// Scrubs the watched replay, if there is one. A replay diverging from its recording stays at the
+/// last time it could be reproduced.
+fn scrub(replay: &mut Option<ReplayViewer>, ticks: isize) {
+    if let Some(ref mut replay) = *replay {
+        if let Err(e) = replay.scrub(ticks) {
+            log!(Level::Error, "client", "can't scrub replay"; time = replay.time(), error = e);
+        }
+    }
+}
+
+/// Received messages stay in the window title this long
+const CHAT_FADE: u64 = 10;
+/// Most received messages shown at once
+const CHAT_LINES: usize = 3;
+
+/// Chat of the player, shown in the window title as the client has no text rendering. Return
+/// starts typing a message, Tab switches its channel and another Return sends it.
+struct ChatView {
+    /// Channel and text of the message being typed
+    draft: Option<(Channel, String)>,
+    recent: VecDeque<(Instant, String)>
+}
+
+impl ChatView {
+    fn new() -> ChatView {
+        ChatView {
+            draft: None,
+            recent: VecDeque::new()
+        }
+    }
+
+    fn typing(&self) -> bool {
+        self.draft.is_some()
+    }
+
+    /// Handles a key pressed while typing, sending the message once it is done
+    fn press<A: API + ?Sized>(&mut self, key: input::Key, server: &mut A) {
+        match key {
+            input::Key::Return => if let Some((channel, text)) = self.draft.take() {
+                if !text.trim().is_empty() { server.say(channel, text.trim()) }
+            },
+            input::Key::Tab => if let Some((ref mut channel, _)) = self.draft {
+                *channel = if *channel == Channel::All { Channel::Team } else { Channel::All };
+            },
+            input::Key::Backspace => if let Some((_, ref mut text)) = self.draft { text.pop(); },
+            _ => {}
+        }
+    }
+
+    fn type_text(&mut self, typed: &str) {
+        if let Some((_, ref mut text)) = self.draft { text.push_str(typed) }
+    }
+
+    fn receive(&mut self, message: &Message) {
+        let sender = match message.sender {
+            Participant::Player(player) => format!("player {}", player),
+            Participant::Spectator => "spectator".to_string()
+        };
+        self.recent.push_back((Instant::now(), format!("[{}] {}: {}", message.channel.name(), sender, message.text)));
+        if self.recent.len() > CHAT_LINES { self.recent.pop_front(); }
+    }
+
+    fn title(&mut self) -> String {
+        while self.recent.front().is_some_and(|&(received, _)| received.elapsed() > Duration::from_secs(CHAT_FADE)) {
+            self.recent.pop_front();
+        }
+        let mut title = "Timewars".to_string();
+        for (_, line) in self.recent.iter() {
+            title.push_str(" | ");
+            title.push_str(line);
+        }
+        if let Some((ref channel, ref text)) = self.draft {
+            title.push_str(&format!(" | say [{}]: {}_", channel.name(), text));
+        }
+        title
+    }
+}
+
+/// Command line argument the given number of places after a flag
+fn argument(flag: &str, offset: usize) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(offset)
+}
 
 fn main() {
-    let mut server = Server::new().difficulty(5).local();
+    // Log levels per subsystem are taken from TIMEWARS_LOG, e.g. "info,rendering=debug"
+    if let Err(directive) = logging::from_env() {
+        log!(Level::Error, "client", "invalid log directive"; directive = directive);
+    }
+
+    // `client --connect <address> <match> [--name <name>]` plays a match hosted by a lobby server
+    let mut server: Box<dyn API> = match (argument("--connect", 1), argument("--connect", 2)) {
+        (Some(address), Some(id)) => {
+            let name = argument("--name", 1).unwrap_or_else(|| "player".to_string());
+            let remote = id.parse().map_err(|_| LobbyError::Protocol(id.clone())).and_then(|id|
+                Server::new().address(&address).credentials(&name, None).connect_to(id));
+            match remote {
+                Ok(remote) => Box::new(remote),
+                Err(e) => {
+                    log!(Level::Error, "client", "can't join match"; address = address, id = id, error = e);
+                    Box::new(Server::new().difficulty(5).local())
+                }
+            }
+        },
+        _ => Box::new(Server::new().difficulty(5).local())
+    };
     server.start_game();
 
+    // `client --replay <file>` shows a recorded match on top of the world
+    let mut replay = argument("--replay", 1)
+        .and_then(|path| match ReplayViewer::open(&path) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                log!(Level::Error, "client", "can't open replay"; path = path, error = e);
+                None
+            }
+        });
+    if replay.is_some() { log!(Level::Info, "client", "watching replay"); }
+
     let mut events: PistonWindow<(), Sdl2Window> =
         WindowSettings::new("Timewars", [640, 480])
         .exit_on_esc(true)
@@ -51,11 +222,8 @@ fn main() {
 
     let ref mut factory = events.factory.borrow().clone();
 
-    let mut my_world = world::World::example();
-
     let assets_path = find_folder::Search::ParentsThenKids(3, 3)
         .for_folder("assets").unwrap();
-    my_world.load_animations(assets_path.as_path().to_str().unwrap().to_string() , 2, factory);
 
     let texture = factory.create_texture_rgba8_static(1, 1, &[0x00_C0_A0_20]).unwrap();
 
@@ -64,22 +232,103 @@ fn main() {
                                    gfx::tex::WrapMode::Clamp)
     );
 
-    let program = {
+    // Shaders are read from the assets so they can be reloaded, the embedded ones are a fallback
+    let shaders = assets_path.join("shader");
+    let read_shaders = |names: &[&str; 4]| names.iter().map(|name| {
+        let mut source = Vec::new();
+        File::open(shaders.join(name)).and_then(|mut f| f.read_to_end(&mut source))
+            .map(|_| source).map_err(|e| format!("{}: {}", name, e))
+    }).collect::<Result<Vec<Vec<u8>>, String>>();
+    let link = |factory: &mut gfx_device_gl::Factory, sources: &[Vec<u8>]| {
         let vertex = gfx::ShaderSource {
-            glsl_120: Some(include_bytes!("../../assets/shader/cube_120.glslv")),
-            glsl_150: Some(include_bytes!("../../assets/shader/cube_150.glslv")),
+            glsl_120: Some(&sources[0][..]),
+            glsl_150: Some(&sources[1][..]),
             .. gfx::ShaderSource::empty()
         };
         let fragment = gfx::ShaderSource {
-            glsl_120: Some(include_bytes!("../../assets/shader/cube_120.glslf")),
-            glsl_150: Some(include_bytes!("../../assets/shader/cube_150.glslf")),
+            glsl_120: Some(&sources[2][..]),
+            glsl_150: Some(&sources[3][..]),
             .. gfx::ShaderSource::empty()
         };
-        factory.link_program_source(vertex, fragment).unwrap()
+        factory.link_program_source(vertex, fragment).map_err(|e| format!("{:?}", e))
+    };
+    let mut program = match read_shaders(&SHADERS).and_then(|sources| link(factory, &sources)) {
+        Ok(program) => program,
+        Err(e) => {
+            log!(Level::Warn, "rendering", "using embedded shaders"; reason = e);
+            let embedded: Vec<Vec<u8>> = EMBEDDED_SHADERS.iter().map(|source| source.to_vec()).collect();
+            link(factory, &embedded).unwrap()
+        }
+    };
+
+    let mut instanced_program = match read_shaders(&INSTANCED_SHADERS).and_then(|sources| link(factory, &sources)) {
+        Ok(program) => program,
+        Err(e) => {
+            log!(Level::Warn, "rendering", "using embedded instancing shaders"; reason = e);
+            let embedded: Vec<Vec<u8>> = EMBEDDED_INSTANCED_SHADERS.iter().map(|source| source.to_vec()).collect();
+            link(factory, &embedded).unwrap()
+        }
+    };
+
+    // GL 2.1 doesn't guarantee instanced arrays, without them every static object is drawn on its own
+    let instancing = {
+        let capabilities = factory.get_capabilities();
+        capabilities.instance_call_supported && capabilities.instance_rate_supported
+    };
+    if !instancing { log!(Level::Warn, "rendering", "instancing unsupported, drawing objects one by one"); }
+
+    // Animations are evaluated in the vertex shader unless it is unavailable, instancing is
+    // unsupported or `--cpu-animation` is given. Baked animations take a draw call per frame
+    // their instances are ahead rather than one per animation.
+    let mut animated_program = None;
+    if instancing && !std::env::args().any(|arg| arg == "--cpu-animation") {
+        let embedded: Vec<Vec<u8>> = EMBEDDED_ANIMATED_SHADERS.iter().map(|source| source.to_vec()).collect();
+        animated_program = match read_shaders(&ANIMATED_SHADERS).and_then(|sources| link(factory, &sources)).or_else(|_| link(factory, &embedded)) {
+            Ok(program) => Some(program),
+            Err(e) => {
+                log!(Level::Warn, "rendering", "animating on the CPU"; reason = e);
+                None
+            }
+        };
+    }
+    let gpu_animation = animated_program.is_some();
+
+    let manifest_path = assets_path.join("manifest.txt");
+    let loaded = Manifest::load(&manifest_path)
+        .and_then(|manifest| world::load_animations(&manifest, gpu_animation, factory).map(|animations| (manifest, animations)))
+        .and_then(|(manifest, animations)| world::World::example(animations, instancing, factory).map(|world| (manifest, world)));
+    let (mut manifest, mut my_world) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log!(Level::Error, "rendering", "can't load assets"; reason = e.to_string());
+            std::process::exit(1);
+        }
     };
+    log!(Level::Info, "rendering", "animations loaded"; count = my_world.animations.len(), assets = assets_path);
+
+    // Changed assets are reloaded while the client is running
+    let mut watcher = Watcher::new();
+    watcher.watch(&manifest_path);
+    for asset in manifest.assets.iter() {
+        watcher.watch(&asset.file);
+    }
+    for name in SHADERS.iter().chain(INSTANCED_SHADERS.iter()).chain(ANIMATED_SHADERS.iter()) {
+        watcher.watch(shaders.join(name));
+    }
 
     let mut data = Params {
         u_model_view_proj: vecmath::mat4_id(),
+        t_color: (texture.clone(), Some(sampler.clone())),
+        _r: std::marker::PhantomData,
+    };
+    let mut instanced_data = InstancedParams {
+        u_view_proj: vecmath::mat4_id(),
+        t_color: (texture.clone(), Some(sampler.clone())),
+        _r: std::marker::PhantomData,
+    };
+    let mut animated_data = AnimatedParams {
+        u_view_proj: vecmath::mat4_id(),
+        u_time: 0.0,
         t_color: (texture, Some(sampler)),
         _r: std::marker::PhantomData,
     };
@@ -102,12 +351,57 @@ fn main() {
     let state = gfx::DrawState::new().depth(gfx::state::Comparison::LessEqual, true);
 
 
+    let mut chat = ChatView::new();
+    let mut title = String::new();
     for e in events {
-        first_person.event(&e);
+        // Keys typed into the chat don't move the player
+        if !chat.typing() { first_person.event(&e); }
+
+        let changed = watcher.poll();
+        if !changed.is_empty() {
+            log!(Level::Info, "assets", "files changed"; files = changed);
+            let mut files = changed.clone();
+            if changed.contains(&manifest_path) {
+                match Manifest::load(&manifest_path) {
+                    Ok(reloaded) => {
+                        manifest = reloaded;
+                        // The manifest may point to different files or override their settings
+                        for asset in manifest.assets.iter() {
+                            watcher.watch(&asset.file);
+                            files.push(asset.file.clone());
+                        }
+                    },
+                    Err(e) => log!(Level::Error, "assets", "keeping previous manifest"; reason = e.to_string())
+                }
+            }
+            for e in world::reload_animations(&mut my_world.animations, &manifest, &files, gpu_animation, factory) {
+                log!(Level::Error, "assets", "keeping previous animation"; reason = e.to_string());
+            }
+            my_world.update_instances(factory);
+            if changed.iter().any(|file| file.starts_with(&shaders)) {
+                match read_shaders(&SHADERS).and_then(|sources| link(factory, &sources)) {
+                    Ok(reloaded) => program = reloaded,
+                    Err(e) => log!(Level::Error, "assets", "keeping previous shaders"; reason = e)
+                }
+                match read_shaders(&INSTANCED_SHADERS).and_then(|sources| link(factory, &sources)) {
+                    Ok(reloaded) => instanced_program = reloaded,
+                    Err(e) => log!(Level::Error, "assets", "keeping previous instancing shaders"; reason = e)
+                }
+                if gpu_animation {
+                    match read_shaders(&ANIMATED_SHADERS).and_then(|sources| link(factory, &sources)) {
+                        Ok(reloaded) => animated_program = Some(reloaded),
+                        Err(e) => log!(Level::Error, "assets", "keeping previous animation shaders"; reason = e)
+                    }
+                }
+            }
+        }
+
+        e.text(|text| chat.type_text(text));
 
         e.press(|key| {
             // let i: i8 = key;
             match key {
+                input::Button::Keyboard(k) if chat.typing() => chat.press(k, &mut *server),
                 input::Button::Keyboard(k) => {
                     match k {
                         input::Key::W => my_world.player.move_player(0, 1.3),
@@ -116,6 +410,12 @@ fn main() {
                         input::Key::D => my_world.player.move_player(3, 1.3),
                         input::Key::Space => my_world.player.move_player(4, 5.0),
                         //input::Key::LShift => my_world.player.move_player(5, 1.0),
+                        input::Key::P => server.ping(my_world.player.ground_position()),
+                        input::Key::Return => chat.draft = Some((Channel::All, String::new())),
+                        input::Key::Left => scrub(&mut replay, -1),
+                        input::Key::Right => scrub(&mut replay, 1),
+                        input::Key::Down => scrub(&mut replay, -10),
+                        input::Key::Up => scrub(&mut replay, 10),
                         _ => {}
                     }
                 }
@@ -145,6 +445,16 @@ fn main() {
             my_world.player.rotate_player(x as f32/150f32, y as f32/150f32);
         });
 
+        for message in server.messages() {
+            log!(Level::Info, "chat", "message"; sender = message.sender, channel = message.channel, text = message.text);
+            chat.receive(&message);
+        }
+        let shown = chat.title();
+        if shown != title {
+            e.set_title(shown.clone());
+            title = shown;
+        }
+
         e.draw_3d(|stream| {
             //let a = TimeDiff::start();
 
@@ -159,14 +469,28 @@ fn main() {
 
             let view = my_world.get_view_matrix();
 
-            for (mesh, slice, model) in my_world.update().iter().cloned() {
-                data.u_model_view_proj = model_view_projection(
-                    model,
-                    view,
-                    projection
-                );
-
-                stream.draw(&(mesh, slice, &program, &data, &state)).unwrap();
+            // Static objects are instanced if the device supports it, their model matrices are part of the instances
+            let view_proj = model_view_projection(
+                vecmath::mat4_id(),
+                view,
+                projection
+            );
+            for batch in my_world.update(&mut *server) {
+                match (batch.model, batch.time, animated_program.as_ref()) {
+                    (Some(model), _, _) => {
+                        data.u_model_view_proj = model_view_projection(model, view, projection);
+                        stream.draw(&(&batch.mesh, batch.slice, &program, &data, &state)).unwrap();
+                    },
+                    (None, Some(time), Some(animated_program)) => {
+                        animated_data.u_view_proj = view_proj;
+                        animated_data.u_time = time;
+                        stream.draw(&(&batch.mesh, batch.slice, animated_program, &animated_data, &state)).unwrap();
+                    },
+                    _ => {
+                        instanced_data.u_view_proj = view_proj;
+                        stream.draw(&(&batch.mesh, batch.slice, &instanced_program, &instanced_data, &state)).unwrap();
+                    }
+                }
             }
             //a.end();
 
@@ -190,9 +514,42 @@ fn main() {
 
             stream.draw(&(&mesh, slice, &program, &data, &state)).unwrap();
 
+            // Pings of the team are shown as pillars around the time they mark, so are the units of a replay
+            let mut markers: Vec<((f32, f32), [f32; 4], f32)> = Vec::new();
+            match replay {
+                Some(ref mut replay) => {
+                    for (owner, location) in replay.units() {
+                        let color = if owner % 2 == 0 { [0.1, 0.3, 0.9, 1.0] } else { [0.9, 0.5, 0.1, 1.0] };
+                        markers.push((location, color, 1.5));
+                    }
+                    let present = replay.time();
+                    markers.extend(replay.pings().iter().filter_map(|ping| ping_marker(ping, present)));
+                },
+                None => {
+                    let present = server.present();
+                    markers.extend(server.pings().iter().filter_map(|ping| ping_marker(ping, present)));
+                }
+            }
+            let mut marker_data = Vec::new();
+            let mut marker_slice: Vec<u32> = Vec::new();
+            for (i, &((x, z), color, height)) in markers.iter().enumerate() {
+                marker_data.push(Vertex::new(x - 0.1, 0.0, z, color));
+                marker_data.push(Vertex::new(x + 0.1, 0.0, z, color));
+                marker_data.push(Vertex::new(x + 0.1, height, z, color));
+                marker_data.push(Vertex::new(x - 0.1, height, z, color));
+                let base = (i * 4) as u32;
+                marker_slice.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 3, base]);
+            }
+            if !marker_data.is_empty() {
+                let mesh = factory.create_mesh(&marker_data);
+                let slice = marker_slice[..].to_slice(factory, gfx::PrimitiveType::TriangleList);
+                stream.draw(&(&mesh, slice, &program, &data, &state)).unwrap();
+            }
+
         });
 
-        if let Some(_) = e.resize_args() {
+        if let Some(size) = e.resize_args() {
+            log!(Level::Debug, "rendering", "window resized"; size = size);
             projection = get_projection(&e);
         }
     }
@@ -1,17 +1,163 @@
-fn factorial(value: usize) -> usize {
-    if value == 0 { 1 }
-    else { value * factorial(value-1) }
+//! Curves used for animations and unit paths. Every curve is parameterized from 0 at its start
+//! to 1 at its end, derivatives are taken with respect to that parameter.
+pub type Point = [f32; 3];
+
+/// Evaluates a bezier curve by repeated linear interpolation, which stays stable for any number
+/// of control points unlike summing up binomial coefficients. Without points it stays at the origin.
+pub fn de_casteljau(t: f32, points: &[Point]) -> Point {
+    if points.is_empty() { return [0.0; 3] }
+    let mut points = points.to_vec();
+    for n in (1..points.len()).rev() {
+        for i in 0..n {
+            points[i] = lerp(points[i], points[i + 1], t);
+        }
+    }
+    points[0]
+}
+
+/// Derivative of a bezier curve, pointing in the direction it moves
+#[allow(dead_code)]
+pub fn bezier_derivative(t: f32, points: &[Point]) -> Point {
+    if points.len() < 2 { return [0.0; 3] }
+    let n = (points.len() - 1) as f32;
+    let differences: Vec<Point> = points.windows(2).map(|pair| scale(sub(pair[1], pair[0]), n)).collect();
+    de_casteljau(t, &differences)
+}
+
+/// Catmull-Rom spline passing through every point, reaching one of them every `1 / (n - 1)`
+#[allow(dead_code)]
+pub fn catmull_rom(t: f32, points: &[Point]) -> Point {
+    spline(t, points, 1, catmull_rom_weights).0
+}
+
+#[allow(dead_code)]
+pub fn catmull_rom_derivative(t: f32, points: &[Point]) -> Point {
+    spline(t, points, 1, catmull_rom_weights).1
+}
+
+/// Uniform cubic B-spline, smoother than Catmull-Rom but only passing through the first and
+/// last point
+#[allow(dead_code)]
+pub fn b_spline(t: f32, points: &[Point]) -> Point {
+    spline(t, points, 2, b_spline_weights).0
 }
 
-fn calculate_binominal_coefficient(n: usize, k: usize) -> usize {
-    factorial(n) / (factorial(k) * factorial(n-k))
+#[allow(dead_code)]
+pub fn b_spline_derivative(t: f32, points: &[Point]) -> Point {
+    spline(t, points, 2, b_spline_weights).1
 }
 
-pub fn calculate_bezier(t:f32, points: Vec<f32>) -> f32 {
-    let n = points.len()-1;
-    points.iter().enumerate().map(|(k, x)| {
-        calculate_binominal_coefficient(n, k) as f32 * (1.0-t).powi((n-k) as i32) * t.powi(k as i32) * x
-    }).fold(0f32, |sum, x| sum + x)
+/// Maps distances along a curve to its parameter, so it can be traversed at constant speed
+pub struct ArcLength {
+    /// Distance covered at evenly spaced parameters, from 0 to the length of the curve
+    distances: Vec<f32>
+}
+
+#[allow(dead_code)]
+impl ArcLength {
+    /// Approximates the curve by `samples` straight segments
+    pub fn new<F: Fn(f32) -> Point>(curve: F, samples: usize) -> ArcLength {
+        let samples = samples.max(1);
+        let mut distances = vec![0.0];
+        let mut previous = curve(0.0);
+        for i in 1..samples + 1 {
+            let point = curve(i as f32 / samples as f32);
+            let distance = distances[i - 1] + length(sub(point, previous));
+            distances.push(distance);
+            previous = point;
+        }
+        ArcLength { distances: distances }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.distances[self.distances.len() - 1]
+    }
+
+    /// Parameter at which the given distance has been covered, clamped to the curve. A NaN
+    /// distance, or a curve that isn't finite, stays at the start.
+    pub fn parameter(&self, distance: f32) -> f32 {
+        let samples = (self.distances.len() - 1) as f32;
+        if distance.is_nan() || !self.length().is_finite() || distance <= 0.0 { return 0.0 }
+        if distance >= self.length() { return 1.0 }
+        let i = match self.distances.binary_search_by(|d| d.total_cmp(&distance)) {
+            Ok(i) => return i as f32 / samples,
+            Err(i) => i - 1
+        };
+        let segment = self.distances[i + 1] - self.distances[i];
+        (i as f32 + (distance - self.distances[i]) / segment) / samples
+    }
+}
+
+/// Position and derivative of a piecewise cubic spline. The first and last point are repeated
+/// `padding` times so the spline reaches them. Without points it stays at the origin.
+fn spline(t: f32, points: &[Point], padding: usize, weights: fn(f32) -> ([f32; 4], [f32; 4])) -> (Point, Point) {
+    if points.is_empty() { return ([0.0; 3], [0.0; 3]) }
+    let last = points.len() - 1;
+    let point = |i: usize| points[i.saturating_sub(padding).min(last)];
+    let segments = last + 2 * padding - 2;
+    if segments == 0 { return (points[0], [0.0; 3]) }
+
+    let position = t.clamp(0.0, 1.0) * segments as f32;
+    let segment = (position as usize).min(segments - 1);
+    let (weights, derivatives) = weights(position - segment as f32);
+    let mut result = ([0.0; 3], [0.0; 3]);
+    for k in 0..4 {
+        let p = point(segment + k);
+        result.0 = add(result.0, scale(p, weights[k]));
+        // The derivative is with respect to the whole spline, not the segment
+        result.1 = add(result.1, scale(p, derivatives[k] * segments as f32));
+    }
+    result
+}
+
+fn catmull_rom_weights(t: f32) -> ([f32; 4], [f32; 4]) {
+    let (t2, t3) = (t * t, t * t * t);
+    ([
+        0.5 * (-t + 2.0 * t2 - t3),
+        0.5 * (2.0 - 5.0 * t2 + 3.0 * t3),
+        0.5 * (t + 4.0 * t2 - 3.0 * t3),
+        0.5 * (-t2 + t3)
+    ], [
+        0.5 * (-1.0 + 4.0 * t - 3.0 * t2),
+        0.5 * (-10.0 * t + 9.0 * t2),
+        0.5 * (1.0 + 8.0 * t - 9.0 * t2),
+        0.5 * (-2.0 * t + 3.0 * t2)
+    ])
+}
+
+fn b_spline_weights(t: f32) -> ([f32; 4], [f32; 4]) {
+    let (t2, t3, u) = (t * t, t * t * t, 1.0 - t);
+    ([
+        u * u * u / 6.0,
+        (3.0 * t3 - 6.0 * t2 + 4.0) / 6.0,
+        (-3.0 * t3 + 3.0 * t2 + 3.0 * t + 1.0) / 6.0,
+        t3 / 6.0
+    ], [
+        -u * u / 2.0,
+        (3.0 * t2 - 4.0 * t) / 2.0,
+        (-3.0 * t2 + 2.0 * t + 1.0) / 2.0,
+        t2 / 2.0
+    ])
+}
+
+fn lerp(a: Point, b: Point, t: f32) -> Point {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+fn add(a: Point, b: Point) -> Point {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: Point, b: Point) -> Point {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: Point, factor: f32) -> Point {
+    [a[0] * factor, a[1] * factor, a[2] * factor]
+}
+
+fn length(a: Point) -> f32 {
+    (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt()
 }
 
 pub fn max(a: f32, b: f32) -> f32 {
@@ -23,3 +169,81 @@ pub fn min(a: f32, b: f32) -> f32 {
     if a < b {a}
     else {b}
 }
+
+#[cfg(test)]
+fn assert_close(a: Point, b: Point) {
+    assert!((0..3).all(|axis| (a[axis] - b[axis]).abs() < 1e-3), "{:?} != {:?}", a, b);
+}
+
+#[test]
+fn bezier_curves() {
+    let points = [[0.0, 0.0, 0.0], [1.0, 2.0, 0.0], [3.0, 2.0, 1.0], [4.0, 0.0, 0.0]];
+    assert_eq!(de_casteljau(0.0, &points), points[0]);
+    assert_eq!(de_casteljau(1.0, &points), points[3]);
+    // Weights 1/8, 3/8, 3/8, 1/8
+    assert_close(de_casteljau(0.5, &points), [2.0, 1.5, 0.375]);
+
+    // Far more control points than binomial coefficients in a usize allow
+    let many: Vec<Point> = (0..40).map(|_| [1.0, -2.0, 0.5]).collect();
+    assert_close(de_casteljau(0.3, &many), [1.0, -2.0, 0.5]);
+    assert_eq!(de_casteljau(0.5, &[]), [0.0; 3]);
+    assert_eq!(bezier_derivative(0.5, &[]), [0.0; 3]);
+
+    // The derivative of a line is its direction, and matches finite differences in general
+    assert_eq!(bezier_derivative(0.25, &[[0.0, 0.0, 0.0], [2.0, 1.0, 0.0]]), [2.0, 1.0, 0.0]);
+    for &t in [0.1, 0.5, 0.9].iter() {
+        let h = 1e-3;
+        let difference = scale(sub(de_casteljau(t + h, &points), de_casteljau(t - h, &points)), 0.5 / h);
+        assert_close(bezier_derivative(t, &points), difference);
+    }
+}
+
+#[test]
+fn splines() {
+    let points = [[0.0, 0.0, 0.0], [1.0, 0.0, 1.0], [2.0, 1.0, 0.0], [4.0, 0.0, 0.0]];
+
+    // Catmull-Rom passes through every point, with the tangent pointing from its neighbours
+    for (i, point) in points.iter().enumerate() {
+        assert_close(catmull_rom(i as f32 / 3.0, &points), *point);
+    }
+    assert_close(catmull_rom_derivative(1.0 / 3.0, &points), scale(sub(points[2], points[0]), 0.5 * 3.0));
+
+    // B-splines only reach the ends, but reproduce evenly spaced lines exactly
+    assert_close(b_spline(0.0, &points), points[0]);
+    assert_close(b_spline(1.0, &points), points[3]);
+    let line: Vec<Point> = (0..5).map(|i| [i as f32, 0.0, 0.0]).collect();
+    assert_close(b_spline(0.5, &line), [2.0, 0.0, 0.0]);
+
+    for &t in [0.2, 0.5, 0.7].iter() {
+        let h = 1e-3;
+        let catmull_rom_difference = scale(sub(catmull_rom(t + h, &points), catmull_rom(t - h, &points)), 0.5 / h);
+        assert_close(catmull_rom_derivative(t, &points), catmull_rom_difference);
+        let b_spline_difference = scale(sub(b_spline(t + h, &points), b_spline(t - h, &points)), 0.5 / h);
+        assert_close(b_spline_derivative(t, &points), b_spline_difference);
+    }
+
+    // Degenerate paths stay where they are
+    assert_eq!(catmull_rom(0.5, &[[1.0, 2.0, 3.0]]), [1.0, 2.0, 3.0]);
+    assert_eq!(b_spline(0.5, &[[1.0, 2.0, 3.0]]), [1.0, 2.0, 3.0]);
+    assert_eq!(catmull_rom(0.5, &[]), [0.0; 3]);
+    assert_eq!(b_spline_derivative(0.5, &[]), [0.0; 3]);
+}
+
+#[test]
+fn arc_length() {
+    let line = ArcLength::new(|t| [3.0 * t, 4.0 * t, 0.0], 10);
+    assert!((line.length() - 5.0).abs() < 1e-5);
+    assert!((line.parameter(2.5) - 0.5).abs() < 1e-5);
+    assert_eq!((line.parameter(-1.0), line.parameter(10.0)), (0.0, 1.0));
+    assert_eq!(line.parameter(f32::NAN), 0.0);
+    let broken = ArcLength::new(|t| [t, f32::NAN, 0.0], 10);
+    assert_eq!(broken.parameter(0.5), 0.0);
+
+    // A quarter circle as a bezier curve, which moves faster in the middle than at the ends
+    let k = 0.552_284_8;
+    let quarter = [[1.0, 0.0, 0.0], [1.0, k, 0.0], [k, 1.0, 0.0], [0.0, 1.0, 0.0]];
+    let arc = ArcLength::new(|t| de_casteljau(t, &quarter), 200);
+    assert!((arc.length() - ::std::f32::consts::FRAC_PI_2).abs() < 1e-3);
+    let halfway = de_casteljau(arc.parameter(arc.length() / 2.0), &quarter);
+    assert_close(halfway, [0.5f32.sqrt(), 0.5f32.sqrt(), 0.0]);
+}
@@ -0,0 +1,530 @@
+//! Parsing and writing of `.3d` animation files, independent of the GPU so assets can be checked
+//! and generated without a window. Every vertex is animated along a closed bezier curve through
+//! its control points, which is sampled into `frames` meshes played over `duration` seconds.
+//!
+//! ```text
+//! 3d <version>
+//! collision <radius> <height>
+//! frames <number of frames>
+//! duration <seconds>
+//! g <r> <g> <b> [<a>]                       material of the following vertices
+//! v <x> <y> <z> <x> <y> <z> ...             control points of a vertex, at least two
+//! f <vertex> <vertex> <vertex>              triangle, vertices counted from 0 across the file
+//! ```
+//!
+//! Legacy files without a version start with the collision radius and height on a line each
+//! and are played with `LEGACY_FRAMES` over `LEGACY_DURATION`.
+//!
+//! The same model can be stored in a checksummed binary form that decodes about five times faster
+//! than parsing the text, as no numbers have to be parsed. Everything is little-endian and 4 byte
+//! aligned:
+//!
+//! ```text
+//! 0   "TW3D"                  magic
+//! 4   u32                     binary version
+//! 8   u64                     FNV-1a hash of everything following it
+//! 16  f32 f32                 collision radius and height
+//! 24  u32 f32                 frames and duration
+//! 32  u32 u32 u32             number of vertices, control points and faces
+//! 44  u32 * (vertices + 1)    index of the first control point of every vertex, then the total
+//!     f32 * 3 * points        control points
+//!     f32 * 4 * vertices      materials
+//!     u32 * 3 * faces         triangles
+//! ```
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Version written by `Model::write`, files without a version are read as version 1
+pub const VERSION: u32 = 2;
+pub const BINARY_MAGIC: &[u8; 4] = b"TW3D";
+pub const BINARY_VERSION: u32 = 1;
+pub const LEGACY_FRAMES: usize = 300;
+pub const LEGACY_DURATION: f32 = 2.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Model {
+    pub version: u32,
+    pub collision_radius: f32,
+    pub collision_y: f32,
+    /// Number of meshes the animation is sampled into
+    pub frames: usize,
+    /// Seconds one loop of the animation takes
+    pub duration: f32,
+    /// Control points of every vertex
+    pub vertices: Vec<Vec<[f32; 3]>>,
+    /// Material of every vertex
+    pub materials: Vec<[f32; 4]>,
+    pub faces: Vec<[u32; 3]>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    Io(String),
+    NotUtf8,
+    UnsupportedVersion(u32),
+    /// The header line is missing, naming what was expected
+    MissingHeader(&'static str),
+    DuplicateHeader(&'static str),
+    InvalidNumber(String),
+    /// A number is valid but makes no sense where it is used, e.g. zero frames
+    OutOfRange(String),
+    /// A directive got the wrong number of values
+    WrongCount { directive: &'static str, expected: &'static str, found: usize },
+    UnknownDirective(String),
+    /// A face refers to a vertex that doesn't exist
+    UnknownVertex(u32),
+    /// A binary file is shorter or longer than its counts say
+    WrongSize { expected: usize, found: usize },
+    NotBinary,
+    ChecksumMismatch,
+    /// A binary file's control point table is out of order or gives a vertex less than two points
+    InvalidPoints(u32)
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::Io(ref e) => write!(f, "can't read file: {}", e),
+            Reason::NotUtf8 => write!(f, "not valid UTF-8"),
+            Reason::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Reason::MissingHeader(expected) => write!(f, "missing {}", expected),
+            Reason::DuplicateHeader(header) => write!(f, "'{}' given more than once", header),
+            Reason::InvalidNumber(ref value) => write!(f, "invalid number '{}'", value),
+            Reason::OutOfRange(ref value) => write!(f, "'{}' is out of range", value),
+            Reason::WrongCount { directive, expected, found } =>
+                write!(f, "'{}' expects {} values, found {}", directive, expected, found),
+            Reason::UnknownDirective(ref directive) => write!(f, "unknown directive '{}'", directive),
+            Reason::UnknownVertex(vertex) => write!(f, "face refers to unknown vertex {}", vertex),
+            Reason::WrongSize { expected, found } => write!(f, "expected {} bytes, found {}", expected, found),
+            Reason::NotBinary => write!(f, "not a binary model"),
+            Reason::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Reason::InvalidPoints(vertex) => write!(f, "invalid control points of vertex {}", vertex)
+        }
+    }
+}
+
+/// What went wrong where, lines are counted from 1 and 0 refers to the whole file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,
+    pub reason: Reason
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.reason)
+    }
+}
+
+impl Model {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Model, ParseError> {
+        let file = path.as_ref().display().to_string();
+        let error = |reason| ParseError { file: file.clone(), line: 0, reason: reason };
+        let mut contents = Vec::new();
+        File::open(path.as_ref()).and_then(|mut f| f.read_to_end(&mut contents))
+            .map_err(|e| error(Reason::Io(e.to_string())))?;
+        Model::from_bytes(&file, &contents)
+    }
+
+    /// Reads either format, telling them apart by the magic of the binary one
+    pub fn from_bytes(file: &str, contents: &[u8]) -> Result<Model, ParseError> {
+        if contents.starts_with(BINARY_MAGIC) { return Model::decode(file, contents) }
+        let text = ::std::str::from_utf8(contents)
+            .map_err(|_| ParseError { file: file.to_string(), line: 0, reason: Reason::NotUtf8 })?;
+        Model::parse(file, text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn save_binary<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(&self.encode())
+    }
+
+    /// Writes the model in the current version, whatever version it was read from
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "3d {}", VERSION)?;
+        writeln!(writer, "collision {} {}", self.collision_radius, self.collision_y)?;
+        writeln!(writer, "frames {}", self.frames)?;
+        writeln!(writer, "duration {}", self.duration)?;
+        let mut current = None;
+        for (points, material) in self.vertices.iter().zip(self.materials.iter()) {
+            if current != Some(material) {
+                writeln!(writer, "g {} {} {} {}", material[0], material[1], material[2], material[3])?;
+                current = Some(material);
+            }
+            let values: Vec<String> = points.iter().flat_map(|p| p.iter()).map(|value| value.to_string()).collect();
+            writeln!(writer, "v {}", values.join(" "))?;
+        }
+        for face in self.faces.iter() {
+            writeln!(writer, "f {} {} {}", face[0], face[1], face[2])?;
+        }
+        Ok(())
+    }
+
+    /// Parses the contents of a file, `file` only being used for error messages
+    pub fn parse(file: &str, text: &str) -> Result<Model, ParseError> {
+        let error = |line, reason| ParseError { file: file.to_string(), line: line, reason: reason };
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())).peekable();
+
+        let mut model = Model {
+            version: 1,
+            collision_radius: 0.0,
+            collision_y: 0.0,
+            frames: LEGACY_FRAMES,
+            duration: LEGACY_DURATION,
+            vertices: Vec::new(),
+            materials: Vec::new(),
+            faces: Vec::new()
+        };
+        let versioned = lines.peek().is_some_and(|&(_, line)| line.split_whitespace().next() == Some("3d"));
+        if versioned {
+            let (n, line) = lines.next().unwrap();
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(error(n, Reason::WrongCount { directive: "3d", expected: "1", found: parts.len() - 1 }))
+            }
+            model.version = parts[1].parse().map_err(|_| error(n, Reason::InvalidNumber(parts[1].to_string())))?;
+            if model.version != VERSION {
+                return Err(error(n, Reason::UnsupportedVersion(model.version)))
+            }
+        } else {
+            let mut header = |expected| match lines.next() {
+                Some((n, line)) if !line.is_empty() => number(line).map_err(|reason| error(n, reason)),
+                Some((n, _)) => Err(error(n, Reason::MissingHeader(expected))),
+                None => Err(error(0, Reason::MissingHeader(expected)))
+            };
+            model.collision_radius = header("collision radius")?;
+            model.collision_y = header("collision height")?;
+        }
+
+        let mut material = [0.0, 0.0, 0.0, 1.0];
+        let mut faces = Vec::new();
+        let mut headers = Vec::new();
+        for (n, line) in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let values = || parts[1..].iter().map(|value| number(value)).collect::<Result<Vec<f32>, Reason>>();
+            let directive = parts.first().cloned();
+            if let Some(&header) = HEADERS.iter().find(|&&header| versioned && directive == Some(header)) {
+                if headers.contains(&header) { return Err(error(n, Reason::DuplicateHeader(header))) }
+                headers.push(header);
+            }
+            match directive {
+                None => {},
+                Some("collision") if versioned => {
+                    let values = values().map_err(|reason| error(n, reason))?;
+                    if values.len() != 2 {
+                        return Err(error(n, Reason::WrongCount { directive: "collision", expected: "2", found: values.len() }))
+                    }
+                    model.collision_radius = values[0];
+                    model.collision_y = values[1];
+                },
+                Some("frames") if versioned => {
+                    if parts.len() != 2 {
+                        return Err(error(n, Reason::WrongCount { directive: "frames", expected: "1", found: parts.len() - 1 }))
+                    }
+                    model.frames = parts[1].parse().map_err(|_| error(n, Reason::InvalidNumber(parts[1].to_string())))?;
+                    if model.frames == 0 { return Err(error(n, Reason::OutOfRange(parts[1].to_string()))) }
+                },
+                Some("duration") if versioned => {
+                    let values = values().map_err(|reason| error(n, reason))?;
+                    if values.len() != 1 {
+                        return Err(error(n, Reason::WrongCount { directive: "duration", expected: "1", found: values.len() }))
+                    }
+                    if values[0] <= 0.0 { return Err(error(n, Reason::OutOfRange(parts[1].to_string()))) }
+                    model.duration = values[0];
+                },
+                Some("g") => {
+                    let values = values().map_err(|reason| error(n, reason))?;
+                    if values.len() != 3 && values.len() != 4 {
+                        return Err(error(n, Reason::WrongCount { directive: "g", expected: "3 or 4", found: values.len() }))
+                    }
+                    material = [values[0], values[1], values[2], values.get(3).cloned().unwrap_or(1.0)];
+                },
+                Some("v") => {
+                    let values = values().map_err(|reason| error(n, reason))?;
+                    // The curve is closed using the tangent at the first point, which needs two of them
+                    if values.len() < 6 || !values.len().is_multiple_of(3) {
+                        return Err(error(n, Reason::WrongCount { directive: "v", expected: "a multiple of 3, at least 6", found: values.len() }))
+                    }
+                    model.vertices.push(values.chunks(3).map(|p| [p[0], p[1], p[2]]).collect());
+                    model.materials.push(material);
+                },
+                Some("f") => {
+                    if parts.len() != 4 {
+                        return Err(error(n, Reason::WrongCount { directive: "f", expected: "3", found: parts.len() - 1 }))
+                    }
+                    let mut face = [0; 3];
+                    for (i, value) in parts[1..].iter().enumerate() {
+                        face[i] = value.parse().map_err(|_| error(n, Reason::InvalidNumber(value.to_string())))?;
+                    }
+                    faces.push((n, face));
+                },
+                Some(directive) => return Err(error(n, Reason::UnknownDirective(directive.to_string())))
+            }
+        }
+
+        if let Some(missing) = HEADERS.iter().find(|header| versioned && !headers.contains(header)) {
+            return Err(error(0, Reason::MissingHeader(missing)))
+        }
+
+        // Faces may refer to vertices defined further down
+        for (n, face) in faces {
+            if let Some(&vertex) = face.iter().find(|&&vertex| vertex as usize >= model.vertices.len()) {
+                return Err(error(n, Reason::UnknownVertex(vertex)))
+            }
+            model.faces.push(face);
+        }
+        Ok(model)
+    }
+}
+
+/// Header directives of versioned files, each of which has to be given exactly once
+const HEADERS: [&str; 3] = ["collision", "frames", "duration"];
+
+fn number(value: &str) -> Result<f32, Reason> {
+    match value.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(Reason::InvalidNumber(value.to_string()))
+    }
+}
+
+// ---------------- Binary ----------------
+impl Model {
+    pub fn encode(&self) -> Vec<u8> {
+        let points: usize = self.vertices.iter().map(|points| points.len()).sum();
+        let mut body = Vec::with_capacity(32 + 4 * (self.vertices.len() * 5 + points * 3 + self.faces.len() * 3));
+        body.extend_from_slice(&self.collision_radius.to_le_bytes());
+        body.extend_from_slice(&self.collision_y.to_le_bytes());
+        body.extend_from_slice(&(self.frames as u32).to_le_bytes());
+        body.extend_from_slice(&self.duration.to_le_bytes());
+        for count in [self.vertices.len(), points, self.faces.len()].iter() {
+            body.extend_from_slice(&(*count as u32).to_le_bytes());
+        }
+        let mut offset = 0;
+        for vertex in self.vertices.iter() {
+            body.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += vertex.len();
+        }
+        body.extend_from_slice(&(offset as u32).to_le_bytes());
+        for value in self.vertices.iter().flat_map(|points| points.iter()).flat_map(|point| point.iter()) {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in self.materials.iter().flat_map(|material| material.iter()) {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in self.faces.iter().flat_map(|face| face.iter()) {
+            body.extend_from_slice(&index.to_le_bytes());
+        }
+
+        let mut bytes = Vec::with_capacity(body.len() + 16);
+        bytes.extend_from_slice(BINARY_MAGIC);
+        bytes.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&fnv(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    /// Decodes the binary form into owned buffers
+    pub fn decode(file: &str, bytes: &[u8]) -> Result<Model, ParseError> {
+        let error = |reason| ParseError { file: file.to_string(), line: 0, reason: reason };
+        let size = |expected: usize| if bytes.len() == expected { Ok(()) } else {
+            Err(error(Reason::WrongSize { expected: expected, found: bytes.len() }))
+        };
+        if !bytes.starts_with(BINARY_MAGIC) { return Err(error(Reason::NotBinary)) }
+        if bytes.len() < 44 { size(44)? }
+        let version = u32_at(bytes, 4);
+        if version != BINARY_VERSION { return Err(error(Reason::UnsupportedVersion(version))) }
+
+        let (vertices, points, faces) = (u32_at(bytes, 32) as usize, u32_at(bytes, 36) as usize, u32_at(bytes, 40) as usize);
+        let points_start = 48 + 4 * vertices;
+        let materials_start = points_start + 12 * points;
+        let faces_start = materials_start + 16 * vertices;
+        size(faces_start + 12 * faces)?;
+        if u64::from_le_bytes(array(&bytes[8..16])) != fnv(&bytes[16..]) { return Err(error(Reason::ChecksumMismatch)) }
+
+        // The same values the text form refuses to parse
+        let finite = |values: Vec<f32>| match values.iter().find(|value| !value.is_finite()) {
+            Some(value) => Err(error(Reason::InvalidNumber(value.to_string()))),
+            None => Ok(values)
+        };
+        let frames = u32_at(bytes, 24);
+        let header = finite(vec![f32_at(bytes, 16), f32_at(bytes, 20), f32_at(bytes, 28)])?;
+        let duration = header[2];
+        if frames == 0 { return Err(error(Reason::OutOfRange(frames.to_string()))) }
+        if duration <= 0.0 { return Err(error(Reason::OutOfRange(duration.to_string()))) }
+
+        let floats = |start: usize, count: usize| finite(bytes[start..start + 4 * count].chunks(4).map(|b| f32::from_le_bytes(array(b))).collect());
+        let offsets: Vec<usize> = (0..vertices + 1).map(|i| u32_at(bytes, 44 + 4 * i) as usize).collect();
+        let coordinates = floats(points_start, 3 * points)?;
+        let mut model = Model {
+            version: VERSION,
+            collision_radius: header[0],
+            collision_y: header[1],
+            frames: frames as usize,
+            duration: duration,
+            vertices: Vec::with_capacity(vertices),
+            materials: Vec::with_capacity(vertices),
+            faces: Vec::with_capacity(faces)
+        };
+        for (i, range) in offsets.windows(2).enumerate() {
+            if range[0] + 2 > range[1] || range[1] > points || (i == 0 && range[0] != 0) {
+                return Err(error(Reason::InvalidPoints(i as u32)))
+            }
+            model.vertices.push(coordinates[3 * range[0]..3 * range[1]].chunks(3).map(|p| [p[0], p[1], p[2]]).collect());
+        }
+        if offsets[vertices] != points { return Err(error(Reason::InvalidPoints(vertices as u32))) }
+        let materials = floats(materials_start, 4 * vertices)?;
+        model.materials.extend(materials.chunks(4).map(|m| [m[0], m[1], m[2], m[3]]));
+        for i in 0..faces {
+            let face = [u32_at(bytes, faces_start + 12 * i), u32_at(bytes, faces_start + 12 * i + 4), u32_at(bytes, faces_start + 12 * i + 8)];
+            if let Some(&vertex) = face.iter().find(|&&vertex| vertex as usize >= vertices) {
+                return Err(error(Reason::UnknownVertex(vertex)))
+            }
+            model.faces.push(face);
+        }
+        Ok(model)
+    }
+}
+
+fn array<T: Default + AsMut<[u8]>>(bytes: &[u8]) -> T {
+    let mut array = T::default();
+    array.as_mut().copy_from_slice(bytes);
+    array
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(array(&bytes[offset..offset + 4]))
+}
+
+fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(array(&bytes[offset..offset + 4]))
+}
+
+/// 64 bit FNV-1a, stable across platforms
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[test]
+fn parse_model() {
+    let model = Model::parse("tree.3d", "0.4\n2.6\ng 0.5 0.25 1\nv 0 1 2 3 4 5\nv 1 1 1 2 2 2\n\ng 1 1 1 0.5\nv 0 0 0 0 0 0\nf 0 1 2\n").unwrap();
+    assert_eq!((model.version, model.frames, model.duration), (1, LEGACY_FRAMES, LEGACY_DURATION));
+    assert_eq!(model.collision_radius, 0.4);
+    assert_eq!(model.collision_y, 2.6);
+    assert_eq!(model.vertices, vec![
+        vec![[0.0, 1.0, 2.0], [3.0, 4.0, 5.0]], vec![[1.0, 1.0, 1.0], [2.0, 2.0, 2.0]], vec![[0.0, 0.0, 0.0], [0.0, 0.0, 0.0]]
+    ]);
+    assert_eq!(model.materials, vec![[0.5, 0.25, 1.0, 1.0], [0.5, 0.25, 1.0, 1.0], [1.0, 1.0, 1.0, 0.5]]);
+    assert_eq!(model.faces, vec![[0, 1, 2]]);
+}
+
+#[test]
+fn versioned_models() {
+    let text = "3d 2\ncollision 0.5 1.25\nframes 60\nduration 1.5\ng 0.5 0.25 1\nv 0 1 2 3 4 5\nv 1 1 1 2 2 2\nf 0 1 1\n";
+    let model = Model::parse("knight.3d", text).unwrap();
+    assert_eq!((model.version, model.frames, model.duration), (2, 60, 1.5));
+    assert_eq!((model.collision_radius, model.collision_y), (0.5, 1.25));
+    assert_eq!(model.materials, vec![[0.5, 0.25, 1.0, 1.0]; 2]);
+
+    let mut written = Vec::new();
+    model.write(&mut written).unwrap();
+    assert_eq!(Model::parse("knight.3d", &String::from_utf8(written).unwrap()), Ok(model));
+
+    // Legacy files are written in the current version
+    let legacy = Model::parse("tree.3d", "0.4\n2.6\ng 1 0 0\nv 0 0 0 1 1 1\ng 0 1 0\nv 1 1 1 0 0 0\nf 0 1 0\n").unwrap();
+    let mut written = Vec::new();
+    legacy.write(&mut written).unwrap();
+    let upgraded = Model::parse("tree.3d", &String::from_utf8(written).unwrap()).unwrap();
+    assert_eq!(upgraded, Model { version: VERSION, ..legacy });
+
+    let reason = |text: &str| Model::parse("bad.3d", text).map(|_| ()).map_err(|e| (e.line, e.reason));
+    assert_eq!(reason("3d 3\n"), Err((1, Reason::UnsupportedVersion(3))));
+    assert_eq!(reason("3d\n"), Err((1, Reason::WrongCount { directive: "3d", expected: "1", found: 0 })));
+    assert_eq!(reason("3d 2\ncollision 1 1\nframes 10\n"), Err((0, Reason::MissingHeader("duration"))));
+    assert_eq!(reason("3d 2\ncollision 1 1\nframes 10\nframes 20\n"), Err((4, Reason::DuplicateHeader("frames"))));
+    assert_eq!(reason("3d 2\ncollision 1\n"), Err((2, Reason::WrongCount { directive: "collision", expected: "2", found: 1 })));
+    assert_eq!(reason("3d 2\nframes 0\n"), Err((2, Reason::OutOfRange("0".to_string()))));
+    assert_eq!(reason("3d 2\nduration -1\n"), Err((2, Reason::OutOfRange("-1".to_string()))));
+    assert_eq!(reason("1\n1\nframes 10\n"), Err((3, Reason::UnknownDirective("frames".to_string()))));
+}
+
+#[test]
+fn binary_models() {
+    let text = "3d 2\ncollision 0.5 1.25\nframes 60\nduration 1.5\ng 0.5 0.25 1\nv 0 1 2 3 4 5\nv 1 1 1 2 2 2 3 3 3\nf 0 1 1\n";
+    let model = Model::parse("knight.3d", text).unwrap();
+    let bytes = model.encode();
+    assert_eq!(bytes.len(), 44 + 4 * 3 + 12 * 5 + 16 * 2 + 12);
+    assert_eq!(Model::from_bytes("knight.3db", &bytes), Ok(model.clone()));
+
+    let reason = |bytes: &[u8]| Model::decode("bad.3db", bytes).unwrap_err().reason;
+    assert_eq!(reason(text.as_bytes()), Reason::NotBinary);
+    assert_eq!(reason(&bytes[..20]), Reason::WrongSize { expected: 44, found: 20 });
+    assert_eq!(reason(&bytes[..bytes.len() - 4]), Reason::WrongSize { expected: bytes.len(), found: bytes.len() - 4 });
+    let mut corrupted = bytes.clone();
+    corrupted[60] ^= 1;
+    assert_eq!(reason(&corrupted), Reason::ChecksumMismatch);
+    let mut future = bytes.clone();
+    future[4] = 2;
+    assert_eq!(reason(&future), Reason::UnsupportedVersion(2));
+
+    // Structurally broken files with a valid checksum
+    let rehash = |mut bytes: Vec<u8>| {
+        let hash = fnv(&bytes[16..]).to_le_bytes();
+        bytes[8..16].copy_from_slice(&hash);
+        bytes
+    };
+    let mut single_point = bytes.clone();
+    single_point[48] = 1;
+    assert_eq!(reason(&rehash(single_point)), Reason::InvalidPoints(0));
+    let mut face = bytes.clone();
+    let last = face.len() - 4;
+    face[last] = 2;
+    assert_eq!(reason(&rehash(face)), Reason::UnknownVertex(2));
+    let mut nan = bytes.clone();
+    nan[60..64].copy_from_slice(&f32::NAN.to_le_bytes());
+    assert_eq!(reason(&rehash(nan)), Reason::InvalidNumber("NaN".to_string()));
+    let mut infinite = bytes.clone();
+    infinite[20..24].copy_from_slice(&f32::INFINITY.to_le_bytes());
+    assert_eq!(reason(&rehash(infinite)), Reason::InvalidNumber("inf".to_string()));
+}
+
+#[test]
+fn malformed_models() {
+    let reason = |text: &str| Model::parse("bad.3d", text).map(|_| ()).map_err(|e| (e.line, e.reason));
+    assert_eq!(reason(""), Err((0, Reason::MissingHeader("collision radius"))));
+    assert_eq!(reason("0.4\n"), Err((0, Reason::MissingHeader("collision height"))));
+    assert_eq!(reason("0.4\n\nv 0 0 0"), Err((2, Reason::MissingHeader("collision height"))));
+    assert_eq!(reason("g 1 1 1\n"), Err((1, Reason::InvalidNumber("g 1 1 1".to_string()))));
+    assert_eq!(reason("1\n1\ng 1 1\n"), Err((3, Reason::WrongCount { directive: "g", expected: "3 or 4", found: 2 })));
+    let v = |found| Reason::WrongCount { directive: "v", expected: "a multiple of 3, at least 6", found: found };
+    assert_eq!(reason("1\n1\nv 1 2 3 4 5 6 7\n"), Err((3, v(7))));
+    assert_eq!(reason("1\n1\nv 1 2 3\n"), Err((3, v(3))));
+    assert_eq!(reason("1\n1\nv\n"), Err((3, v(0))));
+    assert_eq!(reason("1\n1\nv 1 2 3 1 2 x\n"), Err((3, Reason::InvalidNumber("x".to_string()))));
+    assert_eq!(reason("1\n1\nv 1 2 3 1 2 NaN\n"), Err((3, Reason::InvalidNumber("NaN".to_string()))));
+    assert_eq!(reason("1\n1\nv 0 0 0 0 0 0\nf 0 0\n"), Err((4, Reason::WrongCount { directive: "f", expected: "3", found: 2 })));
+    assert_eq!(reason("1\n1\nv 0 0 0 0 0 0\nf 0 0 -1\n"), Err((4, Reason::InvalidNumber("-1".to_string()))));
+    assert_eq!(reason("1\n1\nv 0 0 0 0 0 0\nf 0 0 1\n"), Err((4, Reason::UnknownVertex(1))));
+    assert_eq!(reason("1\n1\nm 0 0 0\n"), Err((3, Reason::UnknownDirective("m".to_string()))));
+
+    let error = Model::parse("bad.3d", "1\n1\nv 0 0 0 0 0 0\nf 0 0 7\n").unwrap_err();
+    assert_eq!(error.to_string(), "bad.3d:4: face refers to unknown vertex 7");
+    assert_eq!(Model::load("/nonexistent/tree.3d").unwrap_err().line, 0);
+}
+
+#[test]
+fn parse_bundled_models() {
+    for name in ["0.3d", "1.3d"].iter() {
+        let path = format!("{}/assets/3d/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let model = Model::load(&path).unwrap();
+        assert!(!model.faces.is_empty() && model.vertices.len() == model.materials.len());
+        assert_eq!(Model::decode(name, &model.encode()), Ok(Model { version: VERSION, ..model }));
+    }
+}
@@ -1,20 +1,107 @@
 #![allow(dead_code)]
 extern crate server as s;
+use self::s::lobby::{LobbyClient, LobbyError, MatchInfo, MatchSettings, Spectator};
+use self::s::session::Token;
+use self::s::movement::{Authority, Input};
+use self::s::chat::{Channel, Chat, Message, Participant, Ping};
+use self::s::transport::{fixed_size, Simulated, Transport};
+use self::s::replay::{Playback, Replay, ReplayError};
+use self::s::events::Event;
+use self::s::logging::Level;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+pub use self::s::transport::Conditions;
+use std::time::{Duration, Instant};
+use std::mem::size_of;
+pub use self::s::movement::{AvatarState, Obstacle, Prediction, PLAYER_HEIGHT};
 
 pub trait API {
     fn start_game(&mut self);
+
+    /// Hands all unacknowledged frames of first person movement to the server
+    fn send_inputs(&mut self, inputs: Vec<Input>);
+
+    /// Latest authoritative avatar state along with the sequence of the last input it includes
+    fn avatar_state(&mut self) -> Option<(u32, AvatarState)>;
+
+    /// Obstacles of the loaded map the avatar collides with
+    fn scenery(&mut self, obstacles: Vec<Obstacle>);
+
+    /// Present of the match
+    fn present(&mut self) -> s::TimeIndex;
+
+    /// Marks a location at the present time for the whole team
+    fn ping(&mut self, location: s::Coordinates);
+
+    fn say(&mut self, channel: Channel, text: &str);
+
+    /// Messages readable by the player that arrived since the last call
+    fn messages(&mut self) -> Vec<Message>;
+
+    /// Pings placed by the team
+    fn pings(&mut self) -> Vec<Ping>;
+
+    /// Events of the match that happened since the last call
+    fn events(&mut self) -> Vec<Event>;
 }
 
 // ----------------------------------------- LOCAL SERVER ----------------------------------------
 
-struct LocalServer {
-    server: s::Server
+/// What the client hands to the chat of a local server
+enum Chatter {
+    Say(Channel, String),
+    Ping(s::Coordinates, s::TimeIndex)
+}
+
+/// Server running in the same process. The connection to it can suffer from simulated
+/// network conditions to try out the netcode without a real network.
+pub struct LocalServer {
+    server: s::Server,
+    events: Receiver<Event>,
+    avatar: Authority,
+    chat: Chat,
+    /// Index of the next message to hand out
+    messages: usize,
+    upstream: Simulated<Vec<Input>>,
+    downstream: Simulated<(u32, AvatarState)>,
+    chatter: Simulated<Chatter>,
+    clock: Instant
 }
 
 impl LocalServer {
-    fn new() -> LocalServer {
+    fn new(conditions: Conditions) -> LocalServer {
+        let mut server = s::Server::new();
         LocalServer {
-            server: s::Server::new()
+            events: server.subscribe(),
+            server: server,
+            avatar: Authority::new(AvatarState::spawn()),
+            chat: Chat::new(vec![0]),
+            messages: 0,
+            upstream: Simulated::new(conditions, |inputs| inputs.len() * size_of::<Input>()),
+            downstream: Simulated::new(conditions.seed(2), fixed_size),
+            chatter: Simulated::new(conditions.seed(3), |chatter| match *chatter {
+                Chatter::Say(_, ref text) => size_of::<Chatter>() + text.len(),
+                Chatter::Ping(..) => size_of::<Chatter>()
+            }),
+            clock: Instant::now()
+        }
+    }
+
+    /// Moves the simulated connection along with the wall clock
+    fn advance(&mut self) {
+        let elapsed = self.clock.elapsed().as_millis() as u64;
+        self.clock += Duration::from_millis(elapsed);
+        self.upstream.advance(elapsed);
+        self.downstream.advance(elapsed);
+        self.chatter.advance(elapsed);
+        for chatter in self.chatter.receive() {
+            let result = match chatter {
+                Chatter::Say(channel, text) => self.chat.say(Participant::Player(0), channel, &text, self.server.present()),
+                Chatter::Ping(location, time) => self.chat.ping(0, location, time)
+            };
+            if let Err(e) = result {
+                log!(Level::Warn, "networking", "chat rejected"; error = e);
+            }
         }
     }
 }
@@ -23,38 +110,349 @@ impl API for LocalServer {
     fn start_game(&mut self) {
         self.server.start_game();
     }
+
+    fn send_inputs(&mut self, inputs: Vec<Input>) {
+        self.advance();
+        self.upstream.send(inputs);
+    }
+
+    fn avatar_state(&mut self) -> Option<(u32, AvatarState)> {
+        self.advance();
+        for inputs in self.upstream.receive() {
+            for input in inputs.iter() {
+                if !self.avatar.apply(input) {
+                    log!(Level::Trace, "networking", "input dropped"; sequence = input.sequence);
+                }
+            }
+        }
+        if let Some(snapshot) = self.avatar.snapshot() {
+            self.downstream.send(snapshot);
+        }
+        // Snapshots may arrive out of order, only the newest one matters
+        self.downstream.receive().into_iter().max_by_key(|snapshot| snapshot.0)
+    }
+
+    fn scenery(&mut self, obstacles: Vec<Obstacle>) {
+        self.avatar.obstacles(obstacles);
+    }
+
+    fn present(&mut self) -> s::TimeIndex {
+        self.server.present()
+    }
+
+    fn ping(&mut self, location: s::Coordinates) {
+        self.advance();
+        let present = self.server.present();
+        self.chatter.send(Chatter::Ping(location, present));
+    }
+
+    fn pings(&mut self) -> Vec<Ping> {
+        self.advance();
+        self.chat.pings(Participant::Player(0))
+    }
+
+    fn say(&mut self, channel: Channel, text: &str) {
+        self.advance();
+        self.chatter.send(Chatter::Say(channel, text.to_string()));
+    }
+
+    fn messages(&mut self) -> Vec<Message> {
+        self.advance();
+        let messages: Vec<(usize, Message)> = self.chat.messages(Participant::Player(0), self.messages).into_iter()
+            .map(|(i, message)| (i, message.clone()))
+            .collect();
+        if let Some(&(i, _)) = messages.last() { self.messages = i + 1 }
+        messages.into_iter().map(|(_, message)| message).collect()
+    }
+
+    fn events(&mut self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
 }
 
 // ---------------------------------------- REMOTE SERVER ----------------------------------------
 
-struct RemoteServer {
-    server: s::Server
+/// How often the connection asks for the state of the match
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the client hands to the connection of a remote server
+enum Request {
+    Ready,
+    Inputs(Vec<Input>),
+    Scenery(Vec<Obstacle>),
+    Ping(s::Coordinates),
+    Say(Channel, String)
+}
+
+/// What the connection of a remote server learned about the match
+enum Update {
+    Avatar(u32, AvatarState),
+    Present(s::TimeIndex),
+    Pings(Vec<Ping>),
+    Messages(Vec<Message>),
+    Events(Vec<Event>)
+}
+
+/// Match hosted by a lobby server. The avatar is moved by the server, which answers every batch of
+/// inputs with its authoritative state. The connection runs on a thread of its own so waiting on
+/// the network never stalls a frame, the calls only queue requests and pick up what arrived.
+pub struct RemoteServer {
+    requests: Sender<Request>,
+    updates: Receiver<Update>,
+    avatar: Option<(u32, AvatarState)>,
+    present: s::TimeIndex,
+    pings: Vec<Ping>,
+    messages: Vec<Message>,
+    events: Vec<Event>
 }
 
 impl RemoteServer {
-    fn new() -> RemoteServer {
+    fn new(client: LobbyClient, id: usize) -> RemoteServer {
+        let (requests, pending) = channel();
+        let (learned, updates) = channel();
+        let link = Link {
+            client: client,
+            id: id,
+            events: 0,
+            messages: 0,
+            present: 0
+        };
+        thread::spawn(move || link.run(pending, learned));
         RemoteServer {
-            server: s::Server::new()
+            requests: requests,
+            updates: updates,
+            avatar: None,
+            present: 0,
+            pings: Vec::new(),
+            messages: Vec::new(),
+            events: Vec::new()
+        }
+    }
+
+    fn request(&mut self, request: Request) {
+        if self.requests.send(request).is_err() {
+            log!(Level::Error, "networking", "connection closed");
+        }
+    }
+
+    /// Takes in everything the connection learned since the last call
+    fn receive(&mut self) {
+        for update in self.updates.try_iter() {
+            match update {
+                Update::Avatar(sequence, state) => self.avatar = Some((sequence, state)),
+                Update::Present(present) => self.present = present,
+                Update::Pings(pings) => self.pings = pings,
+                Update::Messages(messages) => self.messages.extend(messages),
+                Update::Events(events) => self.events.extend(events)
+            }
         }
     }
 }
 
 impl API for RemoteServer {
     fn start_game(&mut self) {
-        self.server.start_game();
+        self.request(Request::Ready);
+    }
+
+    fn send_inputs(&mut self, inputs: Vec<Input>) {
+        self.request(Request::Inputs(inputs));
+    }
+
+    fn avatar_state(&mut self) -> Option<(u32, AvatarState)> {
+        self.receive();
+        self.avatar.take()
+    }
+
+    fn scenery(&mut self, obstacles: Vec<Obstacle>) {
+        self.request(Request::Scenery(obstacles));
+    }
+
+    fn present(&mut self) -> s::TimeIndex {
+        self.receive();
+        self.present
+    }
+
+    fn ping(&mut self, location: s::Coordinates) {
+        self.request(Request::Ping(location));
+    }
+
+    fn pings(&mut self) -> Vec<Ping> {
+        self.receive();
+        self.pings.clone()
+    }
+
+    fn say(&mut self, channel: Channel, text: &str) {
+        self.request(Request::Say(channel, text.to_string()));
+    }
+
+    fn messages(&mut self) -> Vec<Message> {
+        self.receive();
+        self.messages.drain(..).collect()
+    }
+
+    fn events(&mut self) -> Vec<Event> {
+        self.receive();
+        self.events.drain(..).collect()
+    }
+}
+
+/// Connection of a remote server to the lobby, running on its own thread until the remote server
+/// is dropped
+struct Link {
+    client: LobbyClient,
+    id: usize,
+    /// Index of the next event to fetch
+    events: usize,
+    /// Index of the next message to fetch
+    messages: usize,
+    /// First tick the match hasn't advanced to yet
+    present: s::TimeIndex
+}
+
+impl Link {
+    fn run(mut self, requests: Receiver<Request>, updates: Sender<Update>) {
+        let mut polled: Option<Instant> = None;
+        loop {
+            let first = match requests.recv_timeout(POLL_INTERVAL) {
+                Ok(request) => Some(request),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return
+            };
+            // Every batch of inputs repeats the unacknowledged ones of the batches before, so
+            // only the newest batch has to be sent when the client got ahead of the network
+            let mut inputs = None;
+            for request in first.into_iter().chain(requests.try_iter()) {
+                match request {
+                    Request::Inputs(batch) => inputs = Some(batch),
+                    request => self.handle(request)
+                }
+            }
+            let mut learned = Vec::new();
+            if let Some(inputs) = inputs {
+                match self.client.walk(self.id, &inputs) {
+                    Ok(Some((sequence, state))) => learned.push(Update::Avatar(sequence, state)),
+                    Ok(None) => {},
+                    Err(e) => log!(Level::Error, "networking", "inputs lost"; id = self.id, error = e)
+                }
+            }
+            if polled.map_or(true, |polled| polled.elapsed() >= POLL_INTERVAL) {
+                learned.extend(self.poll());
+                polled = Some(Instant::now());
+            }
+            for update in learned {
+                if updates.send(update).is_err() { return }
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        let result = match request {
+            Request::Ready => self.client.ready(self.id).map(|_| ()),
+            Request::Inputs(_) => Ok(()),
+            Request::Scenery(obstacles) => self.client.scenery(self.id, &obstacles),
+            Request::Ping(location) => self.client.ping(self.id, self.present, location),
+            Request::Say(channel, text) => self.client.say(self.id, channel, &text)
+        };
+        if let Err(e) = result {
+            log!(Level::Error, "networking", "request failed"; id = self.id, error = e);
+        }
+    }
+
+    /// Catches up with the ticks, pings, messages and events of the match since the last poll
+    fn poll(&mut self) -> Vec<Update> {
+        let mut learned = Vec::new();
+        match self.client.advance(self.id, self.present) {
+            Ok(steps) => if let Some(step) = steps.last() {
+                self.present = step.tick + 1;
+                learned.push(Update::Present(self.present));
+            },
+            Err(e) => log!(Level::Warn, "networking", "present unknown"; id = self.id, error = e)
+        }
+        match self.client.pings(self.id) {
+            Ok(pings) => learned.push(Update::Pings(pings)),
+            Err(e) => log!(Level::Error, "networking", "pings unavailable"; id = self.id, error = e)
+        }
+        match self.client.messages(self.id, self.messages) {
+            Ok(messages) => if let Some(&(i, _)) = messages.last() {
+                self.messages = i + 1;
+                learned.push(Update::Messages(messages.into_iter().map(|(_, message)| message).collect()));
+            },
+            Err(e) => log!(Level::Error, "networking", "messages unavailable"; id = self.id, error = e)
+        }
+        match self.client.events(self.id, self.events) {
+            Ok(events) => if let Some(&(i, _)) = events.last() {
+                self.events = i + 1;
+                learned.push(Update::Events(events.into_iter().map(|(_, event)| event).collect()));
+            },
+            Err(e) => log!(Level::Error, "networking", "events unavailable"; id = self.id, error = e)
+        }
+        learned
+    }
+}
+
+// -------------------------------------------- REPLAY -------------------------------------------
+
+/// Watches a recorded match. The time being watched can be scrubbed freely in both directions.
+/// Scrubbing backwards shows the timeline as known at the latest present reproduced so far,
+/// reusing its keyframes instead of reproducing the match from the start again.
+pub struct ReplayViewer {
+    playback: Playback,
+    time: s::TimeIndex
+}
+
+impl ReplayViewer {
+    pub fn open(path: &str) -> Result<ReplayViewer, ReplayError> {
+        Ok(ReplayViewer {
+            playback: Playback::new(Replay::load(path)?),
+            time: 0
+        })
+    }
+
+    pub fn time(&self) -> s::TimeIndex {
+        self.time
+    }
+
+    /// Moves the watched time by the given number of ticks, staying within the recording
+    pub fn scrub(&mut self, ticks: isize) -> Result<(), ReplayError> {
+        let end = self.playback.end() as isize;
+        let target = (self.time as isize + ticks).clamp(0, end) as s::TimeIndex;
+        if target > self.playback.present() {
+            self.playback.seek(target)?;
+        }
+        self.time = target;
+        Ok(())
+    }
+
+    /// Owner and location of every unit alive at the watched time
+    pub fn units(&mut self) -> Vec<(s::Player, s::Coordinates)> {
+        let keyframe = self.playback.view(self.time).unwrap_or_default();
+        let server = self.playback.server();
+        keyframe.into_iter().filter_map(|unit| server.owner(unit.0).map(|owner| (owner, unit.1))).collect()
+    }
+
+    pub fn pings(&self) -> Vec<Ping> {
+        self.playback.pings()
     }
 }
 
 // ----------------------------------------- CONSTRUCTOR -----------------------------------------
 
 pub struct Server {
-    difficulty: i8
+    difficulty: i8,
+    address: String,
+    name: String,
+    password: Option<String>,
+    conditions: Conditions
 }
 
 impl Server {
     pub fn new() -> Server {
         Server {
-            difficulty: 0
+            difficulty: 1,
+            address: "127.0.0.1:4242".to_string(),
+            name: "player".to_string(),
+            password: None,
+            conditions: Conditions::new()
         }
     }
 
@@ -63,11 +461,77 @@ impl Server {
         self
     }
 
+    /// Network conditions simulated between the client and a local server
+    pub fn conditions(mut self, conditions: Conditions) -> Server {
+        self.conditions = conditions;
+        self
+    }
+
+    pub fn address(mut self, address: &str) -> Server {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Name and server password used to authenticate with the lobby server
+    pub fn credentials(mut self, name: &str, password: Option<&str>) -> Server {
+        self.name = name.to_string();
+        self.password = password.map(|password| password.to_string());
+        self
+    }
+
+    fn connect(&self) -> Result<LobbyClient, LobbyError> {
+        log!(Level::Info, "networking", "connecting"; address = self.address, name = self.name);
+        let mut client = LobbyClient::connect(&self.address[..])?;
+        client.authenticate(&self.name, self.password.as_ref().map(|password| &password[..]))?;
+        Ok(client)
+    }
+
+    /// Lists the matches waiting for players on the lobby server
+    pub fn browse(&self) -> Result<Vec<MatchInfo>, LobbyError> {
+        self.connect()?.list()
+    }
+
+    /// Opens a new match with the configured difficulty and returns its id
+    pub fn host(&self, map: &str, players: usize, horizon: s::TimeIndex) -> Result<usize, LobbyError> {
+        let settings = MatchSettings {
+            map: map.to_string(),
+            difficulty: self.difficulty,
+            players: players,
+            horizon: horizon
+        };
+        self.connect()?.create(&settings)
+    }
+
+    /// Takes a slot in a match. The returned connection is used to ready up or leave again,
+    /// the token allows reconnecting to the same slot after the connection dropped.
+    pub fn join(&self, id: usize) -> Result<(LobbyClient, s::Player, Token), LobbyError> {
+        let mut client = self.connect()?;
+        let (player, token) = client.join(id)?;
+        Ok((client, player, token))
+    }
+
+    /// Watches a running match without taking a slot
+    pub fn spectate(&self, id: usize) -> Result<Spectator, LobbyError> {
+        self.connect()?.spectate(id)
+    }
+
+    /// Reconnects to the slot held by a session, use `LobbyClient::sync` to catch up afterwards.
+    /// The session identifies the player, the old connection may still hold the name.
+    pub fn resume(&self, id: usize, token: &str) -> Result<(LobbyClient, s::Player), LobbyError> {
+        log!(Level::Info, "networking", "reconnecting"; address = self.address, id = id);
+        let mut client = LobbyClient::connect(&self.address[..])?;
+        let player = client.resume(id, token)?;
+        Ok((client, player))
+    }
+
     pub fn local(&self) -> LocalServer {
-        LocalServer::new()
+        LocalServer::new(self.conditions)
     }
 
-    pub fn connect_to(&self) -> RemoteServer {
-        RemoteServer::new()
+    /// Joins a match on the lobby server to play it
+    pub fn connect_to(&self, id: usize) -> Result<RemoteServer, LobbyError> {
+        let (client, player, _) = self.join(id)?;
+        log!(Level::Info, "networking", "joined"; id = id, player = player);
+        Ok(RemoteServer::new(client, id))
     }
 }
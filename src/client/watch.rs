@@ -0,0 +1,96 @@
+//! Polling file watcher used to reload assets while the client is running. Files are compared by
+//! modification time and size, which is cheap enough to do a few times per second.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Modification time and size of a file, `None` if it doesn't exist
+type Stamp = Option<(SystemTime, u64)>;
+
+pub struct Watcher {
+    files: Vec<(PathBuf, Stamp)>,
+    interval: Duration,
+    last_poll: Option<Instant>
+}
+
+impl Default for Watcher {
+    fn default() -> Watcher {
+        Watcher::new()
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        Watcher {
+            files: Vec::new(),
+            interval: Duration::from_millis(500),
+            last_poll: None
+        }
+    }
+
+    /// Minimum time between two polls actually looking at the files
+    pub fn interval(mut self, interval: Duration) -> Watcher {
+        self.interval = interval;
+        self
+    }
+
+    /// Starts watching a file, changes are reported relative to its current state
+    pub fn watch<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref().to_path_buf();
+        if !self.files.iter().any(|file| file.0 == path) {
+            let stamp = stamp(&path);
+            self.files.push((path, stamp));
+        }
+    }
+
+    /// Files that have been modified, created or deleted since the last poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        if self.last_poll.is_some_and(|last| now.duration_since(last) < self.interval) { return Vec::new() }
+        self.last_poll = Some(now);
+
+        let mut changed = Vec::new();
+        for file in self.files.iter_mut() {
+            let current = stamp(&file.0);
+            if current != file.1 {
+                file.1 = current;
+                changed.push(file.0.clone());
+            }
+        }
+        changed
+    }
+}
+
+fn stamp(path: &Path) -> Stamp {
+    fs::metadata(path).ok().and_then(|metadata| metadata.modified().ok().map(|modified| (modified, metadata.len())))
+}
+
+#[test]
+fn watch_files() {
+    let directory = ::std::env::temp_dir().join(format!("timewars_watch_{}", ::std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let (tree, knight) = (directory.join("tree.3d"), directory.join("knight.3d"));
+    fs::write(&tree, "0.4\n").unwrap();
+
+    let mut watcher = Watcher::new().interval(Duration::from_secs(0));
+    watcher.watch(&tree);
+    watcher.watch(&knight);
+    watcher.watch(&tree);
+    assert!(watcher.poll().is_empty());
+
+    fs::write(&tree, "0.4\n2.6\n").unwrap();
+    fs::write(&knight, "0.5\n").unwrap();
+    assert_eq!(watcher.poll(), vec![tree.clone(), knight.clone()]);
+    assert!(watcher.poll().is_empty());
+
+    fs::remove_file(&knight).unwrap();
+    assert_eq!(watcher.poll(), vec![knight]);
+
+    // Polls in quick succession don't look at the files
+    let mut throttled = Watcher::new().interval(Duration::from_secs(60));
+    throttled.watch(&tree);
+    assert!(throttled.poll().is_empty());
+    fs::write(&tree, "0.4\n2.6\n1\n").unwrap();
+    assert!(throttled.poll().is_empty());
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,69 @@
+//! Converts OBJ keyframes exported from a modelling tool into a `.3d` animation.
+//!
+//! ```text
+//! convert [--frames <n>] [--duration <seconds>] <materials.mtl> <output> <keyframe.obj>...
+//! convert <model> <output>
+//! ```
+//!
+//! The second form converts an existing model between the formats. Outputs ending in `.3db` are
+//! written in the binary format, everything else as text.
+//!
+//! Every vertex moves through its positions in the keyframes, in the order they are given. The
+//! collision radius and height are the largest distance from the vertical axis and the highest
+//! point across all keyframes. At least two keyframes are needed.
+// Structs are initialized as `field: field` throughout, the style of the 2015 code base
+#![allow(clippy::redundant_field_names)]
+use std::env;
+use std::path::Path;
+use std::process;
+
+// Shared with the client, which also reads the files
+#[path = "../client/model.rs"]
+#[allow(dead_code)]
+mod model;
+mod obj;
+
+use model::Model;
+use obj::{Import, Materials};
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut import = Import::new();
+    while args.len() >= 2 && args[0].starts_with("--") {
+        let value = args.remove(1);
+        import = match (&args.remove(0)[..], value.parse::<f32>()) {
+            ("--frames", Ok(frames)) if frames >= 1.0 && frames.fract() == 0.0 => import.frames(frames as usize),
+            ("--duration", Ok(duration)) if duration > 0.0 => import.duration(duration),
+            (option, _) => usage(&format!("invalid value '{}' for {}", value, option))
+        };
+    }
+    if args.len() < 2 { usage("missing arguments") }
+
+    let result = if args.len() == 2 {
+        Model::load(&args[0]).map_err(|e| e.to_string())
+    } else {
+        Materials::load(&args[0]).and_then(|materials| import.load(&args[2..], &materials)).map_err(|e| e.to_string())
+    };
+    let model = match result {
+        Ok(model) => model,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let binary = Path::new(&args[1]).extension().is_some_and(|extension| extension == "3db");
+    if let Err(e) = if binary { model.save_binary(&args[1]) } else { model.save(&args[1]) } {
+        eprintln!("{}: {}", args[1], e);
+        process::exit(1);
+    }
+    println!("{}: {} vertices, {} faces, {} control points each, collision radius {} height {}",
+             args[1], model.vertices.len(), model.faces.len(), model.vertices.first().map_or(0, |points| points.len()),
+             model.collision_radius, model.collision_y);
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("{}", problem);
+    eprintln!("usage: convert [--frames <n>] [--duration <seconds>] <materials.mtl> <output> <keyframe.obj>...");
+    eprintln!("       convert <model> <output>");
+    process::exit(2);
+}
@@ -0,0 +1,324 @@
+//! Import of Wavefront OBJ keyframes and their MTL materials. Every keyframe has to contain the
+//! same vertices in the same order, only their positions change. Faces and materials are taken
+//! from the first keyframe, vertices are grouped by the object (`o` or `g`) they belong to.
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use model::{Model, LEGACY_DURATION, LEGACY_FRAMES, VERSION};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    Io(String),
+    NotUtf8,
+    InvalidNumber(String),
+    /// A directive got too few values
+    MissingValues(&'static str),
+    /// A face refers to a vertex that doesn't exist
+    UnknownVertex(i64),
+    UnknownMaterial(String),
+    /// The MTL file doesn't define any material with a diffuse color
+    NoMaterials,
+    /// Curves need at least two control points, so at least two keyframes
+    TooFewKeyframes(usize),
+    /// A keyframe has a different number of vertices than the first one
+    VertexCount { expected: usize, found: usize }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::Io(ref e) => write!(f, "can't read file: {}", e),
+            Reason::NotUtf8 => write!(f, "not valid UTF-8"),
+            Reason::InvalidNumber(ref value) => write!(f, "invalid number '{}'", value),
+            Reason::MissingValues(directive) => write!(f, "'{}' is missing values", directive),
+            Reason::UnknownVertex(vertex) => write!(f, "face refers to unknown vertex {}", vertex),
+            Reason::UnknownMaterial(ref name) => write!(f, "unknown material '{}'", name),
+            Reason::NoMaterials => write!(f, "no material with a diffuse color"),
+            Reason::TooFewKeyframes(count) => write!(f, "need at least 2 keyframes, got {}", count),
+            Reason::VertexCount { expected, found } => write!(f, "expected {} vertices, found {}", expected, found)
+        }
+    }
+}
+
+/// What went wrong where, lines are counted from 1 and 0 refers to the whole file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportError {
+    pub file: String,
+    pub line: usize,
+    pub reason: Reason
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.reason)
+    }
+}
+
+/// Diffuse colors by material name, in the order they are defined
+#[derive(Clone, Debug, PartialEq)]
+pub struct Materials(pub Vec<(String, [f32; 4])>);
+
+impl Materials {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Materials, ImportError> {
+        let (file, text) = read(path.as_ref())?;
+        Materials::parse(&file, &text)
+    }
+
+    pub fn parse(file: &str, text: &str) -> Result<Materials, ImportError> {
+        let error = |line, reason| ImportError { file: file.to_string(), line: line, reason: reason };
+        let mut materials = Vec::new();
+        let mut name = None;
+        for (i, line) in text.lines().enumerate() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.first().cloned() {
+                Some("newmtl") => name = Some(parts[1..].join(" ")),
+                Some("Kd") => {
+                    let rgb = numbers(&parts[1..], 3, "Kd").map_err(|reason| error(i + 1, reason))?;
+                    let name = name.clone().unwrap_or_default();
+                    materials.retain(|material: &(String, [f32; 4])| material.0 != name);
+                    materials.push((name, [rgb[0], rgb[1], rgb[2], 1.0]));
+                },
+                _ => {}
+            }
+        }
+        if materials.is_empty() { return Err(error(0, Reason::NoMaterials)) }
+        Ok(Materials(materials))
+    }
+
+    /// Looks up a material, exporters replace the dots of some names with underscores
+    fn get(&self, name: &str) -> Option<[f32; 4]> {
+        let dotted = name.replace('_', ".");
+        self.0.iter().find(|material| material.0 == name)
+            .or_else(|| self.0.iter().find(|material| material.0 == dotted))
+            .map(|material| material.1)
+    }
+}
+
+/// A single OBJ keyframe
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub vertices: Vec<[f32; 3]>,
+    /// Object every vertex belongs to
+    pub objects: Vec<usize>,
+    /// Material used by every object, `None` if it didn't name one
+    pub materials: Vec<Option<(usize, String)>>,
+    /// Triangles with 0 based indices into `vertices`, along with the line they were defined on
+    pub faces: Vec<(usize, [i64; 3])>
+}
+
+impl Keyframe {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keyframe, ImportError> {
+        let (file, text) = read(path.as_ref())?;
+        Keyframe::parse(&file, &text)
+    }
+
+    pub fn parse(file: &str, text: &str) -> Result<Keyframe, ImportError> {
+        let error = |line, reason| ImportError { file: file.to_string(), line: line, reason: reason };
+        let mut keyframe = Keyframe { vertices: Vec::new(), objects: Vec::new(), materials: Vec::new(), faces: Vec::new() };
+        // Vertices before the first object belong to an unnamed one
+        let mut object = None;
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.first().cloned() {
+                Some("o") | Some("g") => {
+                    keyframe.materials.push(None);
+                    object = Some(keyframe.materials.len() - 1);
+                },
+                Some("v") => {
+                    let position = numbers(&parts[1..], 3, "v").map_err(|reason| error(n, reason))?;
+                    if object.is_none() {
+                        keyframe.materials.push(None);
+                        object = Some(0);
+                    }
+                    keyframe.vertices.push([position[0], position[1], position[2]]);
+                    keyframe.objects.push(object.unwrap());
+                },
+                Some("usemtl") if parts.len() > 1 => {
+                    if object.is_none() {
+                        keyframe.materials.push(None);
+                        object = Some(0);
+                    }
+                    keyframe.materials[object.unwrap()] = Some((n, parts[1..].join(" ")));
+                },
+                Some("f") => {
+                    if parts.len() < 4 { return Err(error(n, Reason::MissingValues("f"))) }
+                    let mut corners = Vec::new();
+                    for corner in parts[1..].iter() {
+                        let index = corner.split('/').next().unwrap();
+                        let index: i64 = index.parse().map_err(|_| error(n, Reason::InvalidNumber(index.to_string())))?;
+                        // Indices count from 1, negative ones from the last vertex defined so far
+                        let resolved = if index < 0 { keyframe.vertices.len() as i64 + index } else { index - 1 };
+                        if index == 0 || resolved < 0 { return Err(error(n, Reason::UnknownVertex(index))) }
+                        corners.push(resolved);
+                    }
+                    for j in 1..corners.len() - 1 {
+                        keyframe.faces.push((n, [corners[0], corners[j], corners[j + 1]]));
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(keyframe)
+    }
+}
+
+/// Options of the generated animation
+pub struct Import {
+    frames: usize,
+    duration: f32
+}
+
+impl Default for Import {
+    fn default() -> Import {
+        Import::new()
+    }
+}
+
+impl Import {
+    pub fn new() -> Import {
+        Import {
+            frames: LEGACY_FRAMES,
+            duration: LEGACY_DURATION
+        }
+    }
+
+    pub fn frames(mut self, frames: usize) -> Import {
+        self.frames = frames.max(1);
+        self
+    }
+
+    pub fn duration(mut self, duration: f32) -> Import {
+        self.duration = duration;
+        self
+    }
+
+    /// Loads the keyframes in order and combines them into an animation
+    pub fn load<P: AsRef<Path>>(&self, keyframes: &[P], materials: &Materials) -> Result<Model, ImportError> {
+        let files: Vec<String> = keyframes.iter().map(|path| path.as_ref().display().to_string()).collect();
+        let keyframes = keyframes.iter().map(Keyframe::load).collect::<Result<Vec<Keyframe>, ImportError>>()?;
+        self.convert(&files, &keyframes, materials)
+    }
+
+    /// Combines keyframes into an animation, `files` naming them for error messages
+    pub fn convert(&self, files: &[String], keyframes: &[Keyframe], materials: &Materials) -> Result<Model, ImportError> {
+        let error = |i: usize, line, reason| ImportError { file: files[i].clone(), line: line, reason: reason };
+        let first = match keyframes.first() {
+            Some(first) if keyframes.len() >= 2 => first,
+            _ => return Err(ImportError { file: String::new(), line: 0, reason: Reason::TooFewKeyframes(keyframes.len()) })
+        };
+        for (i, keyframe) in keyframes.iter().enumerate() {
+            if keyframe.vertices.len() != first.vertices.len() {
+                return Err(error(i, 0, Reason::VertexCount { expected: first.vertices.len(), found: keyframe.vertices.len() }))
+            }
+        }
+
+        // Objects without a material fall back to the first one like the original script did
+        let mut colors = Vec::new();
+        for material in first.materials.iter() {
+            colors.push(match *material {
+                Some((line, ref name)) => materials.get(name).ok_or_else(|| error(0, line, Reason::UnknownMaterial(name.clone())))?,
+                None => (materials.0[0]).1
+            });
+        }
+
+        // Vertices are stored object by object, `order` maps the new index to the original one
+        let mut order: Vec<usize> = (0..first.vertices.len()).collect();
+        order.sort_by_key(|&vertex| first.objects[vertex]);
+        let mut index = vec![0; order.len()];
+        for (new, &original) in order.iter().enumerate() {
+            index[original] = new as u32;
+        }
+
+        let mut model = Model {
+            version: VERSION,
+            collision_radius: 0.0,
+            collision_y: 0.0,
+            frames: self.frames,
+            duration: self.duration,
+            vertices: Vec::new(),
+            materials: Vec::new(),
+            faces: Vec::new()
+        };
+        for &vertex in order.iter() {
+            let points: Vec<[f32; 3]> = keyframes.iter().map(|keyframe| keyframe.vertices[vertex]).collect();
+            for point in points.iter() {
+                model.collision_radius = model.collision_radius.max(point[0].abs()).max(point[2].abs());
+                model.collision_y = model.collision_y.max(point[1]);
+            }
+            model.vertices.push(points);
+            model.materials.push(colors[first.objects[vertex]]);
+        }
+        for &(line, face) in first.faces.iter() {
+            if let Some(&vertex) = face.iter().find(|&&vertex| vertex as usize >= order.len()) {
+                return Err(error(0, line, Reason::UnknownVertex(vertex + 1)))
+            }
+            model.faces.push([index[face[0] as usize], index[face[1] as usize], index[face[2] as usize]]);
+        }
+        Ok(model)
+    }
+}
+
+fn read(path: &Path) -> Result<(String, String), ImportError> {
+    let file = path.display().to_string();
+    let error = |reason| ImportError { file: file.clone(), line: 0, reason: reason };
+    let mut contents = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| error(Reason::Io(e.to_string())))?;
+    let text = String::from_utf8(contents).map_err(|_| error(Reason::NotUtf8))?;
+    Ok((file, text))
+}
+
+/// Parses the first `count` values, ignoring optional ones following them
+fn numbers(values: &[&str], count: usize, directive: &'static str) -> Result<Vec<f32>, Reason> {
+    if values.len() < count { return Err(Reason::MissingValues(directive)) }
+    values[..count].iter().map(|value| match value.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(Reason::InvalidNumber(value.to_string()))
+    }).collect()
+}
+
+#[test]
+fn import_keyframes() {
+    let materials = Materials::parse("test.mtl", "newmtl Red\nKd 1 0 0\nnewmtl Leaf.001\nKd 0 1 0\n").unwrap();
+    let first = Keyframe::parse("0.obj", "o Trunk\nv 0 0 0\nv 1 0 0\no Leaves\nusemtl Leaf_001\nv 0 2 0\no Trunk2\nv 0 0 1\nf 1 2 4 -2\n").unwrap();
+    let second = Keyframe::parse("1.obj", "o Trunk\nv 0 0 0\nv 2 0 0\no Leaves\nv 0 3 -1.5\no Trunk2\nv 0 0 1\n").unwrap();
+    let files = vec!["0.obj".to_string(), "1.obj".to_string()];
+    let model = Import::new().frames(60).duration(1.5).convert(&files, &[first.clone(), second], &materials).unwrap();
+
+    assert_eq!((model.frames, model.duration), (60, 1.5));
+    assert_eq!((model.collision_radius, model.collision_y), (2.0, 3.0));
+    assert_eq!(model.vertices, vec![
+        vec![[0.0, 0.0, 0.0], [0.0, 0.0, 0.0]], vec![[1.0, 0.0, 0.0], [2.0, 0.0, 0.0]],
+        vec![[0.0, 2.0, 0.0], [0.0, 3.0, -1.5]], vec![[0.0, 0.0, 1.0], [0.0, 0.0, 1.0]]
+    ]);
+    assert_eq!(model.materials, vec![[1.0, 0.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0]]);
+    // The quad is split into a fan of triangles
+    assert_eq!(model.faces, vec![[0, 1, 3], [0, 3, 2]]);
+
+    let short = Keyframe::parse("1.obj", "v 0 0 0\n").unwrap();
+    let error = Import::new().convert(&files, &[first.clone(), short], &materials).unwrap_err();
+    assert_eq!(error.to_string(), "1.obj:0: expected 4 vertices, found 1");
+    let unknown = Keyframe::parse("0.obj", "usemtl Bark\nv 0 0 0\n").unwrap();
+    assert_eq!(Import::new().convert(&files, &[unknown.clone(), unknown], &materials).unwrap_err().reason, Reason::UnknownMaterial("Bark".to_string()));
+    // The loader needs two control points per vertex
+    assert_eq!(Import::new().convert(&files[..1], ::std::slice::from_ref(&first), &materials).unwrap_err().reason, Reason::TooFewKeyframes(1));
+    assert_eq!(Import::new().convert(&[], &[], &materials).unwrap_err().reason, Reason::TooFewKeyframes(0));
+    assert_eq!(Keyframe::parse("0.obj", "v 0 0 0\nf 1 0 1\n").unwrap_err().reason, Reason::UnknownVertex(0));
+    assert_eq!(Keyframe::parse("0.obj", "v 0 x 0\n").unwrap_err().line, 1);
+    assert_eq!(Materials::parse("empty.mtl", "newmtl Mat\n").unwrap_err().reason, Reason::NoMaterials);
+}
+
+#[test]
+fn import_bundled_keyframes() {
+    let data = format!("{}/assets/convert/data", env!("CARGO_MANIFEST_DIR"));
+    let keyframes: Vec<String> = (0..9).map(|i| format!("{}/file_00000{}.obj", data, i)).collect();
+    let materials = Materials::load(format!("{}/file.mtl", data)).unwrap();
+    let model = Import::new().load(&keyframes, &materials).unwrap();
+
+    // out.3d was generated from the same keyframes by the original Python script
+    let expected = Model::load(format!("{}/out.3d", data)).unwrap();
+    assert_eq!(model, Model { version: VERSION, frames: LEGACY_FRAMES, duration: LEGACY_DURATION, ..expected });
+}
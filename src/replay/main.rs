@@ -0,0 +1,106 @@
+//! Headless replay tool, re-simulating recorded matches without opening a window.
+//!
+//! ```text
+//! replay verify <file>...   checks that every replay still reproduces its recorded final state
+//! replay stats <file>...    verifies and prints the statistics of every replay
+//! replay inspect <file> [<present>] [<width>]
+//!                           draws the timeline as it was at the given present, the end by default
+//! ```
+//!
+//! Directories are searched for `.replay` files. The exit code is non-zero if any replay failed.
+extern crate server;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use server::replay::{Playback, Replay, ReplayError, Statistics};
+use server::inspector::Inspector;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stats = match args.first().map(|arg| &arg[..]) {
+        Some("verify") => false,
+        Some("stats") => true,
+        Some("inspect") if args.len() >= 2 && args.len() <= 4 => {
+            if let Err(e) = inspect(&args[1], args.get(2), args.get(3)) {
+                eprintln!("{}: {:?}", args[1], e);
+                process::exit(1);
+            }
+            return
+        },
+        _ => {
+            eprintln!("usage: replay <verify|stats> <file or directory>...");
+            eprintln!("       replay inspect <file> [<present>] [<width>]");
+            process::exit(2);
+        }
+    };
+
+    let mut files = Vec::new();
+    for arg in args[1..].iter() {
+        collect(Path::new(arg), &mut files);
+    }
+
+    let mut failed = 0;
+    for file in files.iter() {
+        match check(file) {
+            Ok(statistics) => {
+                println!("ok   {}", file.display());
+                if stats { print_statistics(&statistics) }
+            },
+            Err(e) => {
+                println!("FAIL {}: {:?}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} replays, {} failed", files.len(), failed);
+    if failed > 0 { process::exit(1) }
+}
+
+fn collect(path: &Path, files: &mut Vec<PathBuf>) {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return
+    }
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => return
+    };
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|extension| extension == "replay") {
+            collect(&entry, files);
+        }
+    }
+}
+
+fn check(file: &Path) -> Result<Statistics, ReplayError> {
+    let mut playback = Playback::new(Replay::load(file)?);
+    playback.verify()?;
+    Ok(playback.statistics())
+}
+
+fn inspect(file: &str, present: Option<&String>, width: Option<&String>) -> Result<(), ReplayError> {
+    let mut playback = Playback::new(Replay::load(file)?);
+    let present = match present {
+        Some(present) => present.parse().map_err(|_| ReplayError::Parse(0, present.clone()))?,
+        None => playback.end()
+    };
+    let inspector = match width {
+        Some(width) => Inspector::new().width(width.parse().map_err(|_| ReplayError::Parse(0, width.clone()))?),
+        None => Inspector::new()
+    };
+    playback.seek(present)?;
+    print!("{}", inspector.pings(playback.pings()).render(playback.server()));
+    Ok(())
+}
+
+fn print_statistics(statistics: &Statistics) {
+    println!("     ticks: {}, portals: {}, forks: {}, paradoxes: {}",
+             statistics.ticks, statistics.portals, statistics.forks, statistics.paradoxes);
+    for (player, lost) in statistics.losses.iter() {
+        println!("     player {} lost {} units", player, lost);
+    }
+}
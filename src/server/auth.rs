@@ -0,0 +1,62 @@
+//! Identities of the people connecting to a hosted server
+/// Longest accepted player name
+const MAX_NAME_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub name: String
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthError {
+    InvalidName,
+    WrongPassword,
+    /// The session belongs to somebody else
+    WrongIdentity,
+    /// Another live connection goes by the name
+    NameTaken,
+    /// The connection already introduced itself
+    AlreadyAuthenticated
+}
+
+/// Admits players knowing the pre-shared password of the server, if it has one
+pub struct Authenticator {
+    password: Option<String>
+}
+
+impl Authenticator {
+    pub fn new(password: Option<String>) -> Authenticator {
+        Authenticator {
+            password: password
+        }
+    }
+
+    pub fn authenticate(&self, name: &str, password: Option<&str>) -> Result<Identity, AuthError> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            return Err(AuthError::InvalidName)
+        }
+        if let Some(ref expected) = self.password {
+            if !constant_time_eq(expected.as_bytes(), password.unwrap_or("").as_bytes()) {
+                return Err(AuthError::WrongPassword)
+            }
+        }
+        Ok(Identity { name: name.to_string() })
+    }
+}
+
+/// Compares without bailing out early so the password can't be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[test]
+fn authenticate_with_password() {
+    let open = Authenticator::new(None);
+    assert_eq!(open.authenticate("alice", None), Ok(Identity { name: "alice".to_string() }));
+    assert_eq!(open.authenticate("al ice", None), Err(AuthError::InvalidName));
+
+    let private = Authenticator::new(Some("hunter2".to_string()));
+    assert!(private.authenticate("alice", Some("hunter2")).is_ok());
+    assert_eq!(private.authenticate("alice", Some("hunter3")), Err(AuthError::WrongPassword));
+    assert_eq!(private.authenticate("alice", None), Err(AuthError::WrongPassword));
+}
@@ -0,0 +1,151 @@
+//! In-match text chat and map pings
+use {Coordinates, Player, TimeIndex};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    All,
+    Team,
+    Spectators
+}
+
+impl Channel {
+    pub fn parse(name: &str) -> Option<Channel> {
+        match name {
+            "all" => Some(Channel::All),
+            "team" => Some(Channel::Team),
+            "spectators" => Some(Channel::Spectators),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Channel::All => "all",
+            Channel::Team => "team",
+            Channel::Spectators => "spectators"
+        }
+    }
+}
+
+/// Author or reader of the chat
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Participant {
+    Player(Player),
+    Spectator
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub sender: Participant,
+    pub channel: Channel,
+    /// Present of the match when the message has been sent
+    pub time: TimeIndex,
+    pub text: String
+}
+
+/// Marker placed by a player at a location and time of the match
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ping {
+    pub player: Player,
+    pub location: Coordinates,
+    pub time: TimeIndex
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChatError {
+    /// Players can't write to spectators and spectators can't write to players
+    Forbidden(Channel),
+    UnknownPlayer(Player),
+    Empty
+}
+
+pub struct Chat {
+    teams: Vec<usize>,
+    messages: Vec<Message>,
+    pings: Vec<Ping>
+}
+
+impl Chat {
+    /// Creates the chat of a match, `teams[player]` being the team each player belongs to
+    pub fn new(teams: Vec<usize>) -> Chat {
+        Chat {
+            teams: teams,
+            messages: Vec::new(),
+            pings: Vec::new()
+        }
+    }
+
+    pub fn say(&mut self, sender: Participant, channel: Channel, text: &str, time: TimeIndex) -> Result<(), ChatError> {
+        if text.trim().is_empty() { return Err(ChatError::Empty) }
+        match (sender, channel) {
+            (Participant::Player(player), _) if player >= self.teams.len() => return Err(ChatError::UnknownPlayer(player)),
+            (Participant::Player(_), Channel::Spectators) | (Participant::Spectator, Channel::All) |
+            (Participant::Spectator, Channel::Team) => return Err(ChatError::Forbidden(channel)),
+            _ => {}
+        }
+        self.messages.push(Message {
+            sender: sender,
+            channel: channel,
+            time: time,
+            text: text.trim().to_string()
+        });
+        Ok(())
+    }
+
+    pub fn ping(&mut self, player: Player, location: Coordinates, time: TimeIndex) -> Result<(), ChatError> {
+        if player >= self.teams.len() { return Err(ChatError::UnknownPlayer(player)) }
+        self.pings.push(Ping { player: player, location: location, time: time });
+        Ok(())
+    }
+
+    /// Returns the messages visible to `reader` starting at index `since` along with their index
+    pub fn messages(&self, reader: Participant, since: usize) -> Vec<(usize, &Message)> {
+        self.messages.iter().enumerate().skip(since)
+            .filter(|&(_, message)| self.can_read(reader, message))
+            .collect()
+    }
+
+    /// Returns the pings placed by the team of `reader`, spectators see all of them
+    pub fn pings(&self, reader: Participant) -> Vec<Ping> {
+        self.pings.iter().filter(|ping| match reader {
+            Participant::Player(player) => self.same_team(player, ping.player),
+            Participant::Spectator => true
+        }).cloned().collect()
+    }
+
+    /// Every message in the order it has been sent, e.g. for recording
+    pub fn history(&self) -> (&[Message], &[Ping]) {
+        (&self.messages, &self.pings)
+    }
+
+    fn can_read(&self, reader: Participant, message: &Message) -> bool {
+        match (reader, message.channel, message.sender) {
+            (Participant::Spectator, _, _) => true,
+            (Participant::Player(_), Channel::All, _) => true,
+            (Participant::Player(player), Channel::Team, Participant::Player(sender)) => self.same_team(player, sender),
+            _ => false
+        }
+    }
+
+    fn same_team(&self, a: Player, b: Player) -> bool {
+        self.teams.get(a).is_some() && self.teams.get(a) == self.teams.get(b)
+    }
+}
+
+#[test]
+fn channels_and_pings() {
+    let mut chat = Chat::new(vec![0, 1, 0]);
+    chat.say(Participant::Player(0), Channel::Team, "flank left", 3).unwrap();
+    chat.say(Participant::Player(1), Channel::All, "gg", 4).unwrap();
+    chat.say(Participant::Spectator, Channel::Spectators, "nice portal", 4).unwrap();
+    assert_eq!(chat.say(Participant::Spectator, Channel::All, "psst", 4), Err(ChatError::Forbidden(Channel::All)));
+    assert_eq!(chat.say(Participant::Player(2), Channel::Spectators, "hi", 4), Err(ChatError::Forbidden(Channel::Spectators)));
+
+    assert_eq!(chat.messages(Participant::Player(2), 0).len(), 2);
+    assert_eq!(chat.messages(Participant::Player(1), 0).len(), 1);
+    assert_eq!(chat.messages(Participant::Spectator, 1).len(), 2);
+
+    chat.ping(0, (4.0, 2.0), 10).unwrap();
+    assert_eq!(chat.pings(Participant::Player(2)), vec![Ping { player: 0, location: (4.0, 2.0), time: 10 }]);
+    assert!(chat.pings(Participant::Player(1)).is_empty());
+}
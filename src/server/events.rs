@@ -0,0 +1,125 @@
+//! Typed notifications about everything happening inside a `Server`. Interested parties subscribe
+//! with `Server::subscribe` and receive every event emitted afterwards through a channel.
+//! Recalculating the timeline after the past changed may report the same kind of event again,
+//! `TimelineForked` tells subscribers that everything reported after `forked_at` is outdated.
+use {AIType, Coordinates, ID, Player, PortalInfo, TimeIndex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    GameStarted,
+    UnitSpawned { unit: ID, player: Player, ai_type: AIType, location: Coordinates },
+    UnitDied { unit: ID, player: Player, time: TimeIndex, location: Coordinates },
+    PortalOpened { portal: usize, info: PortalInfo },
+    /// The origin of a portal expired at the present
+    PortalClosed { portal: usize, player: Player, time: TimeIndex },
+    UnitTraversed { unit: ID, player: Player, portal: usize, departure: TimeIndex, arrival: ID },
+    TimelineForked { forked_at: TimeIndex, branch: usize },
+    /// A unit arrived from the future although it never departed in the current timeline
+    ParadoxDetected { unit: ID, player: Player, departure: TimeIndex },
+    /// Only the units of a single player are left, or none at all
+    MatchEnded { winner: Option<Player>, time: TimeIndex }
+}
+
+impl Event {
+    /// Player the event concerns, `None` for events everyone is allowed to know about
+    pub fn player(&self) -> Option<Player> {
+        match *self {
+            Event::UnitSpawned { player, .. } | Event::UnitDied { player, .. } |
+            Event::PortalClosed { player, .. } | Event::UnitTraversed { player, .. } |
+            Event::ParadoxDetected { player, .. } => Some(player),
+            Event::PortalOpened { info, .. } => Some(info.player),
+            Event::GameStarted | Event::TimelineForked { .. } | Event::MatchEnded { .. } => None
+        }
+    }
+
+    /// Compact textual form used on the wire, readable by `Event::parse`
+    pub fn encode(&self) -> String {
+        match *self {
+            Event::GameStarted => "GameStarted".to_string(),
+            Event::UnitSpawned { unit, player, ai_type, location } =>
+                format!("UnitSpawned {} {} {:?} {} {}", unit, player, ai_type, location.0, location.1),
+            Event::UnitDied { unit, player, time, location } =>
+                format!("UnitDied {} {} {} {} {}", unit, player, time, location.0, location.1),
+            Event::PortalOpened { portal, info } =>
+                format!("PortalOpened {} {} {} {} {} {} {} {} {} {}", portal, info.player,
+                        info.origin.0, (info.origin.1).0, (info.origin.1).1, info.origin_expiration,
+                        info.dest.0, (info.dest.1).0, (info.dest.1).1, info.dest_expiration),
+            Event::PortalClosed { portal, player, time } => format!("PortalClosed {} {} {}", portal, player, time),
+            Event::UnitTraversed { unit, player, portal, departure, arrival } =>
+                format!("UnitTraversed {} {} {} {} {}", unit, player, portal, departure, arrival),
+            Event::TimelineForked { forked_at, branch } => format!("TimelineForked {} {}", forked_at, branch),
+            Event::ParadoxDetected { unit, player, departure } => format!("ParadoxDetected {} {} {}", unit, player, departure),
+            Event::MatchEnded { winner: Some(winner), time } => format!("MatchEnded {} {}", time, winner),
+            Event::MatchEnded { winner: None, time } => format!("MatchEnded {}", time)
+        }
+    }
+
+    pub fn parse(encoded: &str) -> Option<Event> {
+        let parts: Vec<&str> = encoded.split_whitespace().collect();
+        let event = match (*parts.first()?, parts.len()) {
+            ("GameStarted", 1) => Event::GameStarted,
+            ("UnitSpawned", 6) => Event::UnitSpawned {
+                unit: parse(parts[1])?,
+                player: parse(parts[2])?,
+                ai_type: match parts[3] {
+                    "Scout" => AIType::Scout,
+                    "Knight" => AIType::Knight,
+                    _ => return None
+                },
+                location: (parse(parts[4])?, parse(parts[5])?)
+            },
+            ("UnitDied", 6) => Event::UnitDied {
+                unit: parse(parts[1])?,
+                player: parse(parts[2])?,
+                time: parse(parts[3])?,
+                location: (parse(parts[4])?, parse(parts[5])?)
+            },
+            ("PortalOpened", 11) => Event::PortalOpened {
+                portal: parse(parts[1])?,
+                info: PortalInfo {
+                    player: parse(parts[2])?,
+                    origin: (parse(parts[3])?, (parse(parts[4])?, parse(parts[5])?)),
+                    origin_expiration: parse(parts[6])?,
+                    dest: (parse(parts[7])?, (parse(parts[8])?, parse(parts[9])?)),
+                    dest_expiration: parse(parts[10])?
+                }
+            },
+            ("PortalClosed", 4) => Event::PortalClosed { portal: parse(parts[1])?, player: parse(parts[2])?, time: parse(parts[3])? },
+            ("UnitTraversed", 6) => Event::UnitTraversed {
+                unit: parse(parts[1])?,
+                player: parse(parts[2])?,
+                portal: parse(parts[3])?,
+                departure: parse(parts[4])?,
+                arrival: parse(parts[5])?
+            },
+            ("TimelineForked", 3) => Event::TimelineForked { forked_at: parse(parts[1])?, branch: parse(parts[2])? },
+            ("ParadoxDetected", 4) => Event::ParadoxDetected { unit: parse(parts[1])?, player: parse(parts[2])?, departure: parse(parts[3])? },
+            ("MatchEnded", 2) => Event::MatchEnded { winner: None, time: parse(parts[1])? },
+            ("MatchEnded", 3) => Event::MatchEnded { winner: Some(parse(parts[2])?), time: parse(parts[1])? },
+            _ => return None
+        };
+        Some(event)
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+#[test]
+fn encode_events() {
+    let events = vec![
+        Event::GameStarted,
+        Event::UnitSpawned { unit: 3, player: 1, ai_type: AIType::Scout, location: (-1.5, 2.0) },
+        Event::PortalOpened { portal: 0, info: PortalInfo {
+            player: 1, origin: (5, (0.0, 0.25)), origin_expiration: 8, dest: (2, (3.0, 3.0)), dest_expiration: 3
+        } },
+        Event::UnitTraversed { unit: 3, player: 1, portal: 0, departure: 6, arrival: 4 },
+        Event::MatchEnded { winner: Some(1), time: 20 },
+        Event::MatchEnded { winner: None, time: 20 }
+    ];
+    for event in events {
+        assert_eq!(Event::parse(&event.encode()), Some(event));
+    }
+    assert_eq!(Event::parse("UnitDied 1 2"), None);
+}
@@ -0,0 +1,204 @@
+//! Text rendering of a match's timeline for debugging. Time runs from left to right, every line
+//! is a track: the current timeline with the points it forked at, every abandoned branch, every
+//! portal from its destination to its origin, the pings of every player and the lifeline of every
+//! unit.
+//!
+//! ```text
+//! =  current timeline     +  fork           -  abandoned branch
+//! O  portal origin        D  destination    o d  endpoint open    ~  jump through time
+//! #  unit alive           x  unit died      >  entered a portal   <  arrived from the future
+//! !  ping
+//! ```
+use {Player, Server, ID, TimeIndex};
+use chat::Ping;
+
+pub struct Inspector {
+    width: usize,
+    pings: Vec<Ping>
+}
+
+impl Default for Inspector {
+    fn default() -> Inspector {
+        Inspector::new()
+    }
+}
+
+impl Inspector {
+    pub fn new() -> Inspector {
+        Inspector {
+            width: 100,
+            pings: Vec::new()
+        }
+    }
+
+    /// Maximum number of columns used for the tracks, longer matches get several ticks per column
+    pub fn width(mut self, width: usize) -> Inspector {
+        self.width = width.max(1);
+        self
+    }
+
+    /// Pings to show, the server doesn't know about the chat of its match
+    pub fn pings(mut self, pings: Vec<Ping>) -> Inspector {
+        self.pings = pings;
+        self
+    }
+
+    pub fn render(&self, server: &mut Server) -> String {
+        let present = server.present();
+        let ticks = present + 1;
+        let scale = ticks.div_ceil(self.width);
+        let columns = ticks.div_ceil(scale);
+        let track = || Track { cells: vec![' '; columns], scale: scale };
+        let mut lines = Vec::new();
+
+        let mut ruler = String::new();
+        for column in 0..columns {
+            ruler.push(if column % 10 == 0 { '|' } else { '.' });
+        }
+        lines.push((format!("time (x{})", scale), ruler));
+
+        let branches = server.branches();
+        let mut current = track();
+        current.fill(0, present, '=');
+        for branch in branches.iter() {
+            current.mark(branch.forked_at, '+');
+        }
+        lines.push(("timeline".to_string(), current.finish()));
+
+        for (i, branch) in branches.iter().enumerate() {
+            let mut track = track();
+            track.fill(branch.forked_at + 1, branch.end, '-');
+            track.mark(branch.forked_at, '+');
+            lines.push((format!("branch {}", i), track.finish()));
+        }
+
+        for (i, portal) in server.portals().iter().enumerate() {
+            let mut track = track();
+            let (from, to) = if portal.dest.0 < portal.origin.0 { (portal.dest.0, portal.origin.0) } else { (portal.origin.0, portal.dest.0) };
+            track.fill(from, to, '~');
+            track.fill(portal.origin.0, portal.origin_expiration.saturating_sub(1), 'o');
+            track.fill(portal.dest.0, portal.dest_expiration.saturating_sub(1), 'd');
+            track.mark(portal.origin.0, 'O');
+            track.mark(portal.dest.0, 'D');
+            lines.push((format!("portal {} p{}", i, portal.player), track.finish()));
+        }
+
+        let mut players: Vec<Player> = self.pings.iter().map(|ping| ping.player).collect();
+        players.sort();
+        players.dedup();
+        for player in players {
+            let mut track = track();
+            for ping in self.pings.iter().filter(|ping| ping.player == player) {
+                track.mark(ping.time, '!');
+            }
+            lines.push((format!("pings p{}", player), track.finish()));
+        }
+
+        let timeline: Vec<Vec<ID>> = (0..ticks)
+            .map(|t| server.spectate(t).unwrap_or_default().iter().map(|unit| unit.0).collect())
+            .collect();
+        let traversals = server.traversals().to_vec();
+        let mut unit = 0;
+        while let Some(player) = server.owner(unit) {
+            let mut track = track();
+            let mut alive = false;
+            for (t, units) in timeline.iter().enumerate() {
+                let present = units.contains(&unit);
+                if present {
+                    track.mark(t, '#');
+                    if !alive && traversals.iter().any(|traversal| traversal.arrival == unit) { track.mark(t, '<') }
+                } else if alive {
+                    let departed = traversals.iter().any(|traversal| traversal.unit == unit && traversal.departure == t);
+                    track.mark(t, if departed { '>' } else { 'x' });
+                }
+                alive = present;
+            }
+            lines.push((format!("unit {} p{}", unit, player), track.finish()));
+            unit += 1;
+        }
+
+        let label = lines.iter().map(|line| line.0.len()).max().unwrap_or(0);
+        lines.iter().map(|line| format!("{:width$}  {}\n", line.0, line.1.trim_end(), width = label)).collect()
+    }
+}
+
+/// One line of the rendering, several ticks may share a column
+struct Track {
+    cells: Vec<char>,
+    scale: usize
+}
+
+impl Track {
+    /// Marks a tick, keeping the more important symbol if the column is already taken
+    fn mark(&mut self, time: TimeIndex, symbol: char) {
+        if let Some(cell) = self.cells.get_mut(time / self.scale) {
+            if priority(symbol) >= priority(*cell) { *cell = symbol }
+        }
+    }
+
+    fn fill(&mut self, from: TimeIndex, to: TimeIndex, symbol: char) {
+        for time in from..to + 1 {
+            self.mark(time, symbol);
+        }
+    }
+
+    fn finish(self) -> String {
+        self.cells.into_iter().collect()
+    }
+}
+
+fn priority(symbol: char) -> u8 {
+    match symbol {
+        ' ' => 0,
+        '=' | '-' | '~' | '#' => 1,
+        'o' | 'd' => 2,
+        '+' | '<' | '>' => 3,
+        _ => 4
+    }
+}
+
+#[test]
+fn render_timeline() {
+    use {AIType, Command};
+    let mut s = Server::new();
+    s.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+    s.spawn(1, AIType::Knight, (20.0, 0.0), 0.0);
+    for _ in 0..4 { s.tick(); }
+    s.issue(0, Command::OpenPortal {
+        origin: (4, (0.0, 0.0)), origin_lifetime: 2, origin_scale: 1.0,
+        dest: (2, (20.0, 0.0)), dest_lifetime: 1, dest_scale: 1.0
+    }).unwrap();
+    for _ in 0..4 { s.tick(); }
+
+    let rendering = Inspector::new().render(&mut s);
+    assert_eq!(rendering.lines().collect::<Vec<_>>(), vec![
+        "time (x1)    |........",
+        "timeline     =++======",
+        "branch 0       +--",
+        "branch 1      +---",
+        "portal 0 p0    D~Oo",
+        "unit 0 p0    ####>",
+        "unit 1 p1    #########",
+        "unit 2 p0      <######"
+    ]);
+
+    // Three ticks per column, markers win over plain lifelines
+    let condensed = Inspector::new().width(4).render(&mut s);
+    assert_eq!(condensed.lines().nth(5), Some("unit 0 p0    #>"));
+}
+
+#[test]
+fn render_pings() {
+    let mut s = Server::new();
+    for _ in 0..6 { s.tick(); }
+    let pings = vec![
+        Ping { player: 1, location: (0.0, 0.0), time: 4 },
+        Ping { player: 0, location: (1.0, 0.0), time: 2 },
+        Ping { player: 1, location: (2.0, 0.0), time: 5 }
+    ];
+    let rendering = Inspector::new().pings(pings).render(&mut s);
+    assert_eq!(rendering.lines().skip(2).collect::<Vec<_>>(), vec![
+        "pings p0     !",
+        "pings p1       !!"
+    ]);
+}
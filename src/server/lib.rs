@@ -1,15 +1,56 @@
 #![allow(dead_code)]
-use std::collections::BTreeMap;
+// Structs are initialized as `field: field` throughout, the style of the 2015 code base
+#![allow(clippy::redundant_field_names)]
+extern crate rand;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
 #[cfg(feature = "f64-precision")]
 type Coordinate = (f64, f64);
 #[cfg(not(feature = "f64-precision"))]
-type Coordinates = (f32, f32);
-type Orientation = f32;
-type Keyframe = Vec<(ID, Coordinates, Orientation)>;
-type TimeIndex = usize;
-type Player = usize;
-type ID = usize;
+pub type Coordinates = (f32, f32);
+pub type Orientation = f32;
+pub type Keyframe = Vec<(ID, Coordinates, Orientation)>;
+pub type TimeIndex = usize;
+pub type Player = usize;
+pub type ID = usize;
+
+#[macro_use]
+pub mod logging;
+pub mod strategist;
+pub mod lobby;
+pub mod session;
+pub mod lockstep;
+pub mod movement;
+pub mod chat;
+pub mod transport;
+pub mod auth;
+pub mod replay;
+pub mod events;
+pub mod inspector;
+
+use replay::{Record, Replay};
+use lockstep::Checksum;
+use events::Event;
+use logging::Level;
+
+/// Distance below which two hostile units engage each other
+const COMBAT_RANGE: f32 = 2.0;
+/// Distance up to which units reveal enemies to their player
+const SIGHT_RANGE: f32 = 20.0;
+/// Half the edge length of the square map centered around the origin
+const MAP_SIZE: f32 = 100.0;
+/// How far into the future portals may be planned
+const PLAN_AHEAD: TimeIndex = 50;
+/// Largest scale of a portal endpoint
+const MAX_PORTAL_SCALE: f32 = 5.0;
+/// Number of most recently abandoned branches whose keyframes are kept around for spectators
+const KEPT_BRANCHES: usize = 16;
+
+fn distance(a: Coordinates, b: Coordinates) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
 
 
 // ------------------------------------------ PORTAL -----------------------------------------
@@ -28,14 +69,128 @@ struct Portal {
     compression_factor: (f32, f32) // (size, time) - compression level when traveling origin->dest
 }
 
+impl Endpoint {
+    fn is_open(&self, time: TimeIndex) -> bool {
+        self.creation <= time && time < self.expiration
+    }
+
+    fn contains(&self, location: Coordinates) -> bool {
+        distance(self.location, location) <= self.scale
+    }
+}
+
+/// Read-only description of a portal as visible to the players
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortalInfo {
+    pub player: Player,
+    pub origin: (TimeIndex, Coordinates),
+    pub origin_expiration: TimeIndex,
+    pub dest: (TimeIndex, Coordinates),
+    pub dest_expiration: TimeIndex
+}
+
+/// Keyframes that have been replaced after the past was changed. Only the most recent branches
+/// keep their keyframes, older ones are merely remembered.
+struct Branch {
+    forked_at: TimeIndex,
+    end: TimeIndex,
+    keyframes: BTreeMap<TimeIndex, Keyframe>
+}
+
+/// Read-only description of an abandoned timeline branch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BranchInfo {
+    pub forked_at: TimeIndex,
+    pub end: TimeIndex
+}
+
+/// A unit leaving the timeline through a portal and the unit it became on the other side
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Traversal {
+    pub unit: ID,
+    pub portal: usize,
+    pub departure: TimeIndex,
+    pub arrival: ID
+}
+
+// ----------------------------------------- COMMANDS ----------------------------------------
+
+/// Everything a player is able to do, no matter whether it is a human or the `Strategist`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Move { unit: ID, target: Coordinates },
+    OpenPortal {
+        origin: (TimeIndex, Coordinates), origin_lifetime: TimeIndex, origin_scale: f32,
+        dest: (TimeIndex, Coordinates), dest_lifetime: TimeIndex, dest_scale: f32
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    UnknownUnit(ID),
+    NotOwner(ID),
+    /// The unit doesn't exist at the present time, e.g. because it died or traveled
+    NotPresent(ID),
+    /// The location lies outside the map. Maps have no obstacles, so everything else is reachable.
+    OffMap(Coordinates),
+    OutsideWindow(TimeIndex),
+    InvalidLifetime(TimeIndex),
+    InvalidScale(f32)
+}
+
+impl CommandError {
+    /// Compact textual form used on the wire, readable by `CommandError::parse`
+    pub fn encode(&self) -> String {
+        match *self {
+            CommandError::UnknownUnit(id) => format!("UnknownUnit {}", id),
+            CommandError::NotOwner(id) => format!("NotOwner {}", id),
+            CommandError::NotPresent(id) => format!("NotPresent {}", id),
+            CommandError::OffMap(location) => format!("OffMap {} {}", location.0, location.1),
+            CommandError::OutsideWindow(time) => format!("OutsideWindow {}", time),
+            CommandError::InvalidLifetime(lifetime) => format!("InvalidLifetime {}", lifetime),
+            CommandError::InvalidScale(scale) => format!("InvalidScale {}", scale)
+        }
+    }
+
+    pub fn parse(encoded: &str) -> Option<CommandError> {
+        let parts: Vec<&str> = encoded.split_whitespace().collect();
+        match (parts.first().cloned(), parts.len()) {
+            (Some("UnknownUnit"), 2) => parts[1].parse().ok().map(CommandError::UnknownUnit),
+            (Some("NotOwner"), 2) => parts[1].parse().ok().map(CommandError::NotOwner),
+            (Some("NotPresent"), 2) => parts[1].parse().ok().map(CommandError::NotPresent),
+            (Some("OffMap"), 3) => match (parts[1].parse(), parts[2].parse()) {
+                (Ok(x), Ok(y)) => Some(CommandError::OffMap((x, y))),
+                _ => None
+            },
+            (Some("OutsideWindow"), 2) => parts[1].parse().ok().map(CommandError::OutsideWindow),
+            (Some("InvalidLifetime"), 2) => parts[1].parse().ok().map(CommandError::InvalidLifetime),
+            (Some("InvalidScale"), 2) => parts[1].parse().ok().map(CommandError::InvalidScale),
+            _ => None
+        }
+    }
+}
+
+fn on_map(location: Coordinates) -> bool {
+    location.0.abs() <= MAP_SIZE && location.1.abs() <= MAP_SIZE
+}
+
 // -------------------------------------------- AI -------------------------------------------
 
-#[derive(PartialEq)]
-enum AIType {
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AIType {
     Scout,
     Knight
 }
 
+impl AIType {
+    fn speed(&self) -> f32 {
+        match *self {
+            AIType::Scout => 1.0,
+            AIType::Knight => 0.5
+        }
+    }
+}
+
 struct AI {
     ai_type: AIType,
     player: Player,
@@ -48,17 +203,249 @@ struct AI {
 pub struct Server {
     portals: Vec<Portal>,
     keyframes: BTreeMap<TimeIndex, Keyframe>,
-    ais: Vec<AI>
+    ais: Vec<AI>,
+    orders: BTreeMap<TimeIndex, Vec<(ID, Coordinates)>>,
+    traversals: Vec<Traversal>,
+    branches: Vec<Branch>,
+    present: TimeIndex,
+    horizon: TimeIndex,
+    records: Vec<(TimeIndex, Record)>,
+    subscribers: Vec<Sender<Event>>,
+    /// Deaths and paradoxes already reported, so recalculations don't repeat them
+    deaths: BTreeSet<(ID, TimeIndex)>,
+    paradoxes: BTreeSet<usize>,
+    ended: bool
 }
 
 impl Server {
     pub fn new() -> Server {
-        if cfg!(feature = "f64-precision") { println!("Using double precision floating mode!") }
+        if cfg!(feature = "f64-precision") { log!(Level::Info, "simulation", "using double precision floating mode") }
+        let mut keyframes = BTreeMap::new();
+        keyframes.insert(0, Vec::new());
         Server {
             portals: Vec::new(),
-            keyframes: BTreeMap::new(),
-            ais: Vec::new()
+            keyframes: keyframes,
+            ais: Vec::new(),
+            orders: BTreeMap::new(),
+            traversals: Vec::new(),
+            branches: Vec::new(),
+            present: 0,
+            horizon: 100,
+            records: Vec::new(),
+            subscribers: Vec::new(),
+            deaths: BTreeSet::new(),
+            paradoxes: BTreeSet::new(),
+            ended: false
+        }
+    }
+
+    /// How far back in time portals are allowed to reach
+    pub fn horizon(mut self, horizon: TimeIndex) -> Server {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Places a new unit into the initial keyframe. Only meant to be used while setting up a match.
+    pub fn spawn(&mut self, player: Player, ai_type: AIType, location: Coordinates, orientation: Orientation) -> ID {
+        let id = self.ais.len();
+        self.ais.push(AI {
+            ai_type: ai_type,
+            player: player,
+            start_location: location,
+            start_orientation: orientation
+        });
+        self.keyframes.entry(0).or_default().push((id, location, orientation));
+        self.invalidate(0);
+        let present = self.present;
+        self.records.push((present, Record::Spawn { player: player, ai_type: ai_type, location: location, orientation: orientation }));
+        self.emit(Event::UnitSpawned { unit: id, player: player, ai_type: ai_type, location: location });
+        id
+    }
+
+    pub fn present(&self) -> TimeIndex {
+        self.present
+    }
+
+    /// Advances the present by one tick and returns the new present
+    pub fn tick(&mut self) -> TimeIndex {
+        self.present += 1;
+        let present = self.present;
+        let keyframe = self.calculate(present);
+
+        let closed: Vec<Event> = self.portals.iter().enumerate()
+            .filter(|&(_, p)| p.origin.expiration == present)
+            .map(|(i, p)| Event::PortalClosed { portal: i, player: p.player, time: present })
+            .collect();
+        for event in closed { self.emit(event); }
+
+        // Travelers whose departure got undone by a change of the past
+        let paradoxes: Vec<(usize, Traversal)> = self.traversals.iter().cloned().enumerate()
+            .filter(|&(i, t)| t.departure > 0 && t.departure <= present && !self.paradoxes.contains(&i))
+            .filter(|&(_, t)| self.keyframes.get(&(t.departure - 1)).is_some_and(|k| !k.iter().any(|unit| unit.0 == t.unit)))
+            .collect();
+        for (i, traversal) in paradoxes {
+            self.paradoxes.insert(i);
+            let player = self.get_ai(traversal.unit).player;
+            self.emit(Event::ParadoxDetected { unit: traversal.unit, player: player, departure: traversal.departure });
         }
+
+        if !self.ended && !self.ais.is_empty() {
+            let players: BTreeSet<Player> = keyframe.iter().map(|unit| self.get_ai(unit.0).player).collect();
+            if players.len() <= 1 {
+                self.ended = true;
+                self.emit(Event::MatchEnded { winner: players.into_iter().next(), time: present });
+            }
+        }
+        present
+    }
+
+    /// Returns a channel receiving every event from now on
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: Event) {
+        let (level, target) = match event {
+            Event::PortalOpened { .. } | Event::PortalClosed { .. } | Event::UnitTraversed { .. } => (Level::Debug, "portals"),
+            Event::ParadoxDetected { .. } => (Level::Warn, "portals"),
+            Event::GameStarted | Event::MatchEnded { .. } => (Level::Info, "simulation"),
+            _ => (Level::Debug, "simulation")
+        };
+        log!(level, target, "event"; present = self.present, event = event);
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Returns the state of the world at `time` as seen by `player`. The future can't be observed
+    /// and enemies are only visible within the sight range of the player's own units.
+    pub fn observe(&mut self, player: Player, time: TimeIndex) -> Option<Keyframe> {
+        let keyframe = self.spectate(time)?;
+        let (own, others): (Keyframe, Keyframe) = keyframe.into_iter().partition(|unit| self.get_ai(unit.0).player == player);
+        let visible: Keyframe = others.into_iter()
+            .filter(|unit| own.iter().any(|ally| distance(ally.1, unit.1) <= SIGHT_RANGE))
+            .collect();
+        Some(own.into_iter().chain(visible).collect())
+    }
+
+    /// Returns the complete state of the world at `time` without any fog of war
+    pub fn spectate(&mut self, time: TimeIndex) -> Option<Keyframe> {
+        if time > self.present { return None }
+        Some(self.calculate(time))
+    }
+
+    /// Lists the timeline branches that have been abandoned because the past changed
+    pub fn branches(&self) -> Vec<BranchInfo> {
+        self.branches.iter().map(|branch| BranchInfo {
+            forked_at: branch.forked_at,
+            end: branch.end
+        }).collect()
+    }
+
+    /// Returns the state of an abandoned branch at a time after it forked off, as long as it is
+    /// one of the `KEPT_BRANCHES` most recent ones
+    pub fn spectate_branch(&self, branch: usize, time: TimeIndex) -> Option<Keyframe> {
+        self.branches.get(branch).and_then(|branch| branch.keyframes.get(&time)).cloned()
+    }
+
+    pub fn owner(&self, id: ID) -> Option<Player> {
+        self.ais.get(id).map(|ai| ai.player)
+    }
+
+    pub fn unit_type(&self, id: ID) -> Option<AIType> {
+        self.ais.get(id).map(|ai| ai.ai_type)
+    }
+
+    pub fn portals(&self) -> Vec<PortalInfo> {
+        self.portals.iter().map(|p| PortalInfo {
+            player: p.player,
+            origin: (p.origin.creation, p.origin.location),
+            origin_expiration: p.origin.expiration,
+            dest: (p.dest.creation, p.dest.location),
+            dest_expiration: p.dest.expiration
+        }).collect()
+    }
+
+    /// Executes a command on behalf of `player` at the present time
+    pub fn issue(&mut self, player: Player, command: Command) -> Result<(), CommandError> {
+        self.validate(player, &command)?;
+        let present = self.present;
+        self.records.push((present, Record::Command(player, command.clone())));
+        match command {
+            Command::Move { unit, target } => {
+                self.orders.entry(present).or_default().push((unit, target));
+                self.invalidate(present);
+            },
+            Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale } => {
+                self.create_portal(player, origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale);
+                let earliest = if origin.0 < dest.0 { origin.0 } else { dest.0 };
+                self.invalidate(if earliest < present { earliest } else { present });
+            }
+        }
+        Ok(())
+    }
+
+    /// Recording of everything that happened so far, enough to reproduce the match
+    pub fn replay(&mut self) -> Replay {
+        let present = self.present;
+        let mut replay = Replay::new(self.horizon);
+        replay.records = self.records.clone();
+        replay.end = present;
+        replay.checksum = Some(Checksum::new(&self.calculate(present)).keyframe);
+        replay
+    }
+
+    /// Every trip through a portal so far, including those of abandoned timelines
+    pub fn traversals(&self) -> &[Traversal] {
+        &self.traversals
+    }
+
+    /// Makes sure a command is legal for the player at the present time
+    fn validate(&mut self, player: Player, command: &Command) -> Result<(), CommandError> {
+        let present = self.present;
+        match *command {
+            Command::Move { unit, target } => {
+                match self.owner(unit) {
+                    None => return Err(CommandError::UnknownUnit(unit)),
+                    Some(owner) if owner != player => return Err(CommandError::NotOwner(unit)),
+                    _ => {}
+                }
+                if !self.calculate(present).iter().any(|other| other.0 == unit) { return Err(CommandError::NotPresent(unit)) }
+                if !on_map(target) { return Err(CommandError::OffMap(target)) }
+            },
+            Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale } => {
+                let earliest = present.saturating_sub(self.horizon);
+                if origin.0 < present || origin.0 > present + PLAN_AHEAD { return Err(CommandError::OutsideWindow(origin.0)) }
+                if dest.0 < earliest || dest.0 > present + PLAN_AHEAD { return Err(CommandError::OutsideWindow(dest.0)) }
+                for &(location, lifetime, scale) in [(origin.1, origin_lifetime, origin_scale), (dest.1, dest_lifetime, dest_scale)].iter() {
+                    if !on_map(location) { return Err(CommandError::OffMap(location)) }
+                    if lifetime == 0 || lifetime > PLAN_AHEAD { return Err(CommandError::InvalidLifetime(lifetime)) }
+                    if !(scale > 0.0 && scale <= MAX_PORTAL_SCALE) { return Err(CommandError::InvalidScale(scale)) }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every keyframe after `time` so it gets recalculated on the next request.
+    /// The dropped keyframes are kept as a branch of the timeline.
+    fn invalidate(&mut self, time: TimeIndex) {
+        let stale = self.keyframes.split_off(&(time + 1));
+        if !stale.is_empty() {
+            let end = stale.keys().next_back().cloned().unwrap_or(time);
+            self.branches.push(Branch { forked_at: time, end: end, keyframes: stale });
+            let branch = self.branches.len() - 1;
+            if branch >= KEPT_BRANCHES {
+                self.branches[branch - KEPT_BRANCHES].keyframes.clear();
+            }
+            self.emit(Event::TimelineForked { forked_at: time, branch: branch });
+        }
+    }
+
+    fn current_order(&self, id: ID, time: TimeIndex) -> Option<Coordinates> {
+        self.orders.range(..time).rev()
+            .filter_map(|(_, orders)| orders.iter().rev().find(|order| order.0 == id))
+            .next().map(|order| order.1)
     }
 
     fn create_portal(&mut self, player: Player,
@@ -83,7 +470,10 @@ impl Server {
                 dest: dest,
                 compression_factor: (dest_scale / origin_scale, (dest_lifetime as f32) / (origin_lifetime as f32))
             }
-        )
+        );
+        let portal = self.portals.len() - 1;
+        let info = self.portals()[portal];
+        self.emit(Event::PortalOpened { portal: portal, info: info });
     }
 
     fn get_ai(&self, id: ID) -> &AI {
@@ -107,44 +497,149 @@ impl Server {
             for x in last.iter() {
                 let id = x.0;
                 let mut loc = x.1;
-                let o = x.2;
+                let mut o = x.2;
+                let order = self.current_order(id, current);
                 let ai = self.get_ai(id);
 
-                if ai.ai_type == AIType::Scout {
+                if let Some(goal) = order {
+                    let d = distance(loc, goal);
+                    if d > 0.0 {
+                        let step = if d < ai.ai_type.speed() { d } else { ai.ai_type.speed() };
+                        o = (goal.1 - loc.1).atan2(goal.0 - loc.0);
+                        loc.0 += o.cos() * step;
+                        loc.1 += o.sin() * step;
+                    }
+                } else if ai.ai_type == AIType::Scout {
                     loc.0 = loc.0 + 1.0;
                     loc.1 = loc.0.powf(2.0);
                 }
 
                 ais.push((id, loc, o));
             }
+
+            // Units that died in battle are removed before anyone is able to travel
+            let survivors: Vec<bool> = ais.iter().map(|unit| {
+                let player = self.get_ai(unit.0).player;
+                let (allies, enemies) = ais.iter()
+                    .filter(|other| distance(unit.1, other.1) <= COMBAT_RANGE)
+                    .fold((0, 0), |(a, e), other| {
+                        if self.get_ai(other.0).player == player { (a + 1, e) } else { (a, e + 1) }
+                    });
+                enemies <= allies
+            }).collect();
+            let (ais, dead): (Vec<_>, Vec<_>) = ais.into_iter().zip(survivors).partition(|&(_, alive)| alive);
+            let mut ais: Vec<(ID, Coordinates, Orientation)> = ais.into_iter().map(|(unit, _)| unit).collect();
+            for (unit, _) in dead {
+                if self.deaths.insert((unit.0, current)) {
+                    let player = self.get_ai(unit.0).player;
+                    self.emit(Event::UnitDied { unit: unit.0, player: player, time: current, location: unit.1 });
+                }
+            }
+
+            // Units arriving from the future
+            for traversal in self.traversals.iter() {
+                let ref dest = self.portals[traversal.portal].dest;
+                if dest.creation == current {
+                    ais.push((traversal.arrival, dest.location, 0.0));
+                }
+            }
+
+            // Units entering a portal leave the timeline and might change the past
+            let mut rewind = None;
+            for unit in ais.clone() {
+                if let Some(portal) = self.find_traversal(unit.0, unit.1, current) {
+                    ais.retain(|other| other.0 != unit.0);
+                    if self.traversals.iter().any(|t| t.unit == unit.0 && t.departure == current) { continue }
+
+                    let arrival = {
+                        let ai = self.get_ai(unit.0);
+                        AI {
+                            ai_type: ai.ai_type,
+                            player: ai.player,
+                            start_location: self.portals[portal].dest.location,
+                            start_orientation: unit.2
+                        }
+                    };
+                    let arrival_id = self.ais.len();
+                    self.ais.push(arrival);
+                    self.traversals.push(Traversal { unit: unit.0, portal: portal, departure: current, arrival: arrival_id });
+                    let player = self.get_ai(unit.0).player;
+                    self.emit(Event::UnitTraversed { unit: unit.0, player: player, portal: portal, departure: current, arrival: arrival_id });
+
+                    let arrival_time = self.portals[portal].dest.creation;
+                    if arrival_time <= current {
+                        rewind = Some(match rewind {
+                            Some(t) if t < arrival_time => t,
+                            _ => arrival_time
+                        });
+                    }
+                }
+            }
+
             self.keyframes.insert(current, ais.clone());
             last = ais;
+
+            if let Some(time) = rewind {
+                // Travelers landing at the very beginning are added to the initial setup
+                if time == 0 {
+                    for traversal in self.traversals.iter() {
+                        let ref dest = self.portals[traversal.portal].dest;
+                        if dest.creation == 0 && !self.keyframes[&0].iter().any(|unit| unit.0 == traversal.arrival) {
+                            self.keyframes.get_mut(&0).unwrap().push((traversal.arrival, dest.location, 0.0));
+                        }
+                    }
+                    current = 0;
+                } else {
+                    current = time - 1;
+                }
+                self.invalidate(current);
+                last = self.keyframes[&current].clone();
+            }
         }
         self.keyframes.get(&target).unwrap().clone()
     }
 
-    fn print_keyframes(&self) {
-        for keyframe in self.keyframes.iter() {
-            for ai in keyframe.1.iter() {
-                println!("AI: {}, X: {}, Y: {}", ai.0, (ai.1).0, (ai.1).1);
+    /// Returns the portal a unit at `location` would enter at `time`, if any
+    fn find_traversal(&self, id: ID, location: Coordinates, time: TimeIndex) -> Option<usize> {
+        // Units that already traveled through a portal can't use it a second time
+        let traveled: Vec<usize> = self.traversals.iter().filter(|t| t.arrival == id).map(|t| t.portal).collect();
+        let player = self.get_ai(id).player;
+        self.portals.iter().enumerate().find(|&(i, p)| {
+            p.player == player && p.origin.is_open(time) && p.origin.contains(location) && !traveled.contains(&i)
+        }).map(|(i, _)| i)
+    }
+
+    /// Human readable listing of every calculated keyframe of the current timeline
+    pub fn dump_keyframes(&self) -> String {
+        let mut dump = String::new();
+        for (time, keyframe) in self.keyframes.iter() {
+            dump.push_str(&format!("T: {}\n", time));
+            for ai in keyframe.iter() {
+                dump.push_str(&format!("  AI: {}, X: {}, Y: {}, O: {}\n", ai.0, (ai.1).0, (ai.1).1, ai.2));
             }
         }
+        dump
     }
 
-    fn print_portal(&self, id: usize) {
-        let ref p = self.portals[id];
-        let ref origin = p.origin;
-        let ref dest = p.dest;
-        println!("ORIGIN: [X: {}, Y: {}, T: {}], Expiration: {}",
-                    origin.location.0, origin.location.1, origin.creation, origin.expiration);
-        println!("DESTIN: [X: {}, Y: {}, T: {}], Expiration: {}",
-                    dest.location.0, dest.location.1, dest.creation, dest.expiration);
-        println!("Compression ratio (size): {}", p.compression_factor.0);
-        println!("Compression ratio (time): {}", p.compression_factor.1);
+    /// Human readable listing of every portal
+    pub fn dump_portals(&self) -> String {
+        let mut dump = String::new();
+        for (i, p) in self.portals.iter().enumerate() {
+            let origin = &p.origin;
+            let dest = &p.dest;
+            dump.push_str(&format!("PORTAL {} of player {}\n", i, p.player));
+            dump.push_str(&format!("  ORIGIN: [X: {}, Y: {}, T: {}], Expiration: {}\n",
+                                   origin.location.0, origin.location.1, origin.creation, origin.expiration));
+            dump.push_str(&format!("  DESTIN: [X: {}, Y: {}, T: {}], Expiration: {}\n",
+                                   dest.location.0, dest.location.1, dest.creation, dest.expiration));
+            dump.push_str(&format!("  Compression ratio (size): {}\n", p.compression_factor.0));
+            dump.push_str(&format!("  Compression ratio (time): {}\n", p.compression_factor.1));
+        }
+        dump
     }
 
     pub fn start_game(&mut self) {
-        println!("A new game has been started!");
+        self.emit(Event::GameStarted);
     }
 }
 
@@ -172,6 +667,129 @@ fn calculate_keyframes() {
     }
 }
 
+#[test]
+fn validate_commands() {
+    let mut s = Server::new().horizon(10);
+    let knight = s.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+    s.spawn(1, AIType::Knight, (50.0, 0.0), 0.0);
+    for _ in 0..20 { s.tick(); }
+
+    let portal = |origin: TimeIndex, dest: TimeIndex, scale: f32| Command::OpenPortal {
+        origin: (origin, (0.0, 0.0)), origin_lifetime: 5, origin_scale: scale,
+        dest: (dest, (1.0, 1.0)), dest_lifetime: 5, dest_scale: 1.0
+    };
+    assert_eq!(s.issue(0, Command::Move { unit: 7, target: (0.0, 0.0) }), Err(CommandError::UnknownUnit(7)));
+    assert_eq!(s.issue(0, Command::Move { unit: knight, target: (500.0, 0.0) }), Err(CommandError::OffMap((500.0, 0.0))));
+    assert_eq!(s.issue(0, portal(19, 15, 1.0)), Err(CommandError::OutsideWindow(19)));
+    assert_eq!(s.issue(0, portal(20, 5, 1.0)), Err(CommandError::OutsideWindow(5)));
+    assert_eq!(s.issue(0, portal(20, 15, 0.0)), Err(CommandError::InvalidScale(0.0)));
+    assert_eq!(s.issue(0, portal(20, 15, 1.0)), Ok(()));
+    assert!(s.portals.len() == 1);
+
+    let error = CommandError::OffMap((1.5, -2.0));
+    assert_eq!(CommandError::parse(&error.encode()), Some(error));
+}
+
+#[test]
+fn replay_match() {
+    use strategist::Strategist;
+    let mut s = Server::new();
+    for i in 0..3 {
+        s.spawn(0, AIType::Knight, (0.0, i as f32 * 3.0), 0.0);
+    }
+    for i in 0..4 {
+        s.spawn(1, AIType::Knight, (15.0, i as f32 * 0.5), 0.0);
+    }
+    let mut strategists = [Strategist::new(0), Strategist::new(1).horizon(10)];
+    for _ in 0..40 {
+        for strategist in strategists.iter_mut() { strategist.play(&mut s); }
+        s.tick();
+    }
+
+    assert!(!s.portals.is_empty() && !s.branches().is_empty());
+    let mut playback = replay::Playback::new(s.replay());
+    let end = playback.end();
+    playback.seek(end).unwrap();
+    for t in 0..end + 1 {
+        assert_eq!(playback.view(t), s.spectate(t));
+    }
+    assert_eq!(playback.server().branches(), s.branches());
+    playback.seek(10).unwrap();
+    assert_eq!(playback.present(), 10);
+    assert!(playback.view(11).is_none());
+}
+
+#[test]
+fn event_stream() {
+    use strategist::Strategist;
+    let mut s = Server::new();
+    let events = s.subscribe();
+    s.start_game();
+    for i in 0..3 {
+        s.spawn(0, AIType::Knight, (0.0, i as f32 * 3.0), 0.0);
+    }
+    for i in 0..4 {
+        s.spawn(1, AIType::Knight, (15.0, i as f32 * 0.5), 0.0);
+    }
+    let mut strategists = [Strategist::new(0), Strategist::new(1).horizon(10)];
+    for _ in 0..60 {
+        for strategist in strategists.iter_mut() { strategist.play(&mut s); }
+        s.tick();
+    }
+
+    let events: Vec<Event> = events.try_iter().collect();
+    assert_eq!(events[0], Event::GameStarted);
+    assert_eq!(events.iter().filter(|e| matches!(**e, Event::UnitSpawned { .. })).count(), 7);
+    assert!(events.iter().any(|e| matches!(*e, Event::PortalOpened { info, .. } if info.player == 0)));
+    assert!(events.iter().any(|e| matches!(*e, Event::UnitTraversed { unit: 2, .. })));
+    assert!(events.iter().any(|e| matches!(*e, Event::TimelineForked { .. })));
+    assert!(events.iter().any(|e| matches!(*e, Event::MatchEnded { winner: Some(1), .. })));
+
+    // Deaths are reported once, even though the past has been recalculated
+    let deaths: Vec<(ID, TimeIndex)> = events.iter().filter_map(|e| match *e {
+        Event::UnitDied { unit, time, .. } => Some((unit, time)),
+        _ => None
+    }).collect();
+    assert!(deaths.iter().all(|death| deaths.iter().filter(|other| *other == death).count() == 1));
+}
+
+#[test]
+fn prune_branches() {
+    let mut s = Server::new();
+    s.spawn(0, AIType::Scout, (0.0, 0.0), 0.0);
+    for _ in 0..KEPT_BRANCHES + 4 {
+        s.tick();
+        s.spawn(1, AIType::Scout, (10.0, 0.0), 0.0);
+    }
+
+    // Every fork is listed, but only the recent ones can still be watched
+    let branches = s.branches();
+    assert_eq!(branches.len(), KEPT_BRANCHES + 4);
+    assert_eq!(branches[0], BranchInfo { forked_at: 0, end: 1 });
+    assert!(s.spectate_branch(3, 1).is_none());
+    assert!(s.spectate_branch(4, 1).is_some());
+}
+
+#[test]
+fn debug_dumps() {
+    let mut s = Server::new();
+    s.spawn(0, AIType::Knight, (1.0, 2.0), 0.0);
+    s.tick();
+    s.issue(0, Command::OpenPortal {
+        origin: (1, (0.0, 0.0)), origin_lifetime: 2, origin_scale: 1.0,
+        dest: (0, (5.0, 5.0)), dest_lifetime: 1, dest_scale: 2.0
+    }).unwrap();
+    s.tick();
+    assert!(s.dump_keyframes().starts_with("T: 0\n  AI: 0, X: 1, Y: 2, O: 0\nT: 1\n"));
+    assert_eq!(s.dump_portals().lines().collect::<Vec<_>>(), vec![
+        "PORTAL 0 of player 0",
+        "  ORIGIN: [X: 0, Y: 0, T: 1], Expiration: 3",
+        "  DESTIN: [X: 5, Y: 5, T: 0], Expiration: 1",
+        "  Compression ratio (size): 2",
+        "  Compression ratio (time): 0.5"
+    ]);
+}
+
 #[test]
 fn compression_ratio() {
     let mut s = Server::new();
@@ -0,0 +1,1629 @@
+//! Matchmaking for hosted games. Players browse open matches, take a slot and ready up.
+//! The lobby is shared over a simple line based TCP protocol:
+//!
+//! ```text
+//! HELLO <name> [<password>]                    -> OK
+//! LIST                                         -> MATCH <id> <map> <difficulty> <joined> <players> <horizon>... END
+//! CREATE <map> <difficulty> <players> <horizon> -> OK <id>
+//! JOIN <id>                                    -> OK <player> <token>
+//! LEAVE <id>                                   -> OK
+//! READY <id>                                   -> OK STARTED | OK WAITING
+//! RESUME <id> <token>                          -> OK <player>
+//! MOVE <id> <unit> <x> <y>                     -> OK
+//! PORTAL <id> <time> <x> <y> <lifetime> <scale> <time> <x> <y> <lifetime> <scale> -> OK
+//! SYNC <id>                                    -> KEYFRAME <time> (<unit> <x> <y> <orientation>)*... EVENT <index> <event>... END
+//! SPECTATE <id>                                -> OK
+//! BRANCHES <id>                                -> BRANCH <index> <forked at> <end>... END
+//! VIEW <id> <time> [<branch>]                  -> KEYFRAME <time> (<unit> <x> <y> <orientation>)*
+//! SAY <id> <all|team|spectators> <text>        -> OK
+//! MESSAGES <id> <since>                        -> MESSAGE <index> <time> <player|spectator> <channel> <text>... END
+//! PING <id> <time> <x> <y>                     -> OK
+//! PINGS <id>                                   -> PING <player> <time> <x> <y>... END
+//! EVENTS <id> <since>                          -> EVENT <index> <event>... END
+//! SCENERY <id> (<x> <z> <radius> <top>)*        -> OK
+//! INPUT <id> (<sequence> <forward> <backward> <left> <right> <up> <down> <yaw> <dt>)*
+//!                                              -> OK <sequence> <x> <y> <z> <y speed> | OK NONE
+//! SUBMIT <id> <tick> <command>*                 -> OK ADVANCED | OK WAITING
+//! ADVANCE <id> <since>                         -> STEP <tick> (<player> <command>)*... END
+//! CHECKSUM <id> <tick> <keyframe> (<unit> <hash>)* -> OK
+//! ```
+//!
+//! Matches are played on `valley`, `forest` or `sandbox`. Every player starts with as many
+//! knights as the difficulty, from 1 to 10, except on the sandbox, which starts out empty.
+//! Everyone takes one slot of a match at most.
+//!
+//! Commands handed in for a tick are written `MOVE <unit> <x> <y>` or
+//! `PORTAL <time> <x> <y> <lifetime> <scale> <time> <x> <y> <lifetime> <scale>`.
+//!
+//! Failures are answered with `ERR <reason>`, rejected commands with `ERR Command <error>` and
+//! requests beyond the rate limit with `ERR RateLimited`. Everyone taking part in a match has a
+//! budget of requests to it, shared by all their connections. Requests to look at the timeline
+//! cost more than commands, the inputs sent every frame far less. Requests to matches the
+//! connection doesn't take part in, or that ended, aren't charged. Every connection has to introduce itself with
+//! `HELLO` first, giving the server password if there is one, and may do so only once. A name
+//! can't be used by two live connections at the same time. The identity is bound to the
+//! `Player` slots it joins, so a connection may only act on behalf of its own slots. A fresh
+//! connection may instead start with `RESUME`, which takes on the name the session was joined
+//! with, so players can reconnect before the server noticed their old connection dropped.
+//! Sessions joined or resumed through a connection are considered disconnected once it is
+//! closed and expire after the lobby's grace period. Resuming a session hands it over to the new
+//! connection, closing the old one afterwards doesn't affect it anymore. `SYNC` returns the
+//! keyframes and events the session held by the connection missed since it last synced.
+//! `BRANCHES` and `VIEW` are only available to connections spectating the match, which players of
+//! the match aren't allowed to. Chat messages
+//! are sent on behalf of the session or spectator the connection holds for the match.
+//! Players alternate between two teams in the order of their slots. `EVENTS` streams what happens
+//! in the match, players only learn about events concerning themselves or everyone. Only the
+//! latest events are kept, clients falling further behind miss the oldest ones.
+//! Every player walks around the map as a first person avatar. `INPUT` hands in frames of movement
+//! and answers with the resulting authoritative state, including collisions with the obstacles
+//! reported by `SCENERY`. The lobby doesn't know the maps, so each client reports the scenery of the
+//! map it loaded. Frames covering more time than passed on the server, saving up at most a
+//! second, are refused until they are sent again, so are frames that aren't numbers.
+//! Clients simulating the match themselves run in lockstep: once every player submitted its
+//! commands for the present tick it advances, `ADVANCE` hands out the accepted commands of every
+//! tick since `since`. `CHECKSUM` compares a client's keyframe against the server and answers
+//! `ERR Desync <player> <tick> <unit|->` on the first difference.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Server, AIType, Command, CommandError, Player, TimeIndex, Keyframe, BranchInfo, Coordinates};
+use replay::Replay;
+use events::Event;
+use logging::Level;
+use auth::{Authenticator, AuthError, Identity};
+use session::{Attachment, Sessions, SessionError, Token};
+use chat::{Chat, ChatError, Channel, Message, Participant, Ping};
+use lockstep::{Checksum, Desync, Lockstep, LockstepError, Step};
+use movement::{Authority, AvatarState, Input, Obstacle};
+
+/// Events kept per match for clients catching up, older ones are forgotten
+const KEPT_EVENTS: usize = 1024;
+/// Tokens of the rate limit every frame of movement takes, on top of the `INPUT` request
+const INPUT_COST: f32 = 0.05;
+/// Maps matches can be played on and how far from the center the armies start. The sandbox
+/// starts out empty, the units are placed by whoever hosts the match.
+const MAPS: [(&str, Option<f32>); 3] = [("valley", Some(10.0)), ("forest", Some(30.0)), ("sandbox", None)];
+/// Largest difficulty, which is the number of knights every player starts with
+const MAX_DIFFICULTY: i8 = 10;
+/// Distance between the knights of an army
+const ARMY_SPACING: f32 = 1.5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchSettings {
+    pub map: String,
+    pub difficulty: i8,
+    pub players: usize,
+    pub horizon: TimeIndex
+}
+
+impl MatchSettings {
+    fn valid(&self) -> bool {
+        self.players > 0 && self.difficulty > 0 && self.difficulty <= MAX_DIFFICULTY &&
+            MAPS.iter().any(|&(map, _)| map == self.map)
+    }
+
+    /// Spawns the army of every player, `difficulty` knights in a row facing the center of the
+    /// map. Clients simulating the match in lockstep set up their replica the same way.
+    pub fn spawn_armies(&self, server: &mut Server) {
+        let distance = match MAPS.iter().find(|&&(map, _)| map == self.map) {
+            Some(&(_, Some(distance))) => distance,
+            _ => return
+        };
+        for player in 0..self.players {
+            let angle = 2.0 * ::std::f32::consts::PI * player as f32 / self.players as f32;
+            let (sin, cos) = angle.sin_cos();
+            for knight in 0..self.difficulty {
+                let offset = (knight as f32 - (self.difficulty - 1) as f32 / 2.0) * ARMY_SPACING;
+                let location = (distance * cos - offset * sin, distance * sin + offset * cos);
+                server.spawn(player, AIType::Knight, location, angle + ::std::f32::consts::PI);
+            }
+        }
+    }
+}
+
+/// Summary of a match as shown in the match browser
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchInfo {
+    pub id: usize,
+    pub settings: MatchSettings,
+    pub joined: usize
+}
+
+/// Keyframes and indexed events a session hasn't received yet
+pub type Missed = (Vec<(TimeIndex, Keyframe)>, Vec<(usize, Event)>);
+
+#[derive(Debug)]
+pub enum LobbyError {
+    UnknownMatch(usize),
+    UnknownPlayer(Player),
+    MatchFull,
+    AlreadyStarted,
+    InvalidSettings,
+    NotStarted,
+    NotSpectating,
+    NotParticipating,
+    AlreadyParticipating,
+    Unauthenticated,
+    RateLimited,
+    Auth(AuthError),
+    Command(CommandError),
+    UnknownTime(TimeIndex),
+    Session(SessionError),
+    Chat(ChatError),
+    Lockstep(LockstepError),
+    Desync(Desync),
+    Protocol(String),
+    Io(io::Error)
+}
+
+impl From<AuthError> for LobbyError {
+    fn from(e: AuthError) -> LobbyError {
+        LobbyError::Auth(e)
+    }
+}
+
+impl From<CommandError> for LobbyError {
+    fn from(e: CommandError) -> LobbyError {
+        LobbyError::Command(e)
+    }
+}
+
+impl From<ChatError> for LobbyError {
+    fn from(e: ChatError) -> LobbyError {
+        LobbyError::Chat(e)
+    }
+}
+
+impl From<LockstepError> for LobbyError {
+    fn from(e: LockstepError) -> LobbyError {
+        LobbyError::Lockstep(e)
+    }
+}
+
+impl From<SessionError> for LobbyError {
+    fn from(e: SessionError) -> LobbyError {
+        LobbyError::Session(e)
+    }
+}
+
+impl From<io::Error> for LobbyError {
+    fn from(e: io::Error) -> LobbyError {
+        LobbyError::Io(e)
+    }
+}
+
+struct Member {
+    name: String,
+    ready: bool
+}
+
+struct Match {
+    settings: MatchSettings,
+    slots: Vec<Option<Member>>,
+    sessions: Sessions,
+    chat: Chat,
+    server: Option<Server>,
+    /// The latest events of the server and the subscription for upcoming ones. Events keep
+    /// their index within the whole match, `first_event` being the index of the oldest one kept.
+    events: VecDeque<Event>,
+    first_event: usize,
+    subscription: Option<Receiver<Event>>,
+    ended: bool,
+    /// Request budget of everyone taking part in the match, shared by all their connections.
+    /// Dropped once the match ended.
+    limiters: HashMap<String, RateLimiter>,
+    /// First person avatar of every slot and when its inputs were last handed in
+    avatars: Vec<Authority>,
+    walked: Vec<Instant>,
+    lockstep: Lockstep,
+    /// Every tick the lockstep advanced, kept for the whole match like the records of its replay
+    steps: Vec<Step>,
+    /// Whether the replay of the ended match has been saved
+    archived: bool
+}
+
+impl Match {
+    /// Takes in the events the server emitted since the last call, forgetting the oldest ones
+    /// beyond `KEPT_EVENTS`
+    fn poll(&mut self) {
+        if let Some(ref subscription) = self.subscription {
+            for event in subscription.try_iter() {
+                if let Event::MatchEnded { .. } = event {
+                    self.ended = true;
+                    self.limiters.clear();
+                }
+                self.events.push_back(event);
+            }
+        }
+        while self.events.len() > KEPT_EVENTS {
+            self.events.pop_front();
+            self.first_event += 1;
+        }
+    }
+
+    /// Index the next event will get
+    fn event_count(&self) -> usize {
+        self.first_event + self.events.len()
+    }
+
+    /// Returns the events starting at index `since` that `reader` is allowed to know about.
+    /// Events that have been forgotten already are skipped.
+    fn events(&mut self, reader: Participant, since: usize) -> Vec<(usize, Event)> {
+        self.poll();
+        let first = self.first_event;
+        self.events.iter().cloned().enumerate().map(|(i, event)| (first + i, event)).skip(since.saturating_sub(first)).filter(|(_, event)| match (reader, event.player()) {
+            (Participant::Player(player), Some(concerned)) => player == concerned,
+            _ => true
+        }).collect()
+    }
+}
+
+// ------------------------------------------ LOBBY ------------------------------------------
+
+pub struct Lobby {
+    matches: BTreeMap<usize, Match>,
+    next_id: usize,
+    grace_period: Duration,
+    rate_limit: (f32, f32),
+    authenticator: Authenticator,
+    /// Names in use by live connections, along with the number of connections using them
+    online: HashMap<String, usize>,
+    /// Where replays of ended matches are saved
+    replays: Option<PathBuf>
+}
+
+impl Default for Lobby {
+    fn default() -> Lobby {
+        Lobby::new()
+    }
+}
+
+impl Lobby {
+    pub fn new() -> Lobby {
+        Lobby {
+            matches: BTreeMap::new(),
+            next_id: 0,
+            grace_period: Duration::from_secs(60),
+            rate_limit: (20.0, 10.0),
+            authenticator: Authenticator::new(None),
+            online: HashMap::new(),
+            replays: None
+        }
+    }
+
+    /// Saves the replay of every match that ended into `directory`, named after the match id
+    pub fn replays<P: Into<PathBuf>>(mut self, directory: P) -> Lobby {
+        self.replays = Some(directory.into());
+        self
+    }
+
+    /// Requires everyone to know the password before using the lobby
+    pub fn password(mut self, password: &str) -> Lobby {
+        self.authenticator = Authenticator::new(Some(password.to_string()));
+        self
+    }
+
+    /// Lets each connection send `burst` commands at once and `per_second` on average
+    pub fn rate_limit(mut self, burst: f32, per_second: f32) -> Lobby {
+        self.rate_limit = (burst, per_second);
+        self
+    }
+
+    /// Admits a connection under a name no other live connection uses
+    pub fn authenticate(&mut self, name: &str, password: Option<&str>) -> Result<Identity, LobbyError> {
+        let identity = self.authenticator.authenticate(name, password)?;
+        if self.online.contains_key(name) { return Err(LobbyError::Auth(AuthError::NameTaken)) }
+        self.online.insert(identity.name.clone(), 1);
+        Ok(identity)
+    }
+
+    /// Admits a connection resuming a session under the name the session was joined with. The
+    /// token proves the identity, so the name may still be bound to the connection being replaced.
+    pub fn adopt(&mut self, id: usize, token: &str) -> Result<Identity, LobbyError> {
+        let player = self.player(id, token)?;
+        let name = self.player_name(id, player).ok_or(LobbyError::UnknownPlayer(player))?.to_string();
+        *self.online.entry(name.clone()).or_insert(0) += 1;
+        Ok(Identity { name: name })
+    }
+
+    /// Charges a request of `name`, who takes part in a match, against their rate limit and returns
+    /// whether it's allowed. Requests to a match that ended are free.
+    fn allow(&mut self, id: usize, name: &str, cost: f32, now: Instant) -> bool {
+        let rate_limit = self.rate_limit;
+        match self.matches.get_mut(&id) {
+            Some(m) => {
+                m.poll();
+                m.ended || m.limiters.entry(name.to_string())
+                    .or_insert_with(|| RateLimiter::new(rate_limit, now))
+                    .allow(now, cost)
+            },
+            None => true
+        }
+    }
+
+    /// Frees the name of a closed connection
+    pub fn release(&mut self, identity: &Identity) {
+        if let Some(count) = self.online.get_mut(&identity.name) {
+            *count -= 1;
+            if *count == 0 { self.online.remove(&identity.name); }
+        }
+    }
+
+    /// How long the slot of a disconnected player is held for it to reconnect
+    pub fn grace_period(mut self, grace_period: Duration) -> Lobby {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Lists all matches that haven't been started yet
+    pub fn list(&self) -> Vec<MatchInfo> {
+        self.matches.iter().filter(|&(_, m)| m.server.is_none()).map(|(id, m)| MatchInfo {
+            id: *id,
+            settings: m.settings.clone(),
+            joined: m.slots.iter().filter(|slot| slot.is_some()).count()
+        }).collect()
+    }
+
+    pub fn create(&mut self, settings: MatchSettings) -> Result<usize, LobbyError> {
+        if !settings.valid() { return Err(LobbyError::InvalidSettings) }
+        let id = self.next_id;
+        self.next_id += 1;
+        let slots = (0..settings.players).map(|_| None).collect();
+        let teams = (0..settings.players).map(|player| player % 2).collect();
+        let avatars = (0..settings.players).map(|_| Authority::new(AvatarState::spawn()).paced()).collect();
+        let walked = (0..settings.players).map(|_| Instant::now()).collect();
+        let lockstep = Lockstep::new(settings.players);
+        self.matches.insert(id, Match {
+            settings: settings,
+            slots: slots,
+            sessions: Sessions::new(self.grace_period),
+            chat: Chat::new(teams),
+            server: None,
+            events: VecDeque::new(),
+            first_event: 0,
+            subscription: None,
+            ended: false,
+            limiters: HashMap::new(),
+            avatars: avatars,
+            walked: walked,
+            lockstep: lockstep,
+            steps: Vec::new(),
+            archived: false
+        });
+        Ok(id)
+    }
+
+    /// Takes the first free slot of a match and returns the `Player` index assigned to it
+    /// together with the session token required to reconnect to it. Everyone takes one slot at most.
+    pub fn join(&mut self, id: usize, name: &str) -> Result<(Player, Token), LobbyError> {
+        let m = self.open_match(id)?;
+        if m.slots.iter().flatten().any(|member| member.name == name) { return Err(LobbyError::AlreadyParticipating) }
+        match m.slots.iter().position(|slot| slot.is_none()) {
+            Some(player) => {
+                m.slots[player] = Some(Member { name: name.to_string(), ready: false });
+                Ok((player, m.sessions.open(player)))
+            },
+            None => Err(LobbyError::MatchFull)
+        }
+    }
+
+    pub fn leave(&mut self, id: usize, player: Player) -> Result<(), LobbyError> {
+        let m = self.open_match(id)?;
+        match m.slots.get_mut(player) {
+            Some(slot @ &mut Some(_)) => {
+                *slot = None;
+                m.sessions.close(player);
+                Ok(())
+            },
+            _ => Err(LobbyError::UnknownPlayer(player))
+        }
+    }
+
+    /// Marks a player as ready and starts the match once every slot is taken and ready.
+    /// Returns whether the match has been started.
+    pub fn ready(&mut self, id: usize, player: Player) -> Result<bool, LobbyError> {
+        let m = self.open_match(id)?;
+        match m.slots.get_mut(player) {
+            Some(&mut Some(ref mut member)) => member.ready = true,
+            _ => return Err(LobbyError::UnknownPlayer(player))
+        }
+        if m.slots.iter().all(|slot| slot.as_ref().is_some_and(|member| member.ready)) {
+            let mut server = Server::new().horizon(m.settings.horizon);
+            m.subscription = Some(server.subscribe());
+            server.start_game();
+            m.settings.spawn_armies(&mut server);
+            log!(Level::Info, "networking", "match started"; id = id, map = m.settings.map);
+            m.server = Some(server);
+            return Ok(true)
+        }
+        Ok(false)
+    }
+
+    pub fn player_name(&self, id: usize, player: Player) -> Option<&str> {
+        self.matches.get(&id)
+            .and_then(|m| m.slots.get(player))
+            .and_then(|slot| slot.as_ref())
+            .map(|member| &member.name[..])
+    }
+
+    /// Reattaches a dropped client to the slot it was holding. Only the player who joined the slot
+    /// is able to take it back, even if somebody else got hold of the token.
+    pub fn resume(&mut self, id: usize, name: &str, token: &str) -> Result<Player, LobbyError> {
+        let player = self.player(id, token)?;
+        if self.player_name(id, player) != Some(name) { return Err(LobbyError::Auth(AuthError::WrongIdentity)) }
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.sessions.resume(token, Instant::now())?.0)
+    }
+
+    /// Executes a command on behalf of the player holding the session
+    pub fn issue(&mut self, id: usize, token: &str, command: Command) -> Result<(), LobbyError> {
+        let player = self.player(id, token)?;
+        let server = self.started_match(id)?;
+        Ok(server.issue(player, command)?)
+    }
+
+    /// Identifies the connection currently holding the session
+    pub fn attachment(&self, id: usize, token: &str) -> Result<Attachment, LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.sessions.attachment(token)?)
+    }
+
+    /// Starts the grace period of a session, unless another connection resumed it since `attachment`
+    pub fn disconnect(&mut self, id: usize, token: &str, attachment: Attachment) {
+        if let Some(m) = self.matches.get_mut(&id) {
+            m.sessions.disconnect(token, attachment, Instant::now());
+        }
+    }
+
+    /// Returns every keyframe and event the session hasn't received yet and marks them as delivered
+    pub fn sync(&mut self, id: usize, token: &str) -> Result<Missed, LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        let player = m.sessions.player(token)?;
+        let acknowledged = m.sessions.acknowledged(token)?;
+        let keyframes = {
+            let server = m.server.as_mut().ok_or(LobbyError::NotStarted)?;
+            let present = server.present();
+            m.sessions.acknowledge(token, present)?;
+            (acknowledged + 1..present + 1)
+                .filter_map(|time| server.observe(player, time).map(|keyframe| (time, keyframe)))
+                .collect()
+        };
+        let since = m.sessions.acknowledged_events(token)?;
+        let events = m.events(Participant::Player(player), since);
+        let count = m.event_count();
+        m.sessions.acknowledge_events(token, count)?;
+        Ok((keyframes, events))
+    }
+
+    /// Sets the scenery the avatar of a player collides with
+    pub fn scenery(&mut self, id: usize, player: Player, obstacles: Vec<Obstacle>) -> Result<(), LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        m.avatars.get_mut(player).ok_or(LobbyError::UnknownPlayer(player))?.obstacles(obstacles);
+        Ok(())
+    }
+
+    /// Moves the avatar of a player and returns its authoritative state together with the
+    /// sequence of the last input included. Inputs older than that are skipped for free, every
+    /// other one is charged against the rate limit while the match runs. The avatar only covers as much time as passed
+    /// since the last call, inputs beyond that or the rate limit are refused until the client
+    /// sends them again.
+    pub fn walk(&mut self, id: usize, player: Player, inputs: &[Input], now: Instant) -> Result<Option<(u32, AvatarState)>, LobbyError> {
+        let name = self.player_name(id, player).ok_or(LobbyError::UnknownPlayer(player))?.to_string();
+        let rate_limit = self.rate_limit;
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        m.poll();
+        let avatar = m.avatars.get_mut(player).ok_or(LobbyError::UnknownPlayer(player))?;
+        avatar.elapse(now.duration_since(m.walked[player]).as_secs_f32());
+        m.walked[player] = now;
+        let limiter = m.limiters.entry(name).or_insert_with(|| RateLimiter::new(rate_limit, now));
+        for input in inputs {
+            if avatar.snapshot().is_some_and(|(sequence, _)| input.sequence <= sequence) { continue }
+            if !m.ended && !limiter.allow(now, INPUT_COST) { break }
+            avatar.apply(input);
+        }
+        Ok(avatar.snapshot())
+    }
+
+    /// Hands in the commands of a player for the present tick. Returns whether it was the last
+    /// player missing and the match advanced.
+    pub fn submit(&mut self, id: usize, player: Player, tick: TimeIndex, commands: Vec<Command>) -> Result<bool, LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        let server = m.server.as_mut().ok_or(LobbyError::NotStarted)?;
+        match m.lockstep.submit(server, player, tick, commands)? {
+            Some(step) => {
+                m.steps.push(step);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Steps of every tick the lockstep advanced, starting at tick `since`
+    pub fn steps(&self, id: usize, since: TimeIndex) -> Result<Vec<Step>, LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.steps.iter().filter(|step| step.tick >= since).cloned().collect())
+    }
+
+    /// Compares the checksums a player calculated against the authoritative timeline
+    pub fn verify(&mut self, id: usize, player: Player, checksums: &[(TimeIndex, Checksum)]) -> Result<(), LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        let server = m.server.as_mut().ok_or(LobbyError::NotStarted)?;
+        m.lockstep.verify(server, player, checksums).map_err(|desync| {
+            log!(Level::Warn, "networking", "desync"; id = id, player = desync.player, tick = desync.tick, unit = desync.unit);
+            LobbyError::Desync(desync)
+        })
+    }
+
+    /// Checks whether a match can be watched by a spectator. Players of the match can't spectate
+    /// it, they would see through the fog of war.
+    pub fn spectate(&mut self, id: usize, name: &str) -> Result<(), LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        if m.slots.iter().flatten().any(|member| member.name == name) { return Err(LobbyError::AlreadyParticipating) }
+        self.started_match(id).map(|_| ())
+    }
+
+    pub fn branches(&mut self, id: usize) -> Result<Vec<BranchInfo>, LobbyError> {
+        self.started_match(id).map(|server| server.branches())
+    }
+
+    /// Returns the unfiltered state of the current timeline or one of its abandoned branches
+    pub fn view(&mut self, id: usize, time: TimeIndex, branch: Option<usize>) -> Result<Keyframe, LobbyError> {
+        let server = self.started_match(id)?;
+        match branch {
+            Some(branch) => server.spectate_branch(branch, time),
+            None => server.spectate(time)
+        }.ok_or(LobbyError::UnknownTime(time))
+    }
+
+    pub fn say(&mut self, id: usize, sender: Participant, channel: Channel, text: &str) -> Result<(), LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        let time = m.server.as_ref().map_or(0, |server| server.present());
+        Ok(m.chat.say(sender, channel, text, time)?)
+    }
+
+    pub fn ping(&mut self, id: usize, player: Player, location: Coordinates, time: TimeIndex) -> Result<(), LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.chat.ping(player, location, time)?)
+    }
+
+    pub fn messages(&self, id: usize, reader: Participant, since: usize) -> Result<Vec<(usize, Message)>, LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.chat.messages(reader, since).into_iter().map(|(i, message)| (i, message.clone())).collect())
+    }
+
+    pub fn pings(&self, id: usize, reader: Participant) -> Result<Vec<Ping>, LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.chat.pings(reader))
+    }
+
+    /// Returns the events starting at index `since` that `reader` is allowed to know about
+    pub fn events(&mut self, id: usize, reader: Participant, since: usize) -> Result<Vec<(usize, Event)>, LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.events(reader, since))
+    }
+
+    /// Recording of a running match including its chat
+    pub fn replay(&mut self, id: usize) -> Result<Replay, LobbyError> {
+        let m = self.matches.get_mut(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        let server = m.server.as_mut().ok_or(LobbyError::NotStarted)?;
+        let (messages, pings) = m.chat.history();
+        Ok(server.replay().chat(messages, pings))
+    }
+
+    fn player(&self, id: usize, token: &str) -> Result<Player, LobbyError> {
+        let m = self.matches.get(&id).ok_or(LobbyError::UnknownMatch(id))?;
+        Ok(m.sessions.player(token)?)
+    }
+
+    fn started_match(&mut self, id: usize) -> Result<&mut Server, LobbyError> {
+        match self.matches.get_mut(&id) {
+            Some(m) => m.server.as_mut().ok_or(LobbyError::NotStarted),
+            None => Err(LobbyError::UnknownMatch(id))
+        }
+    }
+
+    /// Frees the slots of players whose grace period ran out. Once a match has been started
+    /// the slot stays occupied and its units keep following their last orders.
+    /// The lobby server runs this before every request.
+    pub fn expire(&mut self, now: Instant) {
+        for m in self.matches.values_mut() {
+            for player in m.sessions.expire(now) {
+                if m.server.is_none() {
+                    m.slots[player] = None;
+                }
+            }
+        }
+    }
+
+    /// Saves the replays of matches that ended since the last call, if the lobby keeps replays.
+    /// The lobby server runs this before every request.
+    pub fn archive(&mut self) {
+        let directory = match self.replays {
+            Some(ref directory) => directory.clone(),
+            None => return
+        };
+        let ended: Vec<usize> = self.matches.iter_mut().filter_map(|(&id, m)| {
+            m.poll();
+            if m.ended && !m.archived { Some(id) } else { None }
+        }).collect();
+        for id in ended {
+            let path = directory.join(format!("match-{}.replay", id));
+            let result = self.replay(id).map_err(|e| format!("{:?}", e))
+                .and_then(|replay| replay.save(&path).map_err(|e| e.to_string()));
+            match result {
+                Ok(()) => log!(Level::Info, "replay", "saved"; id = id, path = path),
+                Err(e) => log!(Level::Error, "replay", "saving failed"; id = id, path = path, error = e)
+            }
+            if let Some(m) = self.matches.get_mut(&id) { m.archived = true }
+        }
+    }
+
+    /// Grants access to the simulation of a started match
+    pub fn server(&mut self, id: usize) -> Option<&mut Server> {
+        self.matches.get_mut(&id).and_then(|m| m.server.as_mut())
+    }
+
+    fn open_match(&mut self, id: usize) -> Result<&mut Match, LobbyError> {
+        match self.matches.get_mut(&id) {
+            Some(ref m) if m.server.is_some() => Err(LobbyError::AlreadyStarted),
+            Some(m) => Ok(m),
+            None => Err(LobbyError::UnknownMatch(id))
+        }
+    }
+}
+
+// ------------------------------------------ SERVER -----------------------------------------
+
+pub struct LobbyServer {
+    listener: TcpListener,
+    lobby: Arc<Mutex<Lobby>>
+}
+
+impl LobbyServer {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<LobbyServer> {
+        Ok(LobbyServer {
+            listener: TcpListener::bind(address)?,
+            lobby: Arc::new(Mutex::new(Lobby::new()))
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn grace_period(self, grace_period: Duration) -> LobbyServer {
+        let lobby = ::std::mem::take(&mut *self.lobby.lock().unwrap());
+        *self.lobby.lock().unwrap() = lobby.grace_period(grace_period);
+        self
+    }
+
+    pub fn password(self, password: &str) -> LobbyServer {
+        let lobby = ::std::mem::take(&mut *self.lobby.lock().unwrap());
+        *self.lobby.lock().unwrap() = lobby.password(password);
+        self
+    }
+
+    pub fn replays<P: Into<PathBuf>>(self, directory: P) -> LobbyServer {
+        let lobby = ::std::mem::take(&mut *self.lobby.lock().unwrap());
+        *self.lobby.lock().unwrap() = lobby.replays(directory);
+        self
+    }
+
+    pub fn rate_limit(self, burst: f32, per_second: f32) -> LobbyServer {
+        let lobby = ::std::mem::take(&mut *self.lobby.lock().unwrap());
+        *self.lobby.lock().unwrap() = lobby.rate_limit(burst, per_second);
+        self
+    }
+
+    pub fn lobby(&self) -> Arc<Mutex<Lobby>> {
+        self.lobby.clone()
+    }
+
+    /// Accepts connections in a background thread, each client being served by its own thread
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for stream in self.listener.incoming().flatten() {
+                let lobby = self.lobby.clone();
+                thread::spawn(move || { let _ = handle_client(stream, lobby); });
+            }
+        })
+    }
+}
+
+/// Token bucket holding up to `capacity` tokens, refilled by `refill` tokens per second
+struct RateLimiter {
+    capacity: f32,
+    refill: f32,
+    tokens: f32,
+    last: Instant
+}
+
+impl RateLimiter {
+    fn new((capacity, refill): (f32, f32), now: Instant) -> RateLimiter {
+        RateLimiter {
+            capacity: capacity,
+            refill: refill,
+            tokens: capacity,
+            last: now
+        }
+    }
+
+    /// Takes `cost` tokens if there are enough left
+    fn allow(&mut self, now: Instant, cost: f32) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.last = now;
+        if self.tokens < cost { return false }
+        self.tokens -= cost;
+        true
+    }
+}
+
+/// Tokens of the rate limit a request takes, `None` for requests that aren't limited
+fn cost(verb: &str) -> Option<f32> {
+    match verb {
+        "MOVE" | "PORTAL" | "SAY" | "PING" => Some(1.0),
+        // Answered with many keyframes
+        "VIEW" | "SYNC" => Some(2.0),
+        // Sent every frame or tick by well behaved clients
+        "INPUT" | "SUBMIT" => Some(0.05),
+        _ => None
+    }
+}
+
+/// What a single client connection has access to
+struct Connection {
+    identity: Option<Identity>,
+    sessions: Vec<(usize, Token, Attachment)>,
+    spectating: Vec<usize>
+}
+
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            identity: None,
+            sessions: Vec::new(),
+            spectating: Vec::new()
+        }
+    }
+
+    fn token(&self, id: usize) -> Result<&str, LobbyError> {
+        self.sessions.iter().rev().find(|session| session.0 == id)
+            .map(|session| &session.1[..]).ok_or(LobbyError::NotParticipating)
+    }
+
+    fn player(&self, lobby: &Lobby, id: usize) -> Result<Player, LobbyError> {
+        lobby.player(id, self.token(id)?)
+    }
+
+    /// Who is talking through this connection in the given match
+    fn participant(&self, lobby: &Lobby, id: usize) -> Result<Participant, LobbyError> {
+        if let Ok(player) = self.player(lobby, id) {
+            return Ok(Participant::Player(player))
+        }
+        if self.spectating.contains(&id) { return Ok(Participant::Spectator) }
+        Err(LobbyError::NotParticipating)
+    }
+}
+
+fn handle_client(stream: TcpStream, lobby: Arc<Mutex<Lobby>>) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    log!(Level::Info, "networking", "client connected"; peer = peer);
+    let mut connection = Connection::new();
+    let result = serve_client(stream, &lobby, &mut connection);
+    let mut lobby = lobby.lock().unwrap();
+    for (id, token, attachment) in connection.sessions {
+        lobby.disconnect(id, &token, attachment);
+    }
+    if let Some(identity) = connection.identity {
+        lobby.release(&identity);
+    }
+    log!(Level::Info, "networking", "client disconnected"; peer = peer, result = result);
+    result
+}
+
+fn serve_client(stream: TcpStream, lobby: &Arc<Mutex<Lobby>>, connection: &mut Connection) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        let response = {
+            let mut lobby = lobby.lock().unwrap();
+            lobby.expire(Instant::now());
+            lobby.archive();
+            let response = respond(&mut lobby, &line, connection);
+            log!(Level::Trace, "networking", "request"; line = line, ok = response.is_ok());
+            if let Err(ref e) = response {
+                let level = if let LobbyError::RateLimited = *e { Level::Warn } else { Level::Debug };
+                log!(level, "networking", "request failed"; line = line, error = e);
+            }
+            match response {
+                Ok(response) => response,
+                Err(LobbyError::Command(e)) => format!("ERR Command {}\n", e.encode()),
+                Err(LobbyError::Desync(desync)) => format!("ERR Desync {}\n", desync.encode()),
+                Err(e) => format!("ERR {:?}\n", e)
+            }
+        };
+        writer.write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn respond(lobby: &mut Lobby, line: &str, connection: &mut Connection) -> Result<String, LobbyError> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let identity = match (parts.first().cloned(), connection.identity.clone()) {
+        (Some("HELLO"), Some(_)) => return Err(LobbyError::Auth(AuthError::AlreadyAuthenticated)),
+        (Some("HELLO"), None) if parts.len() == 2 || parts.len() == 3 => {
+            connection.identity = Some(lobby.authenticate(parts[1], parts.get(2).cloned())?);
+            return Ok("OK\n".to_string())
+        },
+        (Some("RESUME"), None) if parts.len() == 3 => {
+            let identity = lobby.adopt(parse(parts[1])?, parts[2])?;
+            connection.identity = Some(identity.clone());
+            identity
+        },
+        (_, Some(identity)) => identity,
+        (_, None) => return Err(LobbyError::Unauthenticated)
+    };
+    if let (Some(cost), Some(id)) = (parts.first().and_then(|verb| cost(verb)), parts.get(1).and_then(|id| id.parse().ok())) {
+        // Requests to matches the connection isn't part of fail anyway, they don't need a budget
+        if connection.participant(lobby, id).is_ok() && !lobby.allow(id, &identity.name, cost, Instant::now()) {
+            return Err(LobbyError::RateLimited)
+        }
+    }
+    match (parts.first().cloned(), parts.len()) {
+        (Some("LIST"), 1) => {
+            let mut response = String::new();
+            for info in lobby.list() {
+                response.push_str(&format!("MATCH {} {} {} {} {} {}\n", info.id, info.settings.map,
+                    info.settings.difficulty, info.joined, info.settings.players, info.settings.horizon));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("CREATE"), 5) => {
+            let settings = MatchSettings {
+                map: parts[1].to_string(),
+                difficulty: parse(parts[2])?,
+                players: parse(parts[3])?,
+                horizon: parse(parts[4])?
+            };
+            lobby.create(settings).map(|id| format!("OK {}\n", id))
+        },
+        (Some("JOIN"), 2) => {
+            let id = parse(parts[1])?;
+            let (player, token) = lobby.join(id, &identity.name)?;
+            connection.sessions.push((id, token.clone(), lobby.attachment(id, &token)?));
+            Ok(format!("OK {} {}\n", player, token))
+        },
+        (Some("LEAVE"), 2) => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            lobby.leave(id, player).map(|_| "OK\n".to_string())
+        },
+        (Some("READY"), 2) => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            lobby.ready(id, player).map(|started| if started { "OK STARTED\n".to_string() } else { "OK WAITING\n".to_string() })
+        },
+        (Some("RESUME"), 3) => {
+            let id = parse(parts[1])?;
+            let player = lobby.resume(id, &identity.name, parts[2])?;
+            connection.sessions.push((id, parts[2].to_string(), lobby.attachment(id, parts[2])?));
+            Ok(format!("OK {}\n", player))
+        },
+        (Some("MOVE"), 5) => {
+            let id = parse(parts[1])?;
+            let command = Command::Move { unit: parse(parts[2])?, target: (parse(parts[3])?, parse(parts[4])?) };
+            lobby.issue(id, connection.token(id)?, command).map(|_| "OK\n".to_string())
+        },
+        (Some("PORTAL"), 12) => {
+            let id = parse(parts[1])?;
+            let command = Command::OpenPortal {
+                origin: (parse(parts[2])?, (parse(parts[3])?, parse(parts[4])?)),
+                origin_lifetime: parse(parts[5])?, origin_scale: parse(parts[6])?,
+                dest: (parse(parts[7])?, (parse(parts[8])?, parse(parts[9])?)),
+                dest_lifetime: parse(parts[10])?, dest_scale: parse(parts[11])?
+            };
+            lobby.issue(id, connection.token(id)?, command).map(|_| "OK\n".to_string())
+        },
+        (Some("SYNC"), 2) => {
+            let id = parse(parts[1])?;
+            let (keyframes, events) = lobby.sync(id, connection.token(id)?)?;
+            let mut response = String::new();
+            for (time, keyframe) in keyframes {
+                response.push_str(&write_keyframe(time, &keyframe));
+            }
+            for (i, event) in events {
+                response.push_str(&format!("EVENT {} {}\n", i, event.encode()));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("SPECTATE"), 2) => {
+            let id = parse(parts[1])?;
+            if connection.token(id).is_ok() { return Err(LobbyError::AlreadyParticipating) }
+            lobby.spectate(id, &identity.name)?;
+            connection.spectating.push(id);
+            Ok("OK\n".to_string())
+        },
+        (Some("BRANCHES"), 2) => {
+            let id = parse(parts[1])?;
+            if !connection.spectating.contains(&id) { return Err(LobbyError::NotSpectating) }
+            let mut response = String::new();
+            for (i, branch) in lobby.branches(id)?.iter().enumerate() {
+                response.push_str(&format!("BRANCH {} {} {}\n", i, branch.forked_at, branch.end));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("VIEW"), 3) | (Some("VIEW"), 4) => {
+            let id = parse(parts[1])?;
+            if !connection.spectating.contains(&id) { return Err(LobbyError::NotSpectating) }
+            let time = parse(parts[2])?;
+            let branch = match parts.get(3) {
+                Some(branch) => Some(parse(branch)?),
+                None => None
+            };
+            lobby.view(id, time, branch).map(|keyframe| write_keyframe(time, &keyframe))
+        },
+        (Some("SAY"), n) if n >= 4 => {
+            let id = parse(parts[1])?;
+            let channel = Channel::parse(parts[2]).ok_or(LobbyError::Protocol(parts[2].to_string()))?;
+            let sender = connection.participant(lobby, id)?;
+            // The text is taken verbatim, splitting it into words would collapse its spacing
+            let text = line.splitn(4, ' ').nth(3).unwrap_or("");
+            lobby.say(id, sender, channel, text).map(|_| "OK\n".to_string())
+        },
+        (Some("MESSAGES"), 3) => {
+            let id = parse(parts[1])?;
+            let reader = connection.participant(lobby, id)?;
+            let mut response = String::new();
+            for (i, message) in lobby.messages(id, reader, parse(parts[2])?)? {
+                let sender = match message.sender {
+                    Participant::Player(player) => player.to_string(),
+                    Participant::Spectator => "spectator".to_string()
+                };
+                response.push_str(&format!("MESSAGE {} {} {} {} {}\n", i, message.time, sender, message.channel.name(), message.text));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("PING"), 5) => {
+            let id = parse(parts[1])?;
+            let player = match connection.participant(lobby, id)? {
+                Participant::Player(player) => player,
+                Participant::Spectator => return Err(LobbyError::NotParticipating)
+            };
+            let location = (parse(parts[3])?, parse(parts[4])?);
+            lobby.ping(id, player, location, parse(parts[2])?).map(|_| "OK\n".to_string())
+        },
+        (Some("EVENTS"), 3) => {
+            let id = parse(parts[1])?;
+            let reader = connection.participant(lobby, id)?;
+            let mut response = String::new();
+            for (i, event) in lobby.events(id, reader, parse(parts[2])?)? {
+                response.push_str(&format!("EVENT {} {}\n", i, event.encode()));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("SCENERY"), n) if n >= 2 && (n - 2).is_multiple_of(4) => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            let mut obstacles = Vec::new();
+            for obstacle in parts[2..].chunks(4) {
+                obstacles.push(Obstacle {
+                    position: [parse(obstacle[0])?, parse(obstacle[1])?],
+                    radius: parse(obstacle[2])?,
+                    top: parse(obstacle[3])?
+                });
+            }
+            lobby.scenery(id, player, obstacles).map(|_| "OK\n".to_string())
+        },
+        (Some("INPUT"), n) if n >= 2 && (n - 2).is_multiple_of(9) => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            let mut inputs = Vec::new();
+            for input in parts[2..].chunks(9) {
+                let mut moving = [0.0; 6];
+                for (value, part) in moving.iter_mut().zip(input[1..7].iter()) {
+                    *value = parse(part)?;
+                }
+                let input = Input { sequence: parse(input[0])?, moving: moving, yaw: parse(input[7])?, dt: parse(input[8])? };
+                if !input.is_finite() { return Err(LobbyError::Protocol(line.to_string())) }
+                inputs.push(input);
+            }
+            match lobby.walk(id, player, &inputs, Instant::now())? {
+                Some((sequence, state)) => Ok(format!("OK {} {} {} {} {}\n", sequence,
+                    state.position[0], state.position[1], state.position[2], state.y_speed)),
+                None => Ok("OK NONE\n".to_string())
+            }
+        },
+        (Some("SUBMIT"), n) if n >= 3 => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            let commands = read_commands(&parts[3..])?;
+            lobby.submit(id, player, parse(parts[2])?, commands)
+                .map(|advanced| if advanced { "OK ADVANCED\n".to_string() } else { "OK WAITING\n".to_string() })
+        },
+        (Some("ADVANCE"), 3) => {
+            let id = parse(parts[1])?;
+            connection.participant(lobby, id)?;
+            let mut response = String::new();
+            for step in lobby.steps(id, parse(parts[2])?)? {
+                response.push_str(&format!("STEP {}", step.tick));
+                for &(player, ref command) in step.commands.iter() {
+                    response.push_str(&format!(" {} {}", player, write_command(command)));
+                }
+                response.push('\n');
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        (Some("CHECKSUM"), n) if n >= 4 && n % 2 == 0 => {
+            let id = parse(parts[1])?;
+            let player = connection.player(lobby, id)?;
+            let mut units = Vec::new();
+            for unit in parts[4..].chunks(2) {
+                units.push((parse(unit[0])?, parse(unit[1])?));
+            }
+            let checksum = Checksum { keyframe: parse(parts[3])?, units: units };
+            lobby.verify(id, player, &[(parse(parts[2])?, checksum)]).map(|_| "OK\n".to_string())
+        },
+        (Some("PINGS"), 2) => {
+            let id = parse(parts[1])?;
+            let reader = connection.participant(lobby, id)?;
+            let mut response = String::new();
+            for ping in lobby.pings(id, reader)? {
+                response.push_str(&format!("PING {} {} {} {}\n", ping.player, ping.time, ping.location.0, ping.location.1));
+            }
+            response.push_str("END\n");
+            Ok(response)
+        },
+        _ => Err(LobbyError::Protocol(line.to_string()))
+    }
+}
+
+fn read_messages(client: &mut LobbyClient, request: &str) -> Result<Vec<(usize, Message)>, LobbyError> {
+    client.send(request)?;
+    let mut messages = Vec::new();
+    loop {
+        let line = client.receive()?;
+        let parts: Vec<&str> = line.splitn(6, ' ').collect();
+        if parts == ["END"] { return Ok(messages) }
+        if parts.len() != 6 || parts[0] != "MESSAGE" { return Err(LobbyError::Protocol(line.clone())) }
+        let sender = match parts[3] {
+            "spectator" => Participant::Spectator,
+            player => Participant::Player(parse(player)?)
+        };
+        messages.push((parse(parts[1])?, Message {
+            sender: sender,
+            channel: Channel::parse(parts[4]).ok_or(LobbyError::Protocol(line.clone()))?,
+            time: parse(parts[2])?,
+            text: parts[5].to_string()
+        }));
+    }
+}
+
+fn read_pings(client: &mut LobbyClient, request: &str) -> Result<Vec<Ping>, LobbyError> {
+    client.send(request)?;
+    let mut pings = Vec::new();
+    loop {
+        let line = client.receive()?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts == ["END"] { return Ok(pings) }
+        if parts.len() != 5 || parts[0] != "PING" { return Err(LobbyError::Protocol(line.clone())) }
+        pings.push(Ping { player: parse(parts[1])?, time: parse(parts[2])?, location: (parse(parts[3])?, parse(parts[4])?) });
+    }
+}
+
+fn write_keyframe(time: TimeIndex, keyframe: &Keyframe) -> String {
+    let mut line = format!("KEYFRAME {}", time);
+    for unit in keyframe {
+        line.push_str(&format!(" {} {} {} {}", unit.0, (unit.1).0, (unit.1).1, unit.2));
+    }
+    line.push('\n');
+    line
+}
+
+fn read_keyframe(line: &str) -> Result<(TimeIndex, Keyframe), LobbyError> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 || parts[0] != "KEYFRAME" || !(parts.len() - 2).is_multiple_of(4) {
+        return Err(LobbyError::Protocol(line.to_string()))
+    }
+    let mut keyframe = Vec::new();
+    for unit in parts[2..].chunks(4) {
+        keyframe.push((parse(unit[0])?, (parse(unit[1])?, parse(unit[2])?), parse(unit[3])?));
+    }
+    Ok((parse(parts[1])?, keyframe))
+}
+
+fn write_command(command: &Command) -> String {
+    match *command {
+        Command::Move { unit, target } => format!("MOVE {} {} {}", unit, target.0, target.1),
+        Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale } =>
+            format!("PORTAL {} {} {} {} {} {} {} {} {} {}",
+                    origin.0, (origin.1).0, (origin.1).1, origin_lifetime, origin_scale,
+                    dest.0, (dest.1).0, (dest.1).1, dest_lifetime, dest_scale)
+    }
+}
+
+/// Reads commands written by `write_command` until the parts run out
+fn read_commands(mut parts: &[&str]) -> Result<Vec<Command>, LobbyError> {
+    let mut commands = Vec::new();
+    while let Some(&verb) = parts.first() {
+        let command = match (verb, parts.len()) {
+            ("MOVE", n) if n >= 4 => Command::Move { unit: parse(parts[1])?, target: (parse(parts[2])?, parse(parts[3])?) },
+            ("PORTAL", n) if n >= 11 => Command::OpenPortal {
+                origin: (parse(parts[1])?, (parse(parts[2])?, parse(parts[3])?)),
+                origin_lifetime: parse(parts[4])?, origin_scale: parse(parts[5])?,
+                dest: (parse(parts[6])?, (parse(parts[7])?, parse(parts[8])?)),
+                dest_lifetime: parse(parts[9])?, dest_scale: parse(parts[10])?
+            },
+            _ => return Err(LobbyError::Protocol(parts.join(" ")))
+        };
+        parts = &parts[if verb == "MOVE" { 4 } else { 11 }..];
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+fn read_event(line: &str) -> Result<(usize, Event), LobbyError> {
+    let event = line.strip_prefix("EVENT ").and_then(|event| {
+        let (index, event) = event.split_at(event.find(' ')?);
+        Some((index.parse().ok()?, Event::parse(event)?))
+    });
+    event.ok_or(LobbyError::Protocol(line.to_string()))
+}
+
+fn parse<T: ::std::str::FromStr>(value: &str) -> Result<T, LobbyError> {
+    value.parse().map_err(|_| LobbyError::Protocol(value.to_string()))
+}
+
+// ------------------------------------------ CLIENT -----------------------------------------
+
+pub struct LobbyClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl LobbyClient {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<LobbyClient> {
+        let stream = TcpStream::connect(address)?;
+        Ok(LobbyClient {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream)
+        })
+    }
+
+    pub fn list(&mut self) -> Result<Vec<MatchInfo>, LobbyError> {
+        self.send("LIST")?;
+        let mut matches = Vec::new();
+        loop {
+            let line = self.receive()?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts == ["END"] { return Ok(matches) }
+            if parts.len() != 7 || parts[0] != "MATCH" { return Err(LobbyError::Protocol(line.clone())) }
+            matches.push(MatchInfo {
+                id: parse(parts[1])?,
+                settings: MatchSettings {
+                    map: parts[2].to_string(),
+                    difficulty: parse(parts[3])?,
+                    players: parse(parts[5])?,
+                    horizon: parse(parts[6])?
+                },
+                joined: parse(parts[4])?
+            });
+        }
+    }
+
+    pub fn create(&mut self, settings: &MatchSettings) -> Result<usize, LobbyError> {
+        let request = format!("CREATE {} {} {} {}", settings.map, settings.difficulty, settings.players, settings.horizon);
+        self.request(&request).and_then(|response| parse(&response))
+    }
+
+    /// Introduces the connection to the server, required before anything else
+    pub fn authenticate(&mut self, name: &str, password: Option<&str>) -> Result<(), LobbyError> {
+        if name.is_empty() || name.contains(char::is_whitespace) { return Err(LobbyError::Auth(AuthError::InvalidName)) }
+        let request = match password {
+            Some(password) => format!("HELLO {} {}", name, password),
+            None => format!("HELLO {}", name)
+        };
+        self.request(&request).map(|_| ())
+    }
+
+    pub fn join(&mut self, id: usize) -> Result<(Player, Token), LobbyError> {
+        let response = self.request(&format!("JOIN {}", id))?;
+        let parts: Vec<&str> = response.split_whitespace().collect();
+        if parts.len() != 2 { return Err(LobbyError::Protocol(response.clone())) }
+        Ok((parse(parts[0])?, parts[1].to_string()))
+    }
+
+    pub fn resume(&mut self, id: usize, token: &str) -> Result<Player, LobbyError> {
+        self.request(&format!("RESUME {} {}", id, token)).and_then(|response| parse(&response))
+    }
+
+    /// Fetches all keyframes calculated and events emitted since the last sync of the session
+    /// this connection joined or resumed
+    pub fn sync(&mut self, id: usize) -> Result<Missed, LobbyError> {
+        self.send(&format!("SYNC {}", id))?;
+        let (mut keyframes, mut events) = (Vec::new(), Vec::new());
+        loop {
+            let line = self.receive()?;
+            if line == "END" { return Ok((keyframes, events)) }
+            if line.starts_with("EVENT ") {
+                events.push(read_event(&line)?);
+            } else {
+                keyframes.push(read_keyframe(&line)?);
+            }
+        }
+    }
+
+    /// Sends a chat message on behalf of the session this connection joined or resumed
+    pub fn say(&mut self, id: usize, channel: Channel, text: &str) -> Result<(), LobbyError> {
+        self.request(&format!("SAY {} {} {}", id, channel.name(), text.replace('\n', " "))).map(|_| ())
+    }
+
+    /// Returns the messages readable by this connection, starting at the given message index
+    pub fn messages(&mut self, id: usize, since: usize) -> Result<Vec<(usize, Message)>, LobbyError> {
+        read_messages(self, &format!("MESSAGES {} {}", id, since))
+    }
+
+    /// Marks a location at a specific time for the whole team
+    pub fn ping(&mut self, id: usize, time: TimeIndex, location: Coordinates) -> Result<(), LobbyError> {
+        self.request(&format!("PING {} {} {} {}", id, time, location.0, location.1)).map(|_| ())
+    }
+
+    pub fn pings(&mut self, id: usize) -> Result<Vec<Ping>, LobbyError> {
+        read_pings(self, &format!("PINGS {}", id))
+    }
+
+    /// Events of the match starting at index `since`, along with their index
+    pub fn events(&mut self, id: usize, since: usize) -> Result<Vec<(usize, Event)>, LobbyError> {
+        self.send(&format!("EVENTS {} {}", id, since))?;
+        let mut events = Vec::new();
+        loop {
+            let line = self.receive()?;
+            if line == "END" { return Ok(events) }
+            events.push(read_event(&line)?);
+        }
+    }
+
+    /// Starts watching a running match. The spectator sees everything but can't give any orders.
+    pub fn spectate(mut self, id: usize) -> Result<Spectator, LobbyError> {
+        self.request(&format!("SPECTATE {}", id))?;
+        Ok(Spectator { client: self, id: id })
+    }
+
+    pub fn leave(&mut self, id: usize) -> Result<(), LobbyError> {
+        self.request(&format!("LEAVE {}", id)).map(|_| ())
+    }
+
+    pub fn ready(&mut self, id: usize) -> Result<bool, LobbyError> {
+        self.request(&format!("READY {}", id)).map(|response| response == "STARTED")
+    }
+
+    /// Gives an order to the units of the slot this connection holds in a running match
+    pub fn issue(&mut self, id: usize, command: &Command) -> Result<(), LobbyError> {
+        let request = match *command {
+            Command::Move { unit, target } => format!("MOVE {} {} {} {}", id, unit, target.0, target.1),
+            Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale } =>
+                format!("PORTAL {} {} {} {} {} {} {} {} {} {} {}", id,
+                        origin.0, (origin.1).0, (origin.1).1, origin_lifetime, origin_scale,
+                        dest.0, (dest.1).0, (dest.1).1, dest_lifetime, dest_scale)
+        };
+        self.request(&request).map(|_| ())
+    }
+
+    /// Reports the obstacles of the loaded map, which the server's avatar collides with
+    pub fn scenery(&mut self, id: usize, obstacles: &[Obstacle]) -> Result<(), LobbyError> {
+        let mut request = format!("SCENERY {}", id);
+        for obstacle in obstacles {
+            request.push_str(&format!(" {} {} {} {}", obstacle.position[0], obstacle.position[1], obstacle.radius, obstacle.top));
+        }
+        self.request(&request).map(|_| ())
+    }
+
+    /// Hands frames of first person movement to the server and returns the authoritative state of
+    /// the avatar, along with the sequence of the last input it includes
+    pub fn walk(&mut self, id: usize, inputs: &[Input]) -> Result<Option<(u32, AvatarState)>, LobbyError> {
+        let mut request = format!("INPUT {}", id);
+        for input in inputs {
+            request.push_str(&format!(" {}", input.sequence));
+            for value in input.moving.iter() {
+                request.push_str(&format!(" {}", value));
+            }
+            request.push_str(&format!(" {} {}", input.yaw, input.dt));
+        }
+        let response = self.request(&request)?;
+        if response == "NONE" { return Ok(None) }
+        let parts: Vec<&str> = response.split_whitespace().collect();
+        if parts.len() != 5 { return Err(LobbyError::Protocol(response.clone())) }
+        let state = AvatarState { position: [parse(parts[1])?, parse(parts[2])?, parse(parts[3])?], y_speed: parse(parts[4])? };
+        Ok(Some((parse(parts[0])?, state)))
+    }
+
+    /// Hands in the commands for a tick of the match this connection plays in. Returns whether
+    /// the match advanced to the next tick.
+    pub fn submit(&mut self, id: usize, tick: TimeIndex, commands: &[Command]) -> Result<bool, LobbyError> {
+        let mut request = format!("SUBMIT {} {}", id, tick);
+        for command in commands {
+            request.push(' ');
+            request.push_str(&write_command(command));
+        }
+        self.request(&request).map(|response| response == "ADVANCED")
+    }
+
+    /// Fetches the steps of every tick the match advanced since `since`. Rejected commands aren't
+    /// transmitted, they don't affect the simulation.
+    pub fn advance(&mut self, id: usize, since: TimeIndex) -> Result<Vec<Step>, LobbyError> {
+        self.send(&format!("ADVANCE {} {}", id, since))?;
+        let mut steps = Vec::new();
+        loop {
+            let line = self.receive()?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts == ["END"] { return Ok(steps) }
+            if parts.len() < 2 || parts[0] != "STEP" { return Err(LobbyError::Protocol(line.clone())) }
+            let mut step = Step { tick: parse(parts[1])?, commands: Vec::new(), rejected: Vec::new() };
+            let mut rest = &parts[2..];
+            while !rest.is_empty() {
+                let length = match rest.get(1) {
+                    Some(&"MOVE") => 5,
+                    Some(&"PORTAL") => 12,
+                    _ => return Err(LobbyError::Protocol(line.clone()))
+                };
+                if rest.len() < length { return Err(LobbyError::Protocol(line.clone())) }
+                let command = read_commands(&rest[1..length])?.remove(0);
+                step.commands.push((parse(rest[0])?, command));
+                rest = &rest[length..];
+            }
+            steps.push(step);
+        }
+    }
+
+    /// Reports the checksum of a locally simulated keyframe, failing with `LobbyError::Desync`
+    /// if it differs from the server
+    pub fn checksum(&mut self, id: usize, tick: TimeIndex, checksum: &Checksum) -> Result<(), LobbyError> {
+        let mut request = format!("CHECKSUM {} {} {}", id, tick, checksum.keyframe);
+        for &(unit, hash) in checksum.units.iter() {
+            request.push_str(&format!(" {} {}", unit, hash));
+        }
+        self.request(&request).map(|_| ())
+    }
+
+    /// Sends a single line request and returns the payload of the `OK` response
+    fn request(&mut self, request: &str) -> Result<String, LobbyError> {
+        self.send(request)?;
+        let line = self.receive()?;
+        if line == "OK" {
+            Ok(String::new())
+        } else if let Some(payload) = line.strip_prefix("OK ") {
+            Ok(payload.to_string())
+        } else {
+            Err(LobbyError::Protocol(line))
+        }
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")
+    }
+
+    /// Reads a line of the response, failures answered by the server turn into errors
+    fn receive(&mut self) -> Result<String, LobbyError> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end().to_string();
+        if line == "ERR RateLimited" {
+            Err(LobbyError::RateLimited)
+        } else if let Some(error) = line.strip_prefix("ERR Command ").and_then(CommandError::parse) {
+            Err(LobbyError::Command(error))
+        } else if let Some(desync) = line.strip_prefix("ERR Desync ").and_then(Desync::parse) {
+            Err(LobbyError::Desync(desync))
+        } else if line.starts_with("ERR ") {
+            Err(LobbyError::Protocol(line))
+        } else {
+            Ok(line)
+        }
+    }
+}
+
+// ---------------------------------------- SPECTATOR ----------------------------------------
+
+pub struct Spectator {
+    client: LobbyClient,
+    id: usize
+}
+
+impl Spectator {
+    /// Returns the state of the current timeline at any time up to the present
+    pub fn view(&mut self, time: TimeIndex) -> Result<Keyframe, LobbyError> {
+        let request = format!("VIEW {} {}", self.id, time);
+        self.fetch(&request)
+    }
+
+    /// Returns the state of an abandoned timeline branch
+    pub fn view_branch(&mut self, branch: usize, time: TimeIndex) -> Result<Keyframe, LobbyError> {
+        let request = format!("VIEW {} {} {}", self.id, time, branch);
+        self.fetch(&request)
+    }
+
+    pub fn branches(&mut self) -> Result<Vec<BranchInfo>, LobbyError> {
+        self.client.send(&format!("BRANCHES {}", self.id))?;
+        let mut branches = Vec::new();
+        loop {
+            let line = self.client.receive()?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts == ["END"] { return Ok(branches) }
+            if parts.len() != 4 || parts[0] != "BRANCH" { return Err(LobbyError::Protocol(line.clone())) }
+            branches.push(BranchInfo { forked_at: parse(parts[2])?, end: parse(parts[3])? });
+        }
+    }
+
+    /// Talks to the other spectators, players won't be able to read it
+    pub fn say(&mut self, text: &str) -> Result<(), LobbyError> {
+        let id = self.id;
+        self.client.say(id, Channel::Spectators, text)
+    }
+
+    pub fn messages(&mut self, since: usize) -> Result<Vec<(usize, Message)>, LobbyError> {
+        let id = self.id;
+        self.client.messages(id, since)
+    }
+
+    pub fn pings(&mut self) -> Result<Vec<Ping>, LobbyError> {
+        let id = self.id;
+        self.client.pings(id)
+    }
+
+    pub fn events(&mut self, since: usize) -> Result<Vec<(usize, Event)>, LobbyError> {
+        let id = self.id;
+        self.client.events(id, since)
+    }
+
+    fn fetch(&mut self, request: &str) -> Result<Keyframe, LobbyError> {
+        self.client.send(request)?;
+        let line = self.client.receive()?;
+        read_keyframe(&line).map(|keyframe| keyframe.1)
+    }
+}
+
+#[test]
+fn lobby_starts_when_ready() {
+    let mut lobby = Lobby::new();
+    let id = lobby.create(MatchSettings { map: "forest".to_string(), difficulty: 1, players: 2, horizon: 100 }).unwrap();
+    let (a, _) = lobby.join(id, "alice").unwrap();
+    let (b, _) = lobby.join(id, "bob").unwrap();
+    assert!(lobby.join(id, "carol").is_err());
+    assert!(!lobby.ready(id, a).unwrap());
+    lobby.leave(id, b).unwrap();
+    assert_eq!(lobby.join(id, "carol").unwrap().0, b);
+    assert!(lobby.ready(id, b).unwrap());
+    assert!(lobby.list().is_empty());
+    assert!(lobby.server(id).is_some());
+}
+
+#[test]
+fn rate_limiter() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new((3.0, 2.0), start);
+    assert!((0..3).all(|_| limiter.allow(start, 1.0)));
+    assert!(!limiter.allow(start, 1.0));
+    assert!(limiter.allow(start + Duration::from_millis(500), 1.0));
+    assert!(!limiter.allow(start + Duration::from_millis(600), 1.0));
+    assert_eq!((0..10).filter(|_| limiter.allow(start + Duration::from_secs(60), 1.0)).count(), 3);
+    assert!(!limiter.allow(start + Duration::from_secs(120), 4.0));
+    assert_eq!((0..100).filter(|_| limiter.allow(start + Duration::from_secs(120), 0.05)).count(), 60);
+}
+
+#[test]
+fn players_cannot_spectate() {
+    let mut lobby = Lobby::new();
+    let mut alice = Connection::new();
+    let mut carol = Connection::new();
+    respond(&mut lobby, "HELLO alice", &mut alice).unwrap();
+    respond(&mut lobby, "HELLO carol", &mut carol).unwrap();
+    respond(&mut lobby, "CREATE forest 1 1 100", &mut alice).unwrap();
+    respond(&mut lobby, "JOIN 0", &mut alice).unwrap();
+    respond(&mut lobby, "READY 0", &mut alice).unwrap();
+
+    match respond(&mut lobby, "SPECTATE 0", &mut alice) { Err(LobbyError::AlreadyParticipating) => {}, e => panic!("{:?}", e) }
+    match respond(&mut lobby, "VIEW 0 0", &mut alice) { Err(LobbyError::NotSpectating) => {}, e => panic!("{:?}", e) }
+
+    respond(&mut lobby, "SPECTATE 0", &mut carol).unwrap();
+    assert!(respond(&mut lobby, "VIEW 0 0", &mut carol).unwrap().starts_with("KEYFRAME 0"));
+}
+
+#[test]
+fn names_are_exclusive() {
+    let mut lobby = Lobby::new();
+    let mut alice = Connection::new();
+    let mut mallory = Connection::new();
+    respond(&mut lobby, "HELLO alice", &mut alice).unwrap();
+    match respond(&mut lobby, "HELLO bob", &mut alice) { Err(LobbyError::Auth(AuthError::AlreadyAuthenticated)) => {}, e => panic!("{:?}", e) }
+    match respond(&mut lobby, "HELLO alice", &mut mallory) { Err(LobbyError::Auth(AuthError::NameTaken)) => {}, e => panic!("{:?}", e) }
+
+    // The name is free again once the connection closed
+    lobby.release(alice.identity.as_ref().unwrap());
+    respond(&mut lobby, "HELLO alice", &mut mallory).unwrap();
+}
+
+#[test]
+fn archive_ended_matches() {
+    use AIType;
+    let directory = ::std::env::temp_dir().join(format!("timewars-replays-{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&directory).unwrap();
+    let mut lobby = Lobby::new().replays(&directory);
+    let id = lobby.create(MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 1, horizon: 50 }).unwrap();
+    let (a, _) = lobby.join(id, "alice").unwrap();
+    lobby.ready(id, a).unwrap();
+    lobby.archive();
+    let path = directory.join(format!("match-{}.replay", id));
+    assert!(!path.exists());
+
+    let server = lobby.server(id).unwrap();
+    server.spawn(a, AIType::Knight, (0.0, 0.0), 0.0);
+    for _ in 0..3 { server.tick(); }
+    lobby.archive();
+    assert_eq!(Replay::load(&path).unwrap().end, 3);
+    ::std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn forget_old_events() {
+    use AIType;
+    let mut lobby = Lobby::new();
+    let id = lobby.create(MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 1, horizon: 50 }).unwrap();
+    let (a, _) = lobby.join(id, "alice").unwrap();
+    lobby.ready(id, a).unwrap();
+    for i in 0..KEPT_EVENTS + 10 {
+        lobby.server(id).unwrap().spawn(a, AIType::Knight, (i as f32, 0.0), 0.0);
+    }
+
+    // Indices stay those within the whole match
+    let events = lobby.events(id, Participant::Player(a), 0).unwrap();
+    assert_eq!(events.len(), KEPT_EVENTS);
+    assert_eq!(events[0].0, 11);
+    assert_eq!(lobby.events(id, Participant::Player(a), KEPT_EVENTS + 10).unwrap()[0].1,
+        Event::UnitSpawned { unit: KEPT_EVENTS + 9, player: a, ai_type: AIType::Knight, location: ((KEPT_EVENTS + 9) as f32, 0.0) });
+}
+
+#[test]
+fn charge_participants_of_running_matches() {
+    use AIType;
+    let mut lobby = Lobby::new().rate_limit(1.0, 0.0);
+    let mut alice = Connection::new();
+    let mut mallory = Connection::new();
+    respond(&mut lobby, "HELLO alice", &mut alice).unwrap();
+    respond(&mut lobby, "HELLO mallory", &mut mallory).unwrap();
+    respond(&mut lobby, "CREATE sandbox 1 1 50", &mut alice).unwrap();
+    respond(&mut lobby, "JOIN 0", &mut alice).unwrap();
+    respond(&mut lobby, "READY 0", &mut alice).unwrap();
+
+    // Strangers are turned away without a budget, whether the match exists or not
+    for _ in 0..3 {
+        match respond(&mut lobby, "PING 0 0 1 1", &mut mallory) { Err(LobbyError::NotParticipating) => {}, e => panic!("{:?}", e) }
+        match respond(&mut lobby, "PING 7 0 1 1", &mut mallory) { Err(LobbyError::NotParticipating) => {}, e => panic!("{:?}", e) }
+    }
+    assert!(lobby.matches[&0].limiters.is_empty());
+
+    respond(&mut lobby, "PING 0 0 1 1", &mut alice).unwrap();
+    match respond(&mut lobby, "PING 0 0 1 1", &mut alice) { Err(LobbyError::RateLimited) => {}, e => panic!("{:?}", e) }
+    assert_eq!(lobby.matches[&0].limiters.len(), 1);
+
+    // Alice is the only one left, so the match ends right away
+    let server = lobby.server(0).unwrap();
+    server.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+    for _ in 0..3 { server.tick(); }
+    respond(&mut lobby, "PING 0 0 1 1", &mut alice).unwrap();
+    assert!(lobby.matches[&0].limiters.is_empty());
+}
@@ -0,0 +1,196 @@
+//! Lockstep synchronisation. The present only advances once every player handed in its commands
+//! for the current tick, clients simulating on their own prove they are in sync via checksums.
+use std::collections::BTreeMap;
+
+use {Server, Command, CommandError, Keyframe, Player, TimeIndex, ID};
+
+/// Fingerprint of a keyframe, with one hash per unit to locate divergences. Units are hashed in
+/// the order of their IDs, so keyframes listing the same units in another order match.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksum {
+    pub keyframe: u64,
+    pub units: Vec<(ID, u64)>
+}
+
+impl Checksum {
+    pub fn new(keyframe: &Keyframe) -> Checksum {
+        let mut units: Vec<(ID, u64)> = keyframe.iter().map(|unit| {
+            let mut hash = Fnv::new();
+            hash.write(unit.0 as u64);
+            hash.write((unit.1).0.to_bits() as u64);
+            hash.write((unit.1).1.to_bits() as u64);
+            hash.write(unit.2.to_bits() as u64);
+            (unit.0, hash.finish())
+        }).collect();
+        units.sort_by_key(|unit| unit.0);
+        let mut hash = Fnv::new();
+        for unit in units.iter() {
+            hash.write(unit.1);
+        }
+        Checksum {
+            keyframe: hash.finish(),
+            units: units
+        }
+    }
+}
+
+/// Stable across platforms and compiler versions, unlike the hashers of the standard library
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, value: u64) {
+        for i in 0..8 {
+            self.0 ^= (value >> (i * 8)) & 0xff;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Everything that happened during a tick, in the order it has been applied
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub tick: TimeIndex,
+    pub commands: Vec<(Player, Command)>,
+    pub rejected: Vec<(Player, CommandError)>
+}
+
+impl Step {
+    /// Applies the step to a locally simulated copy of the match
+    pub fn replay(&self, replica: &mut Server) {
+        for &(player, ref command) in self.commands.iter() {
+            let _ = replica.issue(player, command.clone());
+        }
+        replica.tick();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LockstepError {
+    UnknownPlayer(Player),
+    WrongTick { expected: TimeIndex, received: TimeIndex },
+    AlreadySubmitted(Player)
+}
+
+/// First point at which a client's simulation differs from the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Desync {
+    pub player: Player,
+    pub tick: TimeIndex,
+    /// `None` if the difference can't be attributed to a single unit
+    pub unit: Option<ID>
+}
+
+impl Desync {
+    /// Compact textual form used on the wire, readable by `Desync::parse`
+    pub fn encode(&self) -> String {
+        match self.unit {
+            Some(unit) => format!("{} {} {}", self.player, self.tick, unit),
+            None => format!("{} {} -", self.player, self.tick)
+        }
+    }
+
+    pub fn parse(encoded: &str) -> Option<Desync> {
+        let parts: Vec<&str> = encoded.split_whitespace().collect();
+        if parts.len() != 3 { return None }
+        Some(Desync {
+            player: parts[0].parse().ok()?,
+            tick: parts[1].parse().ok()?,
+            unit: match parts[2] {
+                "-" => None,
+                unit => Some(unit.parse().ok()?)
+            }
+        })
+    }
+}
+
+pub struct Lockstep {
+    players: usize,
+    submitted: BTreeMap<Player, Vec<Command>>
+}
+
+impl Lockstep {
+    pub fn new(players: usize) -> Lockstep {
+        Lockstep {
+            players: players,
+            submitted: BTreeMap::new()
+        }
+    }
+
+    /// Players that still have to hand in their commands for the current tick
+    pub fn waiting_for(&self) -> Vec<Player> {
+        (0..self.players).filter(|player| !self.submitted.contains_key(player)).collect()
+    }
+
+    /// Hands in the commands of a player for the present tick of `server`. Once the last player
+    /// submitted, all commands are issued in player order and the present advances.
+    pub fn submit(&mut self, server: &mut Server, player: Player, tick: TimeIndex, commands: Vec<Command>)
+                  -> Result<Option<Step>, LockstepError> {
+        if player >= self.players { return Err(LockstepError::UnknownPlayer(player)) }
+        if tick != server.present() { return Err(LockstepError::WrongTick { expected: server.present(), received: tick }) }
+        if self.submitted.contains_key(&player) { return Err(LockstepError::AlreadySubmitted(player)) }
+        self.submitted.insert(player, commands);
+        if self.submitted.len() < self.players { return Ok(None) }
+
+        let mut step = Step { tick: tick, commands: Vec::new(), rejected: Vec::new() };
+        let submitted = ::std::mem::take(&mut self.submitted);
+        for (player, commands) in submitted {
+            for command in commands {
+                match server.issue(player, command.clone()) {
+                    Ok(()) => step.commands.push((player, command)),
+                    Err(e) => step.rejected.push((player, e))
+                }
+            }
+        }
+        server.tick();
+        Ok(Some(step))
+    }
+
+    /// Compares the checksums calculated by a client against the authoritative keyframes
+    pub fn verify(&self, server: &mut Server, player: Player, checksums: &[(TimeIndex, Checksum)]) -> Result<(), Desync> {
+        let mut sorted: Vec<&(TimeIndex, Checksum)> = checksums.iter().collect();
+        sorted.sort_by_key(|checksum| checksum.0);
+        for &&(tick, ref remote) in sorted.iter() {
+            let local = match server.spectate(tick) {
+                Some(keyframe) => Checksum::new(&keyframe),
+                None => continue
+            };
+            if local.keyframe == remote.keyframe { continue }
+
+            let mut units: Vec<ID> = local.units.iter().chain(remote.units.iter()).map(|unit| unit.0).collect();
+            units.sort();
+            let unit = units.into_iter().find(|id| {
+                let a = local.units.iter().find(|unit| unit.0 == *id);
+                let b = remote.units.iter().find(|unit| unit.0 == *id);
+                a != b
+            });
+            return Err(Desync { player: player, tick: tick, unit: unit })
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn checksum_detects_changes() {
+    let a = vec![(0, (1.0, 2.0), 0.5), (1, (3.0, 4.0), 0.0)];
+    let mut b = a.clone();
+    assert_eq!(Checksum::new(&a), Checksum::new(&b));
+    b[1].2 = 0.25;
+    assert!(Checksum::new(&a).keyframe != Checksum::new(&b).keyframe);
+    assert_eq!(Checksum::new(&a).units[0], Checksum::new(&b).units[0]);
+
+    // The order units are listed in doesn't matter
+    let reversed: Keyframe = a.iter().rev().cloned().collect();
+    assert_eq!(Checksum::new(&a), Checksum::new(&reversed));
+
+    for desync in [Desync { player: 1, tick: 6, unit: Some(3) }, Desync { player: 0, tick: 2, unit: None }].iter() {
+        assert_eq!(Desync::parse(&desync.encode()).as_ref(), Some(desync));
+    }
+}
@@ -0,0 +1,158 @@
+//! Leveled, structured logging shared by the server and the client. Every entry belongs to a
+//! target naming the subsystem it comes from, e.g. `simulation`, `portals`, `networking` or
+//! `rendering`, and carries a message along with key value pairs:
+//!
+//! ```text
+//! log!(Level::Debug, "portals", "unit traversed"; unit = 3, departure = 14);
+//! ```
+//!
+//! Levels are configured at runtime with a spec like `warn,simulation=debug,networking=trace`,
+//! either through `configure` or the `TIMEWARS_LOG` environment variable read by `from_env`.
+//! Entries go to stderr unless a different sink has been installed.
+use std::env;
+use std::sync::{Arc, RwLock};
+
+/// Environment variable holding the spec read by `from_env`
+pub const ENV: &str = "TIMEWARS_LOG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match &name.to_lowercase()[..] {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub fields: Vec<(&'static str, String)>
+}
+
+impl Entry {
+    /// Single line form written by the default sink
+    pub fn format(&self) -> String {
+        let mut line = format!("{:5} {}: {}", self.level.name(), self.target, self.message);
+        for &(key, ref value) in self.fields.iter() {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+}
+
+pub type Sink = Arc<dyn Fn(&Entry) + Send + Sync>;
+
+struct Config {
+    default: Level,
+    targets: Vec<(String, Level)>,
+    sink: Option<Sink>
+}
+
+static CONFIG: RwLock<Config> = RwLock::new(Config {
+    default: Level::Warn,
+    targets: Vec::new(),
+    sink: None
+});
+
+/// Applies a spec of comma separated `level` or `target=level` directives. Targets that aren't
+/// mentioned use the bare level, or `warn` if there is none.
+pub fn configure(spec: &str) -> Result<(), String> {
+    let mut default = Level::Warn;
+    let mut targets = Vec::new();
+    for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        match directive.find('=') {
+            Some(i) => {
+                let level = Level::parse(&directive[i + 1..]).ok_or_else(|| directive.to_string())?;
+                targets.push((directive[..i].to_string(), level));
+            },
+            None => default = Level::parse(directive).ok_or_else(|| directive.to_string())?
+        }
+    }
+    let mut config = CONFIG.write().unwrap();
+    config.default = default;
+    config.targets = targets;
+    Ok(())
+}
+
+/// Configures logging from the `TIMEWARS_LOG` environment variable, if it is set
+pub fn from_env() -> Result<(), String> {
+    match env::var(ENV) {
+        Ok(spec) => configure(&spec),
+        Err(_) => Ok(())
+    }
+}
+
+/// Changes the level of a single target, leaving the rest of the configuration alone
+pub fn set_level(target: &str, level: Level) {
+    let mut config = CONFIG.write().unwrap();
+    config.targets.retain(|directive| directive.0 != target);
+    config.targets.push((target.to_string(), level));
+}
+
+/// Sends entries somewhere else than stderr, `None` restores the default
+pub fn set_sink(sink: Option<Sink>) {
+    CONFIG.write().unwrap().sink = sink;
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    let config = CONFIG.read().unwrap();
+    let max = config.targets.iter().rev().find(|directive| directive.0 == target)
+        .map(|directive| directive.1).unwrap_or(config.default);
+    level != Level::Off && level <= max
+}
+
+/// Writes an entry without checking the level, use the `log!` macro instead. The sink is called
+/// without holding on to the configuration, so it may change it.
+pub fn write(entry: Entry) {
+    let sink = CONFIG.read().unwrap().sink.clone();
+    match sink {
+        Some(sink) => sink(&entry),
+        None => eprintln!("{}", entry.format())
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $target:expr, $message:expr) => {
+        log!($level, $target, $message;)
+    };
+    ($level:expr, $target:expr, $message:expr; $($key:ident = $value:expr),*) => {
+        if $crate::logging::enabled($level, $target) {
+            $crate::logging::write($crate::logging::Entry {
+                level: $level,
+                target: $target.to_string(),
+                message: $message.to_string(),
+                fields: vec![$((stringify!($key), format!("{:?}", $value))),*]
+            });
+        }
+    };
+}
@@ -0,0 +1,335 @@
+//! Movement of the first person avatar. The same integration, including collisions with the
+//! scenery, runs on the client to predict its own movement and on the server which has the
+//! final say about the position.
+use std::collections::VecDeque;
+
+pub const PLAYER_HEIGHT: f32 = 0.7;
+/// Fastest walking speed a client may request
+const MAX_WALK: f32 = 1.3;
+/// Strongest jump a client may request
+const MAX_JUMP: f32 = 5.0;
+/// Longest frame a single input may cover
+const MAX_DT: f32 = 0.25;
+/// Seconds of movement a paced avatar may save up while the client isn't sending inputs
+const MAX_BUDGET: f32 = 1.0;
+
+/// Movement keys held down during a frame: forward, backward, left, right, up, down
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Input {
+    pub sequence: u32,
+    pub moving: [f32; 6],
+    pub yaw: f32,
+    pub dt: f32
+}
+
+impl Input {
+    /// Whether every value is a number at all, clamping keeps NaN as it is
+    pub fn is_finite(&self) -> bool {
+        self.moving.iter().all(|value| value.is_finite()) && self.yaw.is_finite() && self.dt.is_finite()
+    }
+
+    /// Clamps all values into the range an honest client could send
+    fn sanitize(mut self) -> Input {
+        for (i, value) in self.moving.iter_mut().enumerate() {
+            let limit = if i < 4 { MAX_WALK } else { MAX_JUMP };
+            *value = value.clamp(0.0, limit);
+        }
+        self.dt = self.dt.clamp(0.0, MAX_DT);
+        self
+    }
+
+    fn direction(&self) -> [f32; 3] {
+        let (y_s, y_c) = (self.yaw.sin(), self.yaw.cos());
+        let m = self.moving;
+        [
+            (m[0] - m[1]) * y_s + (m[2] - m[3]) * y_c,
+            (m[0] - m[1]) * y_c - (m[2] - m[3]) * y_s,
+            m[4] - m[5]
+        ]
+    }
+}
+
+/// Round piece of scenery the avatar can't walk through, like the trunk of a tree
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Obstacle {
+    /// Center on the ground along the x and z axes
+    pub position: [f32; 2],
+    pub radius: f32,
+    /// Height of the top, the avatar is able to jump over it
+    pub top: f32
+}
+
+impl Obstacle {
+    /// Pushes the avatar out of the obstacle if it walked into it
+    fn collide(&self, state: &mut AvatarState) {
+        if state.position[1] >= self.top + PLAYER_HEIGHT { return }
+        let dx = state.position[0] - self.position[0];
+        let dz = state.position[2] - self.position[1];
+        let length = (dx * dx + dz * dz).sqrt();
+        if length < self.radius * 0.9 {
+            state.position[0] = self.position[0] + dx * 1.1;
+            state.position[2] = self.position[1] + dz * 1.1;
+            state.y_speed = 0.0;
+        } else if length < self.radius {
+            state.position[0] = self.position[0] + dx / length * self.radius;
+            state.position[2] = self.position[1] + dz / length * self.radius;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AvatarState {
+    pub position: [f32; 3],
+    pub y_speed: f32
+}
+
+impl AvatarState {
+    pub fn new(x: f32, y: f32, z: f32) -> AvatarState {
+        AvatarState {
+            position: [x, y, z],
+            y_speed: 0.0
+        }
+    }
+
+    /// Where avatars enter the map
+    pub fn spawn() -> AvatarState {
+        AvatarState::new(0.0, PLAYER_HEIGHT, 4.0)
+    }
+
+    /// Advances the avatar by one frame of input and resolves collisions with the obstacles
+    pub fn integrate(mut self, input: &Input, obstacles: &[Obstacle]) -> AvatarState {
+        let input = input.sanitize();
+        let direction = input.direction();
+        let dt = input.dt;
+
+        if input.moving[4] == 0.0 {
+            self.y_speed = (self.y_speed - 6.0 * dt).max(-8.0);
+        } else {
+            self.y_speed = (self.y_speed + direction[2] * dt).min(4.0);
+        }
+
+        if self.position[1] <= PLAYER_HEIGHT && self.y_speed < 0.0 {
+            self.position[1] = PLAYER_HEIGHT;
+            self.y_speed = 0.0;
+            self.position[0] -= direction[0] * dt;
+            self.position[2] -= direction[1] * dt;
+        } else {
+            self.position[0] -= direction[0] * dt * 1.5;
+            self.position[2] -= direction[1] * dt * 1.5;
+        }
+        self.position[1] += self.y_speed * dt;
+        for obstacle in obstacles {
+            obstacle.collide(&mut self);
+        }
+        self
+    }
+}
+
+// ---------------------------------------- PREDICTION ---------------------------------------
+
+/// Client side movement which is applied immediately and corrected once the server answers
+pub struct Prediction {
+    state: AvatarState,
+    pending: VecDeque<Input>,
+    next_sequence: u32,
+    /// Sequence of the newest authoritative state applied so far
+    acknowledged: Option<u32>,
+    obstacles: Vec<Obstacle>
+}
+
+impl Prediction {
+    pub fn new(state: AvatarState) -> Prediction {
+        Prediction {
+            state: state,
+            pending: VecDeque::new(),
+            next_sequence: 0,
+            acknowledged: None,
+            obstacles: Vec::new()
+        }
+    }
+
+    /// Replaces the scenery the avatar collides with, which has to match the server's
+    pub fn obstacles(&mut self, obstacles: Vec<Obstacle>) {
+        self.obstacles = obstacles;
+    }
+
+    pub fn state(&self) -> AvatarState {
+        self.state
+    }
+
+    /// Applies a frame of input locally and returns it for sending to the server
+    pub fn input(&mut self, moving: [f32; 6], yaw: f32, dt: f32) -> Input {
+        let input = Input { sequence: self.next_sequence, moving: moving, yaw: yaw, dt: dt };
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.state = self.state.integrate(&input, &self.obstacles);
+        self.pending.push_back(input);
+        input
+    }
+
+    /// Resets to the authoritative state and replays every input the server hasn't processed yet.
+    /// States older than one already applied, e.g. because they arrived out of order, are ignored.
+    pub fn reconcile(&mut self, acknowledged: u32, authoritative: AvatarState) {
+        if self.acknowledged.is_some_and(|sequence| acknowledged <= sequence) { return }
+        self.acknowledged = Some(acknowledged);
+        while self.pending.front().is_some_and(|input| input.sequence <= acknowledged) {
+            self.pending.pop_front();
+        }
+        let obstacles = &self.obstacles;
+        self.state = self.pending.iter().fold(authoritative, |state, input| state.integrate(input, obstacles));
+    }
+
+    /// Number of inputs still waiting for the server to acknowledge them
+    pub fn unacknowledged(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Inputs the server hasn't acknowledged yet. Resending them on lossy connections
+    /// makes sure the server eventually sees every input.
+    pub fn pending(&self) -> Vec<Input> {
+        self.pending.iter().cloned().collect()
+    }
+}
+
+// ---------------------------------------- AUTHORITY ----------------------------------------
+
+/// Server side movement of a single avatar
+pub struct Authority {
+    state: AvatarState,
+    acknowledged: Option<u32>,
+    obstacles: Vec<Obstacle>,
+    /// Seconds of movement still allowed, unlimited unless paced
+    budget: f32
+}
+
+impl Authority {
+    pub fn new(state: AvatarState) -> Authority {
+        Authority {
+            state: state,
+            acknowledged: None,
+            obstacles: Vec::new(),
+            budget: f32::INFINITY
+        }
+    }
+
+    /// Only accepts as much movement as time passed on the server, told by `elapse`, so clients
+    /// can't move faster by handing in more frames
+    pub fn paced(mut self) -> Authority {
+        self.budget = MAX_BUDGET;
+        self
+    }
+
+    /// Allows the movement of the time that passed, saving up at most `MAX_BUDGET`
+    pub fn elapse(&mut self, seconds: f32) {
+        if self.budget.is_finite() {
+            self.budget = (self.budget + seconds).min(MAX_BUDGET);
+        }
+    }
+
+    /// Replaces the scenery the avatar collides with
+    pub fn obstacles(&mut self, obstacles: Vec<Obstacle>) {
+        self.obstacles = obstacles;
+    }
+
+    /// Applies an input unless it is older than the last one processed, isn't a number or covers
+    /// more time than the budget has left
+    pub fn apply(&mut self, input: &Input) -> bool {
+        if self.acknowledged.is_some_and(|sequence| input.sequence <= sequence) || !input.is_finite() { return false }
+        let dt = input.sanitize().dt;
+        if dt > self.budget { return false }
+        self.budget -= dt;
+        self.state = self.state.integrate(input, &self.obstacles);
+        self.acknowledged = Some(input.sequence);
+        true
+    }
+
+    /// Returns the last processed input sequence together with the resulting state
+    pub fn snapshot(&self) -> Option<(u32, AvatarState)> {
+        self.acknowledged.map(|sequence| (sequence, self.state))
+    }
+}
+
+#[test]
+fn reconcile_replays_pending_inputs() {
+    let start = AvatarState::new(0.0, PLAYER_HEIGHT, 0.0);
+    let mut client = Prediction::new(start);
+    let mut server = Authority::new(start);
+
+    let inputs: Vec<Input> = (0..5).map(|_| client.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.1)).collect();
+    for input in inputs.iter().take(2) {
+        assert!(server.apply(input));
+    }
+    assert!(!server.apply(&inputs[0]));
+
+    let predicted = client.state();
+    let (sequence, state) = server.snapshot().unwrap();
+    client.reconcile(sequence, state);
+    assert_eq!(client.unacknowledged(), 3);
+    assert_eq!(client.state(), predicted);
+
+    // Speed hacks are clamped to the walking speed
+    let mut server = Authority::new(start);
+    server.apply(&Input { sequence: 0, moving: [100.0, 0.0, 0.0, 0.0, 0.0, 0.0], yaw: 0.0, dt: 0.1 });
+    assert!((server.snapshot().unwrap().1.position[2] + 0.13).abs() < 1e-6);
+}
+
+#[test]
+fn stale_snapshots_are_ignored() {
+    let start = AvatarState::new(0.0, PLAYER_HEIGHT, 0.0);
+    let mut client = Prediction::new(start);
+    let mut server = Authority::new(start);
+    let mut snapshots = Vec::new();
+    for _ in 0..4 {
+        server.apply(&client.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.1));
+        snapshots.push(server.snapshot().unwrap());
+    }
+
+    // The newest snapshot overtook an older one on the way
+    client.reconcile(snapshots[3].0, snapshots[3].1);
+    let settled = client.state();
+    client.reconcile(snapshots[1].0, snapshots[1].1);
+    assert_eq!(client.state(), settled);
+    assert_eq!(client.unacknowledged(), 0);
+}
+
+#[test]
+fn paced_movement() {
+    let start = AvatarState::new(0.0, PLAYER_HEIGHT, 0.0);
+    let mut client = Prediction::new(start);
+    let mut server = Authority::new(start).paced();
+
+    // Frames beyond the time that passed are refused until the server's clock caught up
+    let inputs: Vec<Input> = (0..20).map(|_| client.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.125)).collect();
+    assert_eq!(inputs.iter().filter(|input| server.apply(input)).count(), 8);
+    server.elapse(0.5);
+    assert_eq!(inputs.iter().skip(8).filter(|input| server.apply(input)).count(), 4);
+    let (sequence, state) = server.snapshot().unwrap();
+    assert_eq!(sequence, 11);
+    assert!((state.position[2] + 1.5 * 1.3).abs() < 1e-4, "{:?}", state);
+
+    // Clamping would keep a NaN, so such inputs are refused altogether
+    let mut broken = client.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.125);
+    broken.sequence = 12;
+    broken.yaw = f32::NAN;
+    server.elapse(1.0);
+    assert!(!server.apply(&broken));
+    assert_eq!(server.snapshot().unwrap().1, state);
+}
+
+#[test]
+fn obstacles_block_both_sides() {
+    let start = AvatarState::new(0.0, PLAYER_HEIGHT, 0.0);
+    let tree = Obstacle { position: [0.0, -1.0], radius: 0.5, top: 3.0 };
+    let mut client = Prediction::new(start);
+    client.obstacles(vec![tree]);
+    let mut server = Authority::new(start);
+    server.obstacles(vec![tree]);
+
+    // Walking straight into the tree for a few seconds stops at its edge
+    for _ in 0..200 {
+        server.apply(&client.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.016));
+    }
+    let (sequence, state) = server.snapshot().unwrap();
+    assert!((state.position[2] + 0.5).abs() < 1e-3, "{:?}", state);
+    client.reconcile(sequence, state);
+    assert_eq!(client.state(), state);
+}
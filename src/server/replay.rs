@@ -0,0 +1,377 @@
+//! Recording of matches. The simulation is deterministic, so the initial setup and the commands
+//! accepted by the `Server` are enough to reproduce a whole match, including every timeline that
+//! has been abandoned along the way. Replays are stored as plain text, one record per line:
+//!
+//! ```text
+//! REPLAY 2
+//! HORIZON <horizon>
+//! SPAWN <present> <player> <Scout|Knight> <x> <y> <orientation>
+//! MOVE <present> <player> <unit> <x> <y>
+//! PORTAL <present> <player> <time> <x> <y> <lifetime> <scale> <time> <x> <y> <lifetime> <scale>
+//! SAY <present> <player|spectator> <channel> <text>
+//! PING <present> <player> <x> <y>
+//! END <present> [<checksum>]
+//! ```
+//!
+//! The checksum of the final keyframe allows verifying that a replay still reproduces the
+//! recorded match, e.g. after the simulation has been changed. It has been added in version 2,
+//! replays of version 1 are still read but can't be verified.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use {Server, Command, CommandError, AIType, Player, TimeIndex, Keyframe, Coordinates, Orientation};
+use chat::{Channel, Message, Participant, Ping};
+use lockstep::Checksum;
+
+const VERSION: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Spawn { player: Player, ai_type: AIType, location: Coordinates, orientation: Orientation },
+    Command(Player, Command),
+    Message(Message),
+    Ping(Ping)
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// Malformed line of a replay file along with its line number
+    Parse(usize, String),
+    UnsupportedVersion(u32),
+    /// A recorded command got rejected on playback, so the simulation has diverged
+    Rejected(TimeIndex, CommandError),
+    /// The final state differs from the recorded one
+    Mismatch { expected: u64, found: u64 },
+    Io(io::Error)
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> ReplayError {
+        ReplayError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub horizon: TimeIndex,
+    /// Everything that happened along with the present it happened at, in order
+    pub records: Vec<(TimeIndex, Record)>,
+    /// Present of the match when the recording stopped
+    pub end: TimeIndex,
+    /// Checksum of the keyframe at `end`
+    pub checksum: Option<u64>
+}
+
+impl Replay {
+    pub fn new(horizon: TimeIndex) -> Replay {
+        Replay {
+            horizon: horizon,
+            records: Vec::new(),
+            end: 0,
+            checksum: None
+        }
+    }
+
+    /// Adds the chat of the match. Messages are placed at the present they have been sent at,
+    /// pings at the time they mark.
+    pub fn chat(mut self, messages: &[Message], pings: &[Ping]) -> Replay {
+        self.records.extend(messages.iter().map(|message| (message.time, Record::Message(message.clone()))));
+        self.records.extend(pings.iter().map(|ping| (ping.time, Record::Ping(*ping))));
+        self.records.sort_by_key(|record| record.0);
+        self
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Replay, ReplayError> {
+        Replay::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "REPLAY {}", VERSION)?;
+        writeln!(writer, "HORIZON {}", self.horizon)?;
+        for &(present, ref record) in self.records.iter() {
+            match *record {
+                Record::Spawn { player, ai_type, location, orientation } =>
+                    writeln!(writer, "SPAWN {} {} {:?} {} {} {}", present, player, ai_type, location.0, location.1, orientation)?,
+                Record::Command(player, Command::Move { unit, target }) =>
+                    writeln!(writer, "MOVE {} {} {} {} {}", present, player, unit, target.0, target.1)?,
+                Record::Command(player, Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale }) =>
+                    writeln!(writer, "PORTAL {} {} {} {} {} {} {} {} {} {} {} {}", present, player,
+                             origin.0, (origin.1).0, (origin.1).1, origin_lifetime, origin_scale,
+                             dest.0, (dest.1).0, (dest.1).1, dest_lifetime, dest_scale)?,
+                Record::Message(ref message) => {
+                    let sender = match message.sender {
+                        Participant::Player(player) => player.to_string(),
+                        Participant::Spectator => "spectator".to_string()
+                    };
+                    writeln!(writer, "SAY {} {} {} {}", present, sender, message.channel.name(), message.text)?
+                },
+                Record::Ping(ping) =>
+                    writeln!(writer, "PING {} {} {} {}", present, ping.player, ping.location.0, ping.location.1)?
+            }
+        }
+        match self.checksum {
+            Some(checksum) => writeln!(writer, "END {} {}", self.end, checksum),
+            None => writeln!(writer, "END {}", self.end)
+        }
+    }
+
+    pub fn read<R: BufRead>(reader: R) -> Result<Replay, ReplayError> {
+        let mut replay = Replay::new(0);
+        let mut ended = false;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let number = i + 1;
+            let error = || ReplayError::Parse(number, line.clone());
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if ended && !parts.is_empty() { return Err(error()) }
+            match (parts.first().cloned(), parts.len()) {
+                (None, _) => {},
+                (Some("REPLAY"), 2) if number == 1 => {
+                    let version = parse(parts[1]).ok_or_else(error)?;
+                    if version == 0 || version > VERSION { return Err(ReplayError::UnsupportedVersion(version)) }
+                },
+                _ if number == 1 => return Err(error()),
+                (Some("HORIZON"), 2) => replay.horizon = parse(parts[1]).ok_or_else(error)?,
+                (Some("END"), 2) | (Some("END"), 3) => {
+                    replay.end = parse(parts[1]).ok_or_else(error)?;
+                    replay.checksum = match parts.get(2) {
+                        Some(checksum) => Some(parse(checksum).ok_or_else(error)?),
+                        None => None
+                    };
+                    ended = true;
+                },
+                _ => {
+                    let record = read_record(&parts, &line).ok_or_else(error)?;
+                    replay.records.push(record);
+                }
+            }
+        }
+        if !ended { return Err(ReplayError::Parse(0, "missing END".to_string())) }
+        Ok(replay)
+    }
+}
+
+fn read_record(parts: &[&str], line: &str) -> Option<(TimeIndex, Record)> {
+    let present = parse(parts.get(1)?)?;
+    let record = match (parts[0], parts.len()) {
+        ("SPAWN", 7) => Record::Spawn {
+            player: parse(parts[2])?,
+            ai_type: match parts[3] {
+                "Scout" => AIType::Scout,
+                "Knight" => AIType::Knight,
+                _ => return None
+            },
+            location: (parse(parts[4])?, parse(parts[5])?),
+            orientation: parse(parts[6])?
+        },
+        ("MOVE", 6) => Record::Command(parse(parts[2])?, Command::Move {
+            unit: parse(parts[3])?,
+            target: (parse(parts[4])?, parse(parts[5])?)
+        }),
+        ("PORTAL", 13) => Record::Command(parse(parts[2])?, Command::OpenPortal {
+            origin: (parse(parts[3])?, (parse(parts[4])?, parse(parts[5])?)),
+            origin_lifetime: parse(parts[6])?, origin_scale: parse(parts[7])?,
+            dest: (parse(parts[8])?, (parse(parts[9])?, parse(parts[10])?)),
+            dest_lifetime: parse(parts[11])?, dest_scale: parse(parts[12])?
+        }),
+        ("SAY", n) if n >= 5 => Record::Message(Message {
+            sender: match parts[2] {
+                "spectator" => Participant::Spectator,
+                player => Participant::Player(parse(player)?)
+            },
+            channel: Channel::parse(parts[3])?,
+            time: present,
+            // The text keeps its inner whitespace, it starts after the fourth separator
+            text: line.trim_start().splitn(5, char::is_whitespace).nth(4)?.trim().to_string()
+        }),
+        ("PING", 5) => Record::Ping(Ping {
+            player: parse(parts[2])?,
+            location: (parse(parts[3])?, parse(parts[4])?),
+            time: present
+        }),
+        _ => return None
+    };
+    Some((present, record))
+}
+
+fn parse<T: ::std::str::FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+// ----------------------------------------- PLAYBACK ----------------------------------------
+
+/// Summary of a match
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistics {
+    pub ticks: TimeIndex,
+    /// Units each player lost in battle
+    pub losses: BTreeMap<Player, usize>,
+    pub portals: usize,
+    /// Timeline branches abandoned because the past changed
+    pub forks: usize,
+    /// Portal trips whose traveler doesn't exist anymore at the time it departed
+    pub paradoxes: usize
+}
+
+/// Feeds a replay into a fresh `Server`. The present can be moved freely, going back in time
+/// restarts the simulation from the beginning.
+pub struct Playback {
+    replay: Replay,
+    server: Server,
+    next: usize
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Playback {
+        Playback {
+            server: Server::new().horizon(replay.horizon),
+            replay: replay,
+            next: 0
+        }
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn present(&self) -> TimeIndex {
+        self.server.present()
+    }
+
+    pub fn end(&self) -> TimeIndex {
+        self.replay.end
+    }
+
+    /// Reproduces the match up to the given present, applying every record made before or at it
+    pub fn seek(&mut self, present: TimeIndex) -> Result<(), ReplayError> {
+        if present < self.server.present() {
+            self.server = Server::new().horizon(self.replay.horizon);
+            self.next = 0;
+        }
+        loop {
+            while let Some(&(time, ref record)) = self.replay.records.get(self.next) {
+                if time > self.server.present() { break }
+                match *record {
+                    Record::Spawn { player, ai_type, location, orientation } => { self.server.spawn(player, ai_type, location, orientation); },
+                    Record::Command(player, ref command) => {
+                        self.server.issue(player, command.clone()).map_err(|e| ReplayError::Rejected(time, e))?
+                    },
+                    Record::Message(_) | Record::Ping(_) => {}
+                }
+                self.next += 1;
+            }
+            if self.server.present() >= present { return Ok(()) }
+            self.server.tick();
+        }
+    }
+
+    /// State of the current timeline at `time`, which mustn't lie beyond the present
+    pub fn view(&mut self, time: TimeIndex) -> Option<Keyframe> {
+        self.server.spectate(time)
+    }
+
+    /// Messages sent up to the present
+    pub fn messages(&self) -> Vec<&Message> {
+        self.applied().filter_map(|record| match *record {
+            Record::Message(ref message) => Some(message),
+            _ => None
+        }).collect()
+    }
+
+    /// Pings marking times up to the present
+    pub fn pings(&self) -> Vec<Ping> {
+        self.applied().filter_map(|record| match *record {
+            Record::Ping(ping) => Some(ping),
+            _ => None
+        }).collect()
+    }
+
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    /// Plays the whole replay and compares the final state to the recorded checksum
+    pub fn verify(&mut self) -> Result<(), ReplayError> {
+        let end = self.replay.end;
+        self.seek(end)?;
+        let found = Checksum::new(&self.server.spectate(end).unwrap_or_default()).keyframe;
+        match self.replay.checksum {
+            Some(expected) if expected != found => Err(ReplayError::Mismatch { expected: expected, found: found }),
+            _ => Ok(())
+        }
+    }
+
+    /// Sums up the timeline as it stands at the present
+    pub fn statistics(&mut self) -> Statistics {
+        let present = self.server.present();
+        let timeline: Vec<Keyframe> = (0..present + 1).filter_map(|t| self.server.spectate(t)).collect();
+        let traversals = self.server.traversals().to_vec();
+
+        let mut losses = BTreeMap::new();
+        for (t, pair) in timeline.windows(2).enumerate() {
+            let departed = |id| traversals.iter().any(|traversal| traversal.unit == id && traversal.departure == t + 1);
+            for unit in pair[0].iter().filter(|unit| !pair[1].iter().any(|other| other.0 == unit.0) && !departed(unit.0)) {
+                if let Some(owner) = self.server.owner(unit.0) {
+                    *losses.entry(owner).or_insert(0) += 1;
+                }
+            }
+        }
+
+        // Travelers who never made it to their portal in the timeline that prevailed
+        let paradoxes = traversals.iter().filter(|traversal| {
+            traversal.departure > 0 && traversal.departure <= present &&
+            !timeline[traversal.departure - 1].iter().any(|unit| unit.0 == traversal.unit)
+        }).count();
+
+        Statistics {
+            ticks: present,
+            losses: losses,
+            portals: self.server.portals().len(),
+            forks: self.server.branches().len(),
+            paradoxes: paradoxes
+        }
+    }
+
+    fn applied(&self) -> impl Iterator<Item = &Record> {
+        self.replay.records[..self.next].iter().map(|record| &record.1)
+    }
+}
+
+#[test]
+fn replay_roundtrip() {
+    let mut replay = Replay::new(30);
+    replay.records.push((0, Record::Spawn { player: 0, ai_type: AIType::Knight, location: (0.1, -2.5), orientation: 1.0 }));
+    replay.records.push((3, Record::Command(0, Command::Move { unit: 0, target: (1.0 / 3.0, 7.0) })));
+    replay.records.push((4, Record::Command(0, Command::OpenPortal {
+        origin: (4, (0.0, 0.0)), origin_lifetime: 2, origin_scale: 1.5,
+        dest: (1, (3.0, 3.0)), dest_lifetime: 1, dest_scale: 0.5
+    })));
+    replay.end = 9;
+    replay.checksum = Some(u64::MAX);
+    let messages = vec![Message { sender: Participant::Spectator, channel: Channel::Spectators, time: 5, text: "what  a move".to_string() }];
+    let replay = replay.chat(&messages, &[Ping { player: 0, location: (2.0, 2.0), time: 4 }]);
+
+    let mut file = Vec::new();
+    replay.write(&mut file).unwrap();
+    assert_eq!(Replay::read(&file[..]).unwrap(), replay);
+
+    match Replay::read(&b"REPLAY 2\nHORIZON 5\nMOVE 1 0\nEND 3\n"[..]) {
+        Err(ReplayError::Parse(3, _)) => {},
+        e => panic!("{:?}", e)
+    }
+    assert!(Replay::read(&b"REPLAY 2\nHORIZON 5\n"[..]).is_err());
+    assert_eq!(Replay::read(&b"REPLAY 1\nHORIZON 5\nEND 3\n"[..]).unwrap().checksum, None);
+    match Replay::read(&b"REPLAY 3\nHORIZON 5\nEND 3\n"[..]) {
+        Err(ReplayError::UnsupportedVersion(3)) => {},
+        e => panic!("{:?}", e)
+    }
+}
@@ -0,0 +1,170 @@
+//! Session tokens allowing dropped clients to take their `Player` slot back
+use std::time::{Duration, Instant};
+
+use rand::{OsRng, Rng};
+
+use {Player, TimeIndex};
+
+pub type Token = String;
+/// Counts how often a session has been taken over by a new connection, so a connection that
+/// has been replaced can't disconnect the session from its successor anymore
+pub type Attachment = usize;
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    UnknownToken,
+    Expired
+}
+
+struct Session {
+    token: Token,
+    player: Player,
+    attachment: Attachment,
+    disconnected_at: Option<Instant>,
+    acknowledged: TimeIndex,
+    /// Number of match events delivered on sync
+    events: usize
+}
+
+pub struct Sessions {
+    sessions: Vec<Session>,
+    grace_period: Duration
+}
+
+impl Sessions {
+    pub fn new(grace_period: Duration) -> Sessions {
+        Sessions {
+            sessions: Vec::new(),
+            grace_period: grace_period
+        }
+    }
+
+    /// Issues a new token for a player that just took a slot
+    pub fn open(&mut self, player: Player) -> Token {
+        let token = generate_token();
+        self.sessions.push(Session {
+            token: token.clone(),
+            player: player,
+            attachment: 0,
+            disconnected_at: None,
+            acknowledged: 0,
+            events: 0
+        });
+        token
+    }
+
+    /// Ends the session of a player for good, e.g. when it left voluntarily
+    pub fn close(&mut self, player: Player) {
+        self.sessions.retain(|session| session.player != player);
+    }
+
+    pub fn player(&self, token: &str) -> Result<Player, SessionError> {
+        self.find(token).map(|session| session.player)
+    }
+
+    /// Current attachment of the session, i.e. the connection holding it
+    pub fn attachment(&self, token: &str) -> Result<Attachment, SessionError> {
+        self.find(token).map(|session| session.attachment)
+    }
+
+    /// Starts the grace period of the session. Its units keep following their last orders meanwhile.
+    /// Nothing happens if the session has been resumed by another connection since `attachment`.
+    pub fn disconnect(&mut self, token: &str, attachment: Attachment, now: Instant) {
+        if let Some(session) = self.sessions.iter_mut().find(|session| session.token == token) {
+            if session.attachment == attachment {
+                session.disconnected_at = Some(now);
+            }
+        }
+    }
+
+    /// Reattaches a client to its slot and returns the slot together with the last tick it received.
+    /// The session is handed over to the new connection even if the old one is still open.
+    pub fn resume(&mut self, token: &str, now: Instant) -> Result<(Player, TimeIndex), SessionError> {
+        let grace_period = self.grace_period;
+        match self.sessions.iter_mut().find(|session| session.token == token) {
+            Some(session) => {
+                if let Some(disconnected_at) = session.disconnected_at {
+                    if now.duration_since(disconnected_at) > grace_period { return Err(SessionError::Expired) }
+                }
+                session.disconnected_at = None;
+                session.attachment += 1;
+                Ok((session.player, session.acknowledged))
+            },
+            None => Err(SessionError::UnknownToken)
+        }
+    }
+
+    /// Remembers the last tick that has been delivered to the client
+    pub fn acknowledge(&mut self, token: &str, time: TimeIndex) -> Result<(), SessionError> {
+        match self.sessions.iter_mut().find(|session| session.token == token) {
+            Some(session) => {
+                session.acknowledged = time;
+                Ok(())
+            },
+            None => Err(SessionError::UnknownToken)
+        }
+    }
+
+    pub fn acknowledged(&self, token: &str) -> Result<TimeIndex, SessionError> {
+        self.find(token).map(|session| session.acknowledged)
+    }
+
+    /// Remembers how many events of the match have been delivered to the client
+    pub fn acknowledge_events(&mut self, token: &str, count: usize) -> Result<(), SessionError> {
+        match self.sessions.iter_mut().find(|session| session.token == token) {
+            Some(session) => {
+                session.events = count;
+                Ok(())
+            },
+            None => Err(SessionError::UnknownToken)
+        }
+    }
+
+    pub fn acknowledged_events(&self, token: &str) -> Result<usize, SessionError> {
+        self.find(token).map(|session| session.events)
+    }
+
+    /// Drops all sessions whose grace period ran out and returns the slots they were holding
+    pub fn expire(&mut self, now: Instant) -> Vec<Player> {
+        let grace_period = self.grace_period;
+        let expired = |session: &Session| session.disconnected_at.is_some_and(|t| now.duration_since(t) > grace_period);
+        let players = self.sessions.iter().filter(|session| expired(session)).map(|session| session.player).collect();
+        self.sessions.retain(|session| !expired(session));
+        players
+    }
+
+    fn find(&self, token: &str) -> Result<&Session, SessionError> {
+        self.sessions.iter().find(|session| session.token == token).ok_or(SessionError::UnknownToken)
+    }
+}
+
+/// 128 bits from the operating system's random number generator, so tokens can't be guessed
+/// from the ones handed out before
+fn generate_token() -> Token {
+    let mut rng = OsRng::new().expect("operating system random number generator unavailable");
+    format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64())
+}
+
+#[test]
+fn resume_within_grace_period() {
+    let mut sessions = Sessions::new(Duration::from_secs(30));
+    let token = sessions.open(1);
+    let other = sessions.open(2);
+    assert!(token != other);
+
+    let start = Instant::now();
+    sessions.acknowledge(&token, 42).unwrap();
+    sessions.disconnect(&token, 0, start);
+    assert!(sessions.expire(start + Duration::from_secs(10)).is_empty());
+    assert_eq!(sessions.resume(&token, start + Duration::from_secs(10)), Ok((1, 42)));
+
+    // The connection that dropped before can't disconnect the one that took over
+    assert_eq!(sessions.attachment(&token), Ok(1));
+    sessions.disconnect(&token, 0, start);
+    assert!(sessions.expire(start + Duration::from_secs(31)).is_empty());
+
+    sessions.disconnect(&token, 1, start);
+    assert_eq!(sessions.resume(&token, start + Duration::from_secs(31)), Err(SessionError::Expired));
+    assert_eq!(sessions.expire(start + Duration::from_secs(31)), vec![1]);
+    assert_eq!(sessions.resume(&token, start), Err(SessionError::UnknownToken));
+}
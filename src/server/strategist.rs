@@ -0,0 +1,109 @@
+//! Computer controlled opponent. It only uses the public `Server` API available to any other player.
+use {Server, Command, Player, TimeIndex, Coordinates, ID, distance, MAX_PORTAL_SCALE};
+
+/// Ticks a reinforcement portal stays open after the slowest unit of the reserve could reach it
+const PORTAL_SLACK: TimeIndex = 2;
+/// Distance beyond the outermost unit of the reserve still covered by the portal
+const PORTAL_MARGIN: f32 = 1.0;
+
+pub struct Strategist {
+    player: Player,
+    horizon: TimeIndex,
+    reinforced: Vec<TimeIndex>
+}
+
+impl Strategist {
+    pub fn new(player: Player) -> Strategist {
+        Strategist {
+            player: player,
+            horizon: 20,
+            reinforced: Vec::new()
+        }
+    }
+
+    /// How far the strategist looks back in time for lost battles worth reinforcing
+    pub fn horizon(mut self, horizon: TimeIndex) -> Strategist {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Evaluates the timeline and issues the commands for the present tick
+    pub fn play(&mut self, server: &mut Server) -> Vec<Command> {
+        let now = server.present();
+        let current = match server.observe(self.player, now) {
+            Some(keyframe) => keyframe,
+            None => return Vec::new()
+        };
+        let (own, enemies): (Vec<_>, Vec<_>) = current.into_iter()
+            .partition(|unit| server.owner(unit.0) == Some(self.player));
+        if own.is_empty() || enemies.is_empty() { return Vec::new() }
+
+        let mut commands = Vec::new();
+
+        // Send as many units as were lost, those furthest away from the frontline, back to the
+        // latest battle we lost. At least one unit stays in the present unless it is the last.
+        // The portal is sized to gather all of them and stays open until the slowest one walked
+        // in, both ends alike so they neither shrink nor slow down.
+        let mut reserve = Vec::new();
+        if let Some((time, location, lost)) = self.find_lost_battle(server, now) {
+            let mut candidates = own.clone();
+            candidates.sort_by(|a, b| closest(b.1, &enemies).1.total_cmp(&closest(a.1, &enemies).1));
+            candidates.truncate(lost.min(own.len() - 1).max(1));
+            let count = candidates.len() as f32;
+            let center = (candidates.iter().map(|unit| (unit.1).0).sum::<f32>() / count,
+                          candidates.iter().map(|unit| (unit.1).1).sum::<f32>() / count);
+            let spread = candidates.iter().map(|unit| distance(unit.1, center)).fold(0.0, f32::max);
+            let scale = (spread + PORTAL_MARGIN).min(MAX_PORTAL_SCALE);
+            let walk = candidates.iter()
+                .map(|unit| (distance(unit.1, center) / server.unit_type(unit.0).unwrap().speed()).ceil() as TimeIndex)
+                .max().unwrap_or(0);
+            let lifetime = walk + PORTAL_SLACK + 1;
+            for unit in candidates.iter() {
+                commands.push(Command::Move { unit: unit.0, target: center });
+            }
+            commands.push(Command::OpenPortal {
+                origin: (now, center), origin_lifetime: lifetime, origin_scale: scale,
+                dest: (time, location), dest_lifetime: lifetime, dest_scale: scale
+            });
+            self.reinforced.push(time);
+            reserve = candidates.into_iter().map(|unit| unit.0).collect();
+        }
+
+        // Everyone else charges the closest enemy
+        for unit in own.iter().filter(|unit| !reserve.contains(&unit.0)) {
+            let target = closest(unit.1, &enemies).0;
+            commands.push(Command::Move { unit: unit.0, target: target });
+        }
+
+        commands.into_iter().filter(|command| server.issue(self.player, command.clone()).is_ok()).collect()
+    }
+
+    /// Finds the most recent tick within the horizon right before some of our units died, where the
+    /// first of them died and how many. Units leaving through a portal didn't die.
+    fn find_lost_battle(&self, server: &mut Server, now: TimeIndex) -> Option<(TimeIndex, Coordinates, usize)> {
+        let start = if now > self.horizon { now - self.horizon } else { 1 };
+        let mut lost = None;
+        let mut previous: Option<Vec<(ID, Coordinates)>> = None;
+        for time in start - 1..now + 1 {
+            let own: Vec<(ID, Coordinates)> = server.observe(self.player, time).unwrap().into_iter()
+                .filter(|unit| server.owner(unit.0) == Some(self.player))
+                .map(|unit| (unit.0, unit.1)).collect();
+            if let Some(before) = previous {
+                let dead: Vec<&(ID, Coordinates)> = before.iter()
+                    .filter(|unit| !own.iter().any(|other| other.0 == unit.0))
+                    .filter(|unit| !server.traversals().iter().any(|t| t.unit == unit.0 && t.departure == time))
+                    .collect();
+                if !dead.is_empty() && !self.reinforced.contains(&(time - 1)) {
+                    lost = Some((time - 1, dead[0].1, dead.len()));
+                }
+            }
+            previous = Some(own);
+        }
+        lost
+    }
+}
+
+fn closest(location: Coordinates, units: &[(ID, Coordinates, f32)]) -> (Coordinates, f32) {
+    units.iter().map(|unit| (unit.1, distance(location, unit.1)))
+        .fold((location, f32::MAX), |best, candidate| if candidate.1 < best.1 { candidate } else { best })
+}
@@ -0,0 +1,250 @@
+//! Message transports between clients and the server. `Simulated` injects bad network conditions
+//! driven by a virtual clock and a seeded random generator, so tests stay deterministic.
+use std::collections::VecDeque;
+use std::mem;
+
+pub trait Transport<T> {
+    fn send(&mut self, message: T);
+
+    /// Returns every message that arrived so far, in the order of arrival
+    fn receive(&mut self) -> Vec<T>;
+}
+
+/// Perfect connection delivering everything immediately and in order
+pub struct Direct<T> {
+    queue: VecDeque<T>
+}
+
+impl<T> Default for Direct<T> {
+    fn default() -> Direct<T> {
+        Direct::new()
+    }
+}
+
+impl<T> Direct<T> {
+    pub fn new() -> Direct<T> {
+        Direct {
+            queue: VecDeque::new()
+        }
+    }
+}
+
+impl<T> Transport<T> for Direct<T> {
+    fn send(&mut self, message: T) {
+        self.queue.push_back(message);
+    }
+
+    fn receive(&mut self) -> Vec<T> {
+        self.queue.drain(..).collect()
+    }
+}
+
+// ---------------------------------------- CONDITIONS ---------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    latency: u64,
+    jitter: u64,
+    loss: f32,
+    reordering: f32,
+    bandwidth: Option<u64>,
+    seed: u64
+}
+
+impl Default for Conditions {
+    fn default() -> Conditions {
+        Conditions::new()
+    }
+}
+
+impl Conditions {
+    pub fn new() -> Conditions {
+        Conditions {
+            latency: 0,
+            jitter: 0,
+            loss: 0.0,
+            reordering: 0.0,
+            bandwidth: None,
+            seed: 0x2545f4914f6cdd1d
+        }
+    }
+
+    /// One way delay in milliseconds
+    pub fn latency(mut self, ms: u64) -> Conditions {
+        self.latency = ms;
+        self
+    }
+
+    /// Maximum random delay in milliseconds added on top of the latency
+    pub fn jitter(mut self, ms: u64) -> Conditions {
+        self.jitter = ms;
+        self
+    }
+
+    /// Probability of a message getting lost
+    pub fn loss(mut self, probability: f32) -> Conditions {
+        self.loss = probability;
+        self
+    }
+
+    /// Probability of a message being held back long enough to arrive after its successors
+    pub fn reordering(mut self, probability: f32) -> Conditions {
+        self.reordering = probability;
+        self
+    }
+
+    /// Maximum throughput in bytes per second
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> Conditions {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Conditions {
+        self.seed = if seed == 0 { 1 } else { seed };
+        self
+    }
+}
+
+// ---------------------------------------- SIMULATED ----------------------------------------
+
+/// Xorshift generator, good enough to roll the dice for the network simulation
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32 <= probability && probability > 0.0
+    }
+
+    fn below(&mut self, max: u64) -> u64 {
+        if max == 0 { 0 } else { self.next() % (max + 1) }
+    }
+}
+
+/// Size of a message of fixed length, its stack size
+pub fn fixed_size<T>(_: &T) -> usize {
+    mem::size_of::<T>()
+}
+
+/// One direction of a connection suffering from the configured conditions
+pub struct Simulated<T> {
+    conditions: Conditions,
+    /// Bytes a message occupies on the wire, charged against the bandwidth
+    size: fn(&T) -> usize,
+    rng: Rng,
+    now: u64,
+    link_free_at: u64,
+    sent: u64,
+    in_flight: Vec<(u64, u64, T)>
+}
+
+impl<T> Simulated<T> {
+    /// Creates a link charging `size(message)` bytes of bandwidth per message. Messages of
+    /// varying length, like `Vec`s, need a size function counting their contents.
+    pub fn new(conditions: Conditions, size: fn(&T) -> usize) -> Simulated<T> {
+        Simulated {
+            conditions: conditions,
+            size: size,
+            rng: Rng(conditions.seed),
+            now: 0,
+            link_free_at: 0,
+            sent: 0,
+            in_flight: Vec::new()
+        }
+    }
+
+    /// Moves the virtual clock forward
+    pub fn advance(&mut self, ms: u64) {
+        self.now += ms;
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Number of messages that have been sent but not received yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+impl<T> Transport<T> for Simulated<T> {
+    fn send(&mut self, message: T) {
+        if self.rng.chance(self.conditions.loss) { return }
+
+        // Messages queue up while the link is busy transmitting their predecessors
+        let start = if self.link_free_at > self.now { self.link_free_at } else { self.now };
+        self.link_free_at = match self.conditions.bandwidth {
+            Some(bandwidth) => start + ((self.size)(&message) as u64 * 1000).div_ceil(bandwidth.max(1)),
+            None => start
+        };
+
+        let mut delay = self.conditions.latency + self.rng.below(self.conditions.jitter);
+        if self.rng.chance(self.conditions.reordering) {
+            delay += self.conditions.latency + self.conditions.jitter + 1;
+        }
+        self.in_flight.push((self.link_free_at + delay, self.sent, message));
+        self.sent += 1;
+    }
+
+    fn receive(&mut self) -> Vec<T> {
+        let now = self.now;
+        let (mut arrived, pending): (Vec<_>, Vec<_>) = self.in_flight.drain(..).partition(|message| message.0 <= now);
+        self.in_flight = pending;
+        arrived.sort_by_key(|message| (message.0, message.1));
+        arrived.into_iter().map(|message| message.2).collect()
+    }
+}
+
+#[test]
+fn simulated_conditions() {
+    let mut perfect = Simulated::new(Conditions::new(), fixed_size);
+    perfect.send(1);
+    assert_eq!(perfect.receive(), vec![1]);
+
+    let mut slow = Simulated::new(Conditions::new().latency(100), fixed_size);
+    slow.send(1);
+    slow.advance(99);
+    assert!(slow.receive().is_empty());
+    slow.advance(1);
+    assert_eq!(slow.receive(), vec![1]);
+
+    let mut lossy = Simulated::new(Conditions::new().loss(0.5).seed(7), fixed_size);
+    for i in 0..1000 { lossy.send(i); }
+    let received = lossy.receive().len();
+    assert!(received > 400 && received < 600);
+
+    // 8 byte messages at 80 bytes per second leave every 100 ms
+    let mut narrow = Simulated::new(Conditions::new().bandwidth(80), fixed_size);
+    for i in 0..10u64 { narrow.send(i); }
+    narrow.advance(500);
+    assert_eq!(narrow.receive(), vec![0, 1, 2, 3, 4]);
+
+    let mut shuffled = Simulated::new(Conditions::new().latency(10).reordering(0.3).seed(3), fixed_size);
+    for i in 0..100 { shuffled.send(i); }
+    shuffled.advance(1000);
+    let received = shuffled.receive();
+    assert_eq!(received.len(), 100);
+    assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+}
+
+#[test]
+fn bandwidth_counts_contents() {
+    let conditions = Conditions::new().bandwidth(1000);
+    let mut small = Simulated::new(conditions, |message: &Vec<u8>| message.len());
+    let mut large = Simulated::new(conditions, |message: &Vec<u8>| message.len());
+    small.send(vec![0; 10]);
+    large.send(vec![0; 500]);
+    small.advance(10);
+    large.advance(10);
+    assert_eq!(small.receive().len(), 1);
+    assert!(large.receive().is_empty());
+    large.advance(490);
+    assert_eq!(large.receive().len(), 1);
+}
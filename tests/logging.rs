@@ -0,0 +1,56 @@
+// The configuration is global, so these tests get a binary of their own and run one after another
+#[macro_use]
+extern crate server;
+
+mod logging_test {
+    use server::logging::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn logging() {
+        configure_targets();
+        sink_changes_configuration();
+    }
+
+    fn configure_targets() {
+        assert!(configure("info,logging_test=debug,quiet=off").is_ok());
+        assert!(enabled(Level::Debug, "logging_test"));
+        assert!(!enabled(Level::Trace, "logging_test"));
+        assert!(enabled(Level::Info, "anything"));
+        assert!(!enabled(Level::Error, "quiet"));
+        assert_eq!(configure("simulation=loud"), Err("simulation=loud".to_string()));
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let captured = entries.clone();
+        set_sink(Some(Arc::new(move |entry: &Entry| {
+            if entry.target == "logging_test" { captured.lock().unwrap().push(entry.format()) }
+        })));
+        log!(Level::Debug, "logging_test", "unit died"; unit = 3, location = (1.0, 2.5));
+        log!(Level::Trace, "logging_test", "too detailed");
+        set_level("logging_test", Level::Trace);
+        log!(Level::Trace, "logging_test", "now visible");
+        set_sink(None);
+        configure("").unwrap();
+
+        assert_eq!(*entries.lock().unwrap(), vec![
+            "DEBUG logging_test: unit died unit=3 location=(1.0, 2.5)".to_string(),
+            "TRACE logging_test: now visible".to_string()
+        ]);
+    }
+
+    fn sink_changes_configuration() {
+        // A sink muting its target after the first entry, and removing itself
+        configure("logging_test=info").unwrap();
+        let entries = Arc::new(Mutex::new(0));
+        let counted = entries.clone();
+        set_sink(Some(Arc::new(move |_: &Entry| {
+            *counted.lock().unwrap() += 1;
+            set_level("logging_test", Level::Off);
+            set_sink(None);
+        })));
+        log!(Level::Info, "logging_test", "first");
+        log!(Level::Info, "logging_test", "second");
+        configure("").unwrap();
+        assert_eq!(*entries.lock().unwrap(), 1);
+    }
+}
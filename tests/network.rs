@@ -0,0 +1,228 @@
+mod network_test {
+    extern crate server;
+    use self::server::*;
+    use self::server::lockstep::*;
+    use self::server::movement::*;
+    use self::server::transport::*;
+    use std::collections::BTreeMap;
+    use std::mem::size_of;
+
+    #[test]
+    fn reconciliation_on_bad_connection() {
+        let conditions = Conditions::new().latency(80).jitter(40).loss(0.2).reordering(0.1);
+        let mut upstream: Simulated<Vec<Input>> = Simulated::new(conditions.seed(11), |inputs| inputs.len() * size_of::<Input>());
+        let mut downstream: Simulated<(u32, AvatarState)> = Simulated::new(conditions.seed(12), fixed_size);
+
+        let start = AvatarState::new(0.0, PLAYER_HEIGHT, 0.0);
+        let mut client = Prediction::new(start);
+        let mut server = Authority::new(start);
+
+        for frame in 0..400 {
+            // Walk in a circle for a while, then stand still until everything settled
+            let moving = if frame < 200 { [1.3, 0.0, 0.0, 1.3, 0.0, 0.0] } else { [0.0; 6] };
+            client.input(moving, frame as f32 * 0.05, 1.0 / 60.0);
+            upstream.send(client.pending());
+
+            for inputs in upstream.receive() {
+                for input in inputs.iter() {
+                    server.apply(input);
+                }
+            }
+            if let Some(snapshot) = server.snapshot() {
+                downstream.send(snapshot);
+            }
+            for (sequence, state) in downstream.receive() {
+                client.reconcile(sequence, state);
+            }
+
+            upstream.advance(16);
+            downstream.advance(16);
+        }
+
+        assert_eq!(client.state(), server.snapshot().unwrap().1);
+        assert!(client.unacknowledged() < 20);
+    }
+
+    fn setup() -> Server {
+        let mut s = Server::new();
+        s.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+        s.spawn(1, AIType::Knight, (8.0, 0.0), 0.0);
+        s
+    }
+
+    #[test]
+    fn lockstep_on_bad_connection() {
+        // Lockstep needs reliable delivery, so only delay and reorder messages
+        let conditions = Conditions::new().latency(50).jitter(60).reordering(0.2).bandwidth(4096);
+        let mut s = setup();
+        let mut lockstep = Lockstep::new(2);
+        let mut replicas = [setup(), setup()];
+        let mut upstream: Vec<Simulated<(TimeIndex, Vec<Command>)>> = (0..2).map(|i| Simulated::new(conditions.seed(i + 1), |submission: &(TimeIndex, Vec<Command>)| {
+            size_of::<TimeIndex>() + submission.1.len() * size_of::<Command>()
+        })).collect();
+        let mut downstream: Vec<Simulated<Step>> = (0..2).map(|i| Simulated::new(conditions.seed(i + 10), |step: &Step| {
+            size_of::<TimeIndex>() + step.commands.len() * size_of::<(Player, Command)>() + step.rejected.len() * size_of::<(Player, CommandError)>()
+        })).collect();
+        let mut buffered: Vec<BTreeMap<TimeIndex, Step>> = vec![BTreeMap::new(), BTreeMap::new()];
+        let mut submitted = [None, None];
+
+        for _ in 0..2000 {
+            for player in 0..2 {
+                // Each client submits once per tick of its replica
+                let tick = replicas[player].present();
+                if tick < 30 && submitted[player] != Some(tick) {
+                    let commands = if tick.is_multiple_of(5) {
+                        vec![Command::Move { unit: player, target: (tick as f32 * 0.1, player as f32) }]
+                    } else { vec![] };
+                    upstream[player].send((tick, commands));
+                    submitted[player] = Some(tick);
+                }
+
+                for (tick, commands) in upstream[player].receive() {
+                    if let Some(step) = lockstep.submit(&mut s, player, tick, commands).unwrap() {
+                        for link in downstream.iter_mut() {
+                            link.send(step.clone());
+                        }
+                    }
+                }
+
+                // Steps may arrive out of order and are only applied in sequence
+                for step in downstream[player].receive() {
+                    buffered[player].insert(step.tick, step);
+                }
+                loop {
+                    let present = replicas[player].present();
+                    match buffered[player].remove(&present) {
+                        Some(step) => step.replay(&mut replicas[player]),
+                        None => break
+                    }
+                }
+            }
+            for link in upstream.iter_mut() { link.advance(5); }
+            for link in downstream.iter_mut() { link.advance(5); }
+        }
+
+        assert_eq!(s.present(), 30);
+        for (player, replica) in replicas.iter_mut().enumerate() {
+            assert_eq!(replica.present(), 30);
+            let checksums: Vec<_> = (0..31).map(|t| (t, Checksum::new(&replica.spectate(t).unwrap()))).collect();
+            assert_eq!(lockstep.verify(&mut s, player, &checksums), Ok(()));
+        }
+    }
+}
+
+mod lobby_lockstep_test {
+    extern crate server;
+    use self::server::*;
+    use self::server::lobby::*;
+    use self::server::lockstep::*;
+
+    #[test]
+    fn lockstep_through_the_lobby() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut clients: Vec<LobbyClient> = ["alice", "bob"].iter().map(|name| {
+            let mut client = LobbyClient::connect(address).unwrap();
+            client.authenticate(name, None).unwrap();
+            client
+        }).collect();
+        let settings = MatchSettings { map: "valley".to_string(), difficulty: 1, players: 2, horizon: 50 };
+        let id = clients[0].create(&settings).unwrap();
+        for client in clients.iter_mut() { client.join(id).unwrap(); }
+        for client in clients.iter_mut() { client.ready(id).unwrap(); }
+        // Replicas start out with the same armies as the lobby's match
+        let mut replicas = [Server::new().horizon(50), Server::new().horizon(50)];
+        for replica in replicas.iter_mut() { settings.spawn_armies(replica); }
+
+        for tick in 0..10 {
+            // Alice also tries to order Bob's knight around, which the server rejects
+            let alice = vec![Command::Move { unit: 0, target: (tick as f32, 1.0) }, Command::Move { unit: 1, target: (0.0, 0.0) }];
+            let bob = vec![Command::OpenPortal {
+                origin: (tick, (8.0, 0.0)), origin_lifetime: 2, origin_scale: 1.0,
+                dest: (tick + 1, (8.0, 2.0)), dest_lifetime: 1, dest_scale: 0.5
+            }];
+            assert!(!clients[0].submit(id, tick, &alice).unwrap());
+            assert!(clients[0].submit(id, tick, &[]).is_err());
+            assert!(clients[1].submit(id, tick, if tick == 4 { &bob } else { &[] }).unwrap());
+
+            for (client, replica) in clients.iter_mut().zip(replicas.iter_mut()) {
+                let steps = client.advance(id, tick).unwrap();
+                assert_eq!(steps.len(), 1);
+                assert_eq!(steps[0].commands.len(), if tick == 4 { 2 } else { 1 });
+                steps[0].replay(replica);
+                let checksum = Checksum::new(&replica.spectate(tick + 1).unwrap());
+                client.checksum(id, tick + 1, &checksum).unwrap();
+            }
+        }
+        assert_eq!(lobby.lock().unwrap().server(id).unwrap().portals().len(), 1);
+        assert_eq!(clients[0].advance(id, 0).unwrap().len(), 10);
+
+        // Alice's replica applies an order the server never saw
+        replicas[0].issue(0, Command::Move { unit: 0, target: (8.0, 8.0) }).unwrap();
+        for client in clients.iter_mut() {
+            client.submit(id, 10, &[]).unwrap();
+        }
+        for (client, replica) in clients.iter_mut().zip(replicas.iter_mut()) {
+            client.advance(id, 10).unwrap()[0].replay(replica);
+        }
+        let checksum = Checksum::new(&replicas[1].spectate(11).unwrap());
+        clients[1].checksum(id, 11, &checksum).unwrap();
+        let checksum = Checksum::new(&replicas[0].spectate(11).unwrap());
+        match clients[0].checksum(id, 11, &checksum) {
+            Err(LobbyError::Desync(desync)) => assert_eq!(desync, Desync { player: 0, tick: 11, unit: Some(0) }),
+            e => panic!("{:?}", e)
+        }
+    }
+}
+
+mod lobby_movement_test {
+    extern crate server;
+    use self::server::lobby::*;
+    use self::server::movement::*;
+
+    #[test]
+    fn avatar_moves_through_the_lobby() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        host.spawn();
+
+        let mut client = LobbyClient::connect(address).unwrap();
+        client.authenticate("alice", None).unwrap();
+        let id = client.create(&MatchSettings { map: "valley".to_string(), difficulty: 1, players: 2, horizon: 50 }).unwrap();
+        client.join(id).unwrap();
+        assert_eq!(client.walk(id, &[]).unwrap(), None);
+
+        // The server collides with the scenery reported by the client, just like the prediction
+        let tree = Obstacle { position: [0.0, 3.0], radius: 0.5, top: 3.0 };
+        client.scenery(id, &[tree]).unwrap();
+        let mut prediction = Prediction::new(AvatarState::spawn());
+        prediction.obstacles(vec![tree]);
+        for _ in 0..40 {
+            prediction.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.016);
+        }
+        let (sequence, state) = client.walk(id, &prediction.pending()).unwrap().unwrap();
+        assert_eq!(sequence, 39);
+        assert!((state.position[2] - 3.5).abs() < 1e-3, "{:?}", state);
+        prediction.reconcile(sequence, state);
+        assert_eq!(prediction.state(), state);
+
+        // Inputs the server already applied are skipped when they are sent again
+        assert_eq!(client.walk(id, &prediction.pending()).unwrap(), Some((sequence, state)));
+
+        // Handing in more frames than time passed doesn't make the avatar any faster
+        for _ in 0..200 {
+            prediction.input([0.0, 0.0, 1.3, 0.0, 0.0, 0.0], 0.0, 0.016);
+        }
+        let (sequence, state) = client.walk(id, &prediction.pending()).unwrap().unwrap();
+        assert!(sequence < 100, "{}", sequence);
+        assert!(state.position[0].abs() < 1.0, "{:?}", state);
+
+        // Clamping would keep a NaN, the server refuses it right away
+        let mut broken = prediction.input([1.3, 0.0, 0.0, 0.0, 0.0, 0.0], 0.0, 0.016);
+        broken.dt = f32::NAN;
+        assert!(client.walk(id, &[broken]).is_err());
+    }
+}
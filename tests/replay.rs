@@ -0,0 +1,40 @@
+mod replay_test {
+    extern crate server;
+    use self::server::replay::*;
+    use std::fs;
+
+    /// Every recorded match has to keep reproducing its final state
+    #[test]
+    fn recorded_matches() {
+        let mut paths: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/replays")).unwrap()
+            .map(|entry| entry.unwrap().path()).collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let mut playback = Playback::new(Replay::load(&path).unwrap());
+            if let Err(e) = playback.verify() { panic!("{}: {:?}", path.display(), e) }
+            let statistics = playback.statistics();
+            assert_eq!(statistics.ticks, playback.end());
+            assert!(statistics.portals > 0 && statistics.forks > 0);
+        }
+    }
+
+    #[test]
+    fn tampered_replay() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/replays/duel_0.replay");
+        let mut replay = Replay::load(path).unwrap();
+        // Nudge the last unit to spawn, one of the survivors
+        let spawn = replay.records.iter().rposition(|record| matches!(record.1, Record::Spawn { .. })).unwrap();
+        if let Record::Spawn { ref mut location, .. } = replay.records[spawn].1 {
+            location.0 += 1.0;
+        }
+        // Every command is still accepted, only the final state gives the tampering away
+        match Playback::new(replay).verify() {
+            Err(ReplayError::Mismatch { expected, found }) => {
+                assert_eq!(expected, 10008994121740919531);
+                assert_ne!(found, expected);
+            },
+            e => panic!("{:?}", e)
+        }
+    }
+}
@@ -1,6 +1,9 @@
+// Structs are initialized as `field: field` throughout, the style of the 2015 code base
+#![allow(clippy::redundant_field_names)]
 mod api_test {
     extern crate server;
     use self::server::*;
+    use self::server::strategist::Strategist;
 
     #[test]
     fn start_game() {
@@ -8,4 +11,449 @@ mod api_test {
         s.start_game();
         //assert_eq!(6, plus_one(5));
     }
+
+    #[test]
+    fn strategist_duel() {
+        let mut s = Server::new();
+        for i in 0..3 {
+            s.spawn(0, AIType::Knight, (0.0, i as f32 * 3.0), 0.0);
+        }
+        s.spawn(0, AIType::Scout, (-10.0, 0.0), 0.0);
+        for i in 0..4 {
+            s.spawn(1, AIType::Knight, (15.0, i as f32 * 0.5), 0.0);
+        }
+
+        let mut red = Strategist::new(0);
+        let mut blue = Strategist::new(1).horizon(10);
+        let mut opened = Vec::new();
+        for _ in 0..60 {
+            let present = s.present();
+            let commands = red.play(&mut s);
+            if present == 0 {
+                // Everyone charges at the start
+                let moved: Vec<ID> = commands.iter().filter_map(|command| match *command {
+                    Command::Move { unit, .. } => Some(unit),
+                    _ => None
+                }).collect();
+                assert_eq!(moved, vec![0, 1, 2, 3]);
+            }
+            opened.extend(commands.into_iter().filter_map(|command| match command {
+                Command::OpenPortal { origin, dest, .. } => Some((present, origin.0, dest.0)),
+                _ => None
+            }));
+            blue.play(&mut s);
+            s.tick();
+        }
+
+        assert!(s.observe(0, s.present() + 1).is_none());
+        // Red first loses a knight at tick 13 and sends the scout, which is furthest
+        // from the fight, one tick back to reinforce
+        assert_eq!(opened, vec![(14, 14, 13)]);
+        assert_eq!(s.portals().len(), 1);
+        assert_eq!(s.portals()[0].player, 0);
+        assert_eq!(s.traversals().iter().map(|traversal| traversal.unit).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(s.issue(0, Command::Move { unit: 4, target: (0.0, 0.0) }), Err(CommandError::NotOwner(4)));
+    }
+
+    #[test]
+    fn strategist_sizes_portals() {
+        let mut s = Server::new();
+        for i in 0..3 {
+            s.spawn(0, AIType::Knight, (0.0, i as f32 * 3.0), 0.0);
+        }
+        s.spawn(0, AIType::Scout, (-10.0, 0.0), 0.0);
+        s.spawn(0, AIType::Scout, (-10.0, 4.0), 0.0);
+        for i in 0..4 {
+            s.spawn(1, AIType::Knight, (15.0, i as f32 * 0.5), 0.0);
+        }
+
+        let mut red = Strategist::new(0);
+        let mut blue = Strategist::new(1).horizon(10);
+        let mut portals = Vec::new();
+        for _ in 0..60 {
+            portals.extend(red.play(&mut s).into_iter().filter(|command| matches!(*command, Command::OpenPortal { .. })));
+            blue.play(&mut s);
+            s.tick();
+        }
+
+        // Two knights fall at tick 13, so both scouts are sent back through a portal large enough
+        // for the two of them, which stays open until they walked in
+        match portals[..] {
+            [Command::OpenPortal { origin, origin_lifetime, origin_scale, dest, dest_lifetime, dest_scale }] => {
+                assert_eq!((origin.0, dest.0), (14, 13));
+                assert!(origin_scale > 1.0 && origin_scale == dest_scale, "{}", origin_scale);
+                assert!(origin_lifetime > 3 && origin_lifetime == dest_lifetime, "{}", origin_lifetime);
+            },
+            ref other => panic!("{:?}", other)
+        }
+        assert_eq!(s.traversals().iter().map(|traversal| traversal.unit).collect::<Vec<_>>(), vec![3, 4]);
+    }
+}
+
+mod lobby_test {
+    extern crate server;
+    use self::server::lobby::*;
+    use self::server::events::Event;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    fn connect(address: SocketAddr, name: &str) -> LobbyClient {
+        let mut client = LobbyClient::connect(address).unwrap();
+        client.authenticate(name, None).unwrap();
+        client
+    }
+
+    #[test]
+    fn matchmaking_on_localhost() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut alice = connect(address, "alice");
+        let mut bob = connect(address, "bob");
+        let settings = MatchSettings { map: "valley".to_string(), difficulty: 5, players: 2, horizon: 500 };
+        let id = alice.create(&settings).unwrap();
+
+        alice.join(id).unwrap();
+        // Nobody takes two slots of the same match
+        assert!(alice.join(id).is_err());
+        let matches = bob.list().unwrap();
+        assert_eq!(matches, vec![MatchInfo { id: id, settings: settings, joined: 1 }]);
+        bob.join(id).unwrap();
+        let mut mallory = connect(address, "mallory");
+        assert!(mallory.join(id).is_err());
+
+        // Slots can only be readied or given up by the connection holding them
+        assert!(mallory.ready(id).is_err());
+        assert!(mallory.leave(id).is_err());
+        assert_eq!(bob.list().unwrap()[0].joined, 2);
+
+        assert!(!alice.ready(id).unwrap());
+        assert!(bob.ready(id).unwrap());
+        assert!(bob.list().unwrap().is_empty());
+
+        // Both players start with an army as large as the difficulty
+        let keyframe = lobby.lock().unwrap().server(id).unwrap().spectate(0).unwrap();
+        assert_eq!(keyframe.len(), 10);
+        let owners: Vec<_> = keyframe.iter().map(|unit| lobby.lock().unwrap().server(id).unwrap().owner(unit.0).unwrap()).collect();
+        assert_eq!(owners, vec![0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+        assert!(alice.create(&MatchSettings { map: "moon".to_string(), difficulty: 1, players: 2, horizon: 50 }).is_err());
+        assert!(alice.create(&MatchSettings { map: "valley".to_string(), difficulty: 0, players: 2, horizon: 50 }).is_err());
+    }
+
+    #[test]
+    fn resume_dropped_session() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap().grace_period(Duration::from_secs(60));
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut alice = connect(address, "alice");
+        let mut bob = connect(address, "bob");
+        let id = alice.create(&MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 2, horizon: 50 }).unwrap();
+        let (a, token) = alice.join(id).unwrap();
+        bob.join(id).unwrap();
+        alice.ready(id).unwrap();
+        bob.ready(id).unwrap();
+        let (keyframes, events) = alice.sync(id).unwrap();
+        assert!(keyframes.is_empty());
+        assert_eq!(events, vec![(0, Event::GameStarted)]);
+        drop(alice);
+
+        {
+            let mut lobby = lobby.lock().unwrap();
+            let server = lobby.server(id).unwrap();
+            server.spawn(a, server::AIType::Knight, (0.0, 0.0), 0.0);
+            for _ in 0..5 { server.tick(); }
+        }
+
+        // Resuming takes on the name the session was joined with, no matter if the server
+        // already noticed the old connection dropped
+        let mut alice = LobbyClient::connect(address).unwrap();
+        assert!(alice.resume(id, "forged").is_err());
+        assert!(connect(address, "mallory").resume(id, &token).is_err());
+        assert_eq!(alice.resume(id, &token).unwrap(), a);
+        let (missed, events) = alice.sync(id).unwrap();
+        assert_eq!(missed.iter().map(|k| k.0).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(missed[4].1, vec![(0, (0.0, 0.0), 0.0)]);
+        assert_eq!(events, vec![
+            (1, Event::UnitSpawned { unit: 0, player: a, ai_type: server::AIType::Knight, location: (0.0, 0.0) }),
+            (2, Event::MatchEnded { winner: Some(a), time: 1 })
+        ]);
+        assert_eq!(alice.sync(id).unwrap(), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn replaced_connection_keeps_session() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap().grace_period(Duration::from_millis(200));
+        let address = host.local_addr().unwrap();
+        host.spawn();
+
+        let mut alice = connect(address, "alice");
+        let id = alice.create(&MatchSettings { map: "valley".to_string(), difficulty: 1, players: 1, horizon: 50 }).unwrap();
+        let (a, token) = alice.join(id).unwrap();
+        alice.ready(id).unwrap();
+
+        // Alice reconnects before the server noticed the old connection dropped
+        let mut replacement = LobbyClient::connect(address).unwrap();
+        assert!(LobbyClient::connect(address).unwrap().authenticate("alice", None).is_err());
+        assert_eq!(replacement.resume(id, &token).unwrap(), a);
+        drop(alice);
+        thread::sleep(Duration::from_millis(400));
+        assert!(replacement.sync(id).is_ok());
+    }
+
+    #[test]
+    fn spectate_all_branches() {
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut alice = connect(address, "alice");
+        let id = alice.create(&MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 1, horizon: 50 }).unwrap();
+        assert!(connect(address, "dave").spectate(id).is_err());
+        let (a, _) = alice.join(id).unwrap();
+        alice.ready(id).unwrap();
+
+        {
+            let mut lobby = lobby.lock().unwrap();
+            let server = lobby.server(id).unwrap();
+            let knight = server.spawn(a, server::AIType::Knight, (0.0, 0.0), 0.0);
+            server.spawn(1, server::AIType::Knight, (100.0, 0.0), 0.0);
+            for _ in 0..5 { server.tick(); }
+            server.issue(a, server::Command::OpenPortal {
+                origin: (5, (0.0, 0.0)), origin_lifetime: 2, origin_scale: 1.0,
+                dest: (2, (0.0, 5.0)), dest_lifetime: 1, dest_scale: 1.0
+            }).unwrap();
+            // The knight stepped through the portal and only exists in the past now
+            assert_eq!(server.issue(a, server::Command::Move { unit: knight, target: (0.0, 0.0) }),
+                       Err(server::CommandError::NotPresent(knight)));
+            server.tick();
+            assert_eq!(server.observe(a, 6).unwrap().len(), 1);
+        }
+
+        let mut spectator = connect(address, "carol").spectate(id).unwrap();
+        assert_eq!(spectator.view(6).unwrap().len(), 2);
+        assert_eq!(spectator.view(3).unwrap().len(), 3);
+        assert!(spectator.view(7).is_err());
+        let branches = spectator.branches().unwrap();
+        assert_eq!(branches.iter().map(|b| b.forked_at).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(spectator.view_branch(1, 3).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn chat_and_pings() {
+        use self::server::chat::{Channel, Participant};
+        use self::server::replay::{Playback, Replay};
+
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut clients: Vec<LobbyClient> = (0..3).map(|i| connect(address, &format!("player{}", i))).collect();
+        let id = clients[0].create(&MatchSettings { map: "valley".to_string(), difficulty: 1, players: 3, horizon: 50 }).unwrap();
+        for client in clients.iter_mut() {
+            client.join(id).unwrap();
+            client.ready(id).unwrap();
+        }
+        let mut spectator = connect(address, "carol").spectate(id).unwrap();
+
+        clients[0].say(id, Channel::Team, "hold the bridge").unwrap();
+        // Spacing inside a message is kept as written
+        clients[1].say(id, Channel::All, "good luck,  have fun").unwrap();
+        clients[2].ping(id, 12, (3.5, -1.0)).unwrap();
+        spectator.say("this is going to be close").unwrap();
+        assert!(clients[0].say(id, Channel::Spectators, "hello?").is_err());
+
+        let messages = clients[2].messages(id, 0).unwrap();
+        assert_eq!(messages.iter().map(|m| &m.1.text[..]).collect::<Vec<_>>(), vec!["hold the bridge", "good luck,  have fun"]);
+        assert_eq!(clients[1].messages(id, 0).unwrap().len(), 1);
+        assert_eq!(spectator.messages(2).unwrap()[0].1.sender, Participant::Spectator);
+
+        assert_eq!(clients[0].pings(id).unwrap().len(), 1);
+        assert!(clients[1].pings(id).unwrap().is_empty());
+        assert_eq!(spectator.pings().unwrap()[0].location, (3.5, -1.0));
+
+        // Replays keep the whole conversation, including what was only visible to some
+        let replay = lobby.lock().unwrap().replay(id).unwrap();
+        let mut file = Vec::new();
+        replay.write(&mut file).unwrap();
+        let mut playback = Playback::new(Replay::read(&file[..]).unwrap());
+        playback.seek(12).unwrap();
+        assert_eq!(playback.messages().len(), 3);
+        assert_eq!(playback.messages()[1].text, "good luck,  have fun");
+        assert_eq!(playback.pings().len(), 1);
+    }
+
+    #[test]
+    fn event_stream() {
+        use self::server::AIType;
+
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap();
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut clients: Vec<LobbyClient> = (0..2).map(|i| connect(address, &format!("player{}", i))).collect();
+        let id = clients[0].create(&MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 2, horizon: 50 }).unwrap();
+        for client in clients.iter_mut() {
+            client.join(id).unwrap();
+            client.ready(id).unwrap();
+        }
+        {
+            let mut lobby = lobby.lock().unwrap();
+            let server = lobby.server(id).unwrap();
+            server.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+            server.spawn(1, AIType::Knight, (1.0, 0.0), 0.0);
+            server.spawn(1, AIType::Knight, (1.0, 1.0), 0.0);
+            server.tick();
+        }
+
+        let mut spectator = connect(address, "carol").spectate(id).unwrap();
+        let events = spectator.events(0).unwrap();
+        assert_eq!(events[0], (0, Event::GameStarted));
+        assert!(events.iter().any(|e| matches!(e.1, Event::UnitDied { unit: 0, player: 0, time: 1, .. })));
+        assert!(events.iter().any(|e| e.1 == Event::MatchEnded { winner: Some(1), time: 1 }));
+
+        // Players only hear about their own units
+        let own = clients[1].events(id, 0).unwrap();
+        assert_eq!(own.iter().filter(|e| matches!(e.1, Event::UnitSpawned { .. })).count(), 2);
+        assert!(!own.iter().any(|e| matches!(e.1, Event::UnitDied { .. })));
+        assert_eq!(clients[0].events(id, events.last().unwrap().0 + 1).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn authenticated_commands() {
+        use self::server::{AIType, Command, CommandError};
+
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap().password("sesame");
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut stranger = LobbyClient::connect(address).unwrap();
+        match stranger.list() { Err(LobbyError::Protocol(ref e)) if e.contains("Unauthenticated") => {}, e => panic!("{:?}", e) }
+        assert!(stranger.authenticate("stranger", Some("open")).is_err());
+
+        let mut alice = LobbyClient::connect(address).unwrap();
+        alice.authenticate("alice", Some("sesame")).unwrap();
+        let mut bob = LobbyClient::connect(address).unwrap();
+        bob.authenticate("bob", Some("sesame")).unwrap();
+        let id = alice.create(&MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 2, horizon: 50 }).unwrap();
+        let (a, _) = alice.join(id).unwrap();
+        assert!(bob.ready(id).is_err());
+        let (b, _) = bob.join(id).unwrap();
+        alice.ready(id).unwrap();
+        bob.ready(id).unwrap();
+
+        let (knight, scout) = {
+            let mut lobby = lobby.lock().unwrap();
+            let server = lobby.server(id).unwrap();
+            (server.spawn(a, AIType::Knight, (0.0, 0.0), 0.0), server.spawn(b, AIType::Scout, (5.0, 0.0), 0.0))
+        };
+        alice.issue(id, &Command::Move { unit: knight, target: (1.0, 1.0) }).unwrap();
+        match alice.issue(id, &Command::Move { unit: scout, target: (1.0, 1.0) }) {
+            Err(LobbyError::Command(CommandError::NotOwner(unit))) if unit == scout => {},
+            e => panic!("{:?}", e)
+        }
+    }
+
+    #[test]
+    fn rate_limited_commands() {
+        use self::server::{AIType, Command, CommandError};
+
+        let host = LobbyServer::bind("127.0.0.1:0").unwrap().rate_limit(5.0, 0.1);
+        let address = host.local_addr().unwrap();
+        let lobby = host.lobby();
+        host.spawn();
+
+        let mut alice = connect(address, "alice");
+        let id = alice.create(&MatchSettings { map: "sandbox".to_string(), difficulty: 1, players: 1, horizon: 50 }).unwrap();
+        let (a, token) = alice.join(id).unwrap();
+        alice.ready(id).unwrap();
+        let knight = lobby.lock().unwrap().server(id).unwrap().spawn(a, AIType::Knight, (0.0, 0.0), 0.0);
+
+        match alice.issue(id, &Command::Move { unit: knight, target: (1000.0, 0.0) }) {
+            Err(LobbyError::Command(CommandError::OffMap(target))) => assert_eq!(target, (1000.0, 0.0)),
+            e => panic!("{:?}", e)
+        }
+        for _ in 0..4 {
+            alice.issue(id, &Command::Move { unit: knight, target: (1.0, 1.0) }).unwrap();
+        }
+        match alice.issue(id, &Command::Move { unit: knight, target: (1.0, 1.0) }) {
+            Err(LobbyError::RateLimited) => {},
+            e => panic!("{:?}", e)
+        }
+        // Lobby requests are never limited, the budget is shared by every connection of the player
+        assert!(alice.list().is_ok());
+        let mut replacement = LobbyClient::connect(address).unwrap();
+        replacement.resume(id, &token).unwrap();
+        match replacement.issue(id, &Command::Move { unit: knight, target: (1.0, 1.0) }) {
+            Err(LobbyError::RateLimited) => {},
+            e => panic!("{:?}", e)
+        }
+
+        // Others have their own budget, looking at the timeline uses it up faster
+        let mut spectator = connect(address, "carol").spectate(id).unwrap();
+        assert!(spectator.say("hi").is_ok());
+        assert!(spectator.view(0).is_ok());
+        assert!(spectator.view(0).is_ok());
+        match spectator.view(0) {
+            Err(LobbyError::RateLimited) => {},
+            e => panic!("{:?}", e)
+        }
+    }
+}
+
+mod lockstep_test {
+    extern crate server;
+    use self::server::*;
+    use self::server::lockstep::*;
+
+    fn setup() -> Server {
+        let mut s = Server::new();
+        s.spawn(0, AIType::Knight, (0.0, 0.0), 0.0);
+        s.spawn(1, AIType::Scout, (10.0, 0.0), 0.0);
+        s
+    }
+
+    #[test]
+    fn lockstep_with_desync() {
+        let mut s = setup();
+        let mut replica = setup();
+        let mut lockstep = Lockstep::new(2);
+
+        let order = Command::Move { unit: 0, target: (5.0, 5.0) };
+        assert_eq!(lockstep.submit(&mut s, 0, 0, vec![order]).unwrap(), None);
+        assert_eq!(s.present(), 0);
+        assert_eq!(lockstep.waiting_for(), vec![1]);
+        assert_eq!(lockstep.submit(&mut s, 0, 0, vec![]), Err(LockstepError::AlreadySubmitted(0)));
+
+        let step = lockstep.submit(&mut s, 1, 0, vec![Command::Move { unit: 0, target: (0.0, 0.0) }]).unwrap().unwrap();
+        assert_eq!(step.rejected, vec![(1, CommandError::NotOwner(0))]);
+        assert_eq!(s.present(), 1);
+        step.replay(&mut replica);
+
+        for tick in 1..5 {
+            lockstep.submit(&mut s, 0, tick, vec![]).unwrap();
+            lockstep.submit(&mut s, 1, tick, vec![]).unwrap().unwrap().replay(&mut replica);
+        }
+        assert_eq!(lockstep.submit(&mut s, 0, 3, vec![]), Err(LockstepError::WrongTick { expected: 5, received: 3 }));
+
+        let checksums: Vec<_> = (0..6).map(|t| (t, Checksum::new(&replica.spectate(t).unwrap()))).collect();
+        assert_eq!(lockstep.verify(&mut s, 1, &checksums), Ok(()));
+
+        // The replica misses an order and diverges afterwards
+        replica.issue(1, Command::Move { unit: 1, target: (10.0, 0.0) }).unwrap();
+        replica.tick();
+        s.tick();
+        s.tick();
+        replica.tick();
+        let checksums: Vec<_> = (0..8).map(|t| (t, Checksum::new(&replica.spectate(t).unwrap()))).collect();
+        assert_eq!(lockstep.verify(&mut s, 1, &checksums), Err(Desync { player: 1, tick: 6, unit: Some(1) }));
+    }
 }